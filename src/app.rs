@@ -0,0 +1,285 @@
+// The editor tied to a score file, the system clipboard and an autosave, the
+// parts of the app that the backend doesn't need
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::pianoroll::{self, PianoRoll, Score};
+use crate::{renderer, Command, Msg};
+
+pub struct Model {
+    file: Option<PathBuf>,
+    pub pianoroll: PianoRoll,
+    autosave: Autosave,
+    /// Opened on the first copy or paste, and kept open since on X11 the
+    /// copied text is gone once it's closed
+    clipboard: Option<arboard::Clipboard>,
+}
+
+/// Periodically writes the score next to its file, to recover it after a crash
+struct Autosave {
+    /// How long an edit may stay unsaved, zero disables autosaving
+    interval: Duration,
+    /// How many edits may stay unsaved
+    max_edits: u32,
+    edits: u32,
+    first_edit: Option<Instant>,
+    /// `PianoRoll::score_changes` as of the last edit that was counted
+    changes: u64,
+}
+
+impl Autosave {
+    fn new() -> Self {
+        Autosave {
+            interval: Duration::from_secs(60),
+            max_edits: 50,
+            edits: 0,
+            first_edit: None,
+            changes: 0,
+        }
+    }
+
+    /// Counts an edit if the score changed since the last call
+    fn track(&mut self, changes: u64) {
+        if changes != self.changes {
+            self.changes = changes;
+            self.edits += 1;
+            self.first_edit.get_or_insert_with(Instant::now);
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        let overdue = self.first_edit.is_some_and(|t| t.elapsed() >= self.interval);
+
+        self.interval != Duration::from_secs(0)
+            && (overdue || self.edits >= self.max_edits)
+    }
+
+    fn reset(&mut self) {
+        self.edits = 0;
+        self.first_edit = None;
+    }
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::new()
+    }
+}
+
+impl Model {
+    pub fn new() -> Self {
+        Self::with_file(None)
+    }
+
+    pub fn with_file(path: Option<PathBuf>) -> Self {
+        Model {
+            file: path,
+            pianoroll: PianoRoll::new(),
+            autosave: Autosave::new(),
+            clipboard: None,
+        }
+    }
+
+    /// Starts with a blank score instead of an empty one
+    pub fn with_score(mut self, score: Score) -> Self {
+        self.pianoroll.load_score(score);
+
+        self
+    }
+
+    /// Loads a score with at most `max_notes` notes. A score cut short isn't
+    /// tied to its file, so saving can't overwrite the notes left out.
+    pub fn from_file(path: PathBuf, max_notes: usize) -> Result<Self, String> {
+        let mut score = read_score(&path, is_binary(&path))?;
+
+        let count = score.note_count();
+        let dropped = score.truncate_notes(max_notes);
+        let file =
+            if dropped > 0 {
+                eprintln!(
+                    "{} has {} notes, only the first {} were loaded. Saving is off so the file keeps the rest.",
+                    path.to_string_lossy(), count, max_notes
+                );
+                None
+            }
+            else {
+                if count > pianoroll::MANY_NOTES {
+                    eprintln!("Loaded {} notes from {}, editing may be slow", count, path.to_string_lossy())
+                }
+                Some(path)
+            };
+
+        let mut model = Self::with_file(file);
+        model.pianoroll.load_score(score);
+
+        Ok(model)
+    }
+
+    /// How long an edit may stay unsaved before it's autosaved, zero turns autosaving off
+    pub fn set_autosave_interval(&mut self, interval: Duration) {
+        self.autosave.interval = interval
+    }
+
+    /// How many edits may stay unsaved before they're autosaved
+    pub fn set_autosave_edits(&mut self, edits: u32) {
+        self.autosave.max_edits = edits
+    }
+
+    fn system_clipboard(&mut self) -> Option<&mut arboard::Clipboard> {
+        if self.clipboard.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(e) => eprintln!("No system clipboard: {}", e),
+            }
+        }
+
+        self.clipboard.as_mut()
+    }
+
+    /// Puts the copied notes on the system clipboard as text
+    fn copy(&mut self) {
+        let text = self.pianoroll.clipboard_text();
+
+        if let Some(clipboard) = self.system_clipboard() {
+            if let Err(e) = clipboard.set_text(text) {
+                eprintln!("Failed to copy: {}", e)
+            }
+        }
+    }
+
+    /// Pastes the notes on the system clipboard, or the ones copied last if it has none
+    fn paste(&mut self) {
+        let text = self.system_clipboard().and_then(|c| c.get_text().ok());
+
+        self.pianoroll.paste_text(text.as_deref());
+    }
+
+    /// Replaces the score with the one from the autosave file
+    fn recover(&mut self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("No file to recover")?;
+        let score = read_score(&autosave_path(path), is_binary(path))?;
+
+        self.pianoroll.load_score(score);
+        Ok(())
+    }
+
+    /// Whether the autosave file has changes the score file doesn't
+    pub fn has_recovery(&self) -> bool {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return false,
+        };
+        let modified = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+
+        match (modified(&autosave_path(path)), modified(path)) {
+            (Some(autosaved), Some(saved)) => autosaved > saved,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    /// Writes the autosave file if there are enough unsaved edits or they're old enough
+    pub fn autosave(&mut self) {
+        if !self.autosave.is_due() { return }
+        self.autosave.reset();
+
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return,
+        };
+        let result = score_data(self.pianoroll.score(), is_binary(path))
+            .and_then(|data| std::fs::write(autosave_path(path), data).map_err(|e| e.to_string()));
+
+        if let Err(e) = result {
+            eprintln!("Failed to autosave: {}", e)
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("No file to save to")?;
+        let data = score_data(self.pianoroll.score(), is_binary(path))?;
+
+        std::fs::write(path, data).map_err(|e| e.to_string())?;
+
+        // The autosave is older than the file now
+        self.autosave.reset();
+        let _ = std::fs::remove_file(autosave_path(path));
+        Ok(())
+    }
+}
+
+/// The recovery file of a score, e.g. song.dsq.autosave for song.dsq
+pub fn autosave_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".autosave");
+
+    name.into()
+}
+
+/// Whether a score file uses the binary format, by its .dsqb extension
+pub fn is_binary(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "dsqb")
+}
+
+/// Reads a score in the binary format or as RON text
+pub fn read_score(path: &Path, binary: bool) -> Result<Score, String> {
+    if binary {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        Score::from_bytes(&bytes)
+    }
+    else {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Score::load(&text)
+    }
+}
+
+/// A score in the binary format or as RON text, to be written to a file
+pub fn score_data(score: &Score, binary: bool) -> Result<Vec<u8>, String> {
+    if binary { score.to_bytes() }
+    else { score.save().map(String::into_bytes) }
+}
+
+/// Passes a message to the piano roll, and carries out the commands that need
+/// the file or the clipboard. The rest are left for the backend.
+pub fn model(mut model: Model, msg: Msg, cmds: &mut Vec<Command>) -> Model {
+    model.pianoroll.handle_msg(msg, cmds);
+    model.autosave.track(model.pianoroll.score_changes());
+
+    // Saving needs the file name, so it's done here instead of by the backend
+    if let Some(i) = cmds.iter().position(|&c| c == Command::Save) {
+        cmds.remove(i);
+
+        if let Err(e) = model.save() {
+            eprintln!("Failed to save: {}", e)
+        }
+    }
+
+    // So is the clipboard, which the backend doesn't have
+    if let Some(i) = cmds.iter().position(|&c| c == Command::Copy) {
+        cmds.remove(i);
+        model.copy()
+    }
+    if let Some(i) = cmds.iter().position(|&c| c == Command::Paste) {
+        cmds.remove(i);
+        model.paste()
+    }
+    if let Some(i) = cmds.iter().position(|&c| c == Command::Recover) {
+        cmds.remove(i);
+        if let Err(e) = model.recover() {
+            eprintln!("Failed to recover: {}", e)
+        }
+    }
+
+    model
+}
+
+/// Updates the scene, and returns whether it changed and needs to be rendered again
+pub fn draw(model: &mut Model, renderer: &mut renderer::Renderer, scene: &mut renderer::Scene) -> bool {
+    let changed = model.pianoroll.draw_changes(scene);
+    if changed {
+        renderer.clear(scene.bg_color())
+    }
+
+    changed
+}
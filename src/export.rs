@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use glutin::GlContext;
+
+use crate::renderer;
+
+/// Renders a scene into an offscreen target and saves it as a PNG
+pub fn export_png(scene: &renderer::Scene, size: [u32; 2], path: &Path) -> Result<(), String> {
+    render(scene, size)?.save(path).map_err(|e| e.to_string())
+}
+
+/// Renders a scene into an offscreen target, top row first
+pub fn render(scene: &renderer::Scene, size: [u32; 2]) -> Result<image::RgbaImage, String> {
+    let [width, height] = size;
+
+    let context = glutin::HeadlessRendererBuilder::new(width, height)
+        .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (3, 2)))
+        .with_gl_profile(glutin::GlProfile::Core)
+        .build()
+        .map_err(|e| e.to_string())?;
+    unsafe { context.make_current() }.map_err(|e| e.to_string())?;
+
+    let (mut device, mut factory) =
+        gfx_device_gl::create(|s| context.get_proc_address(s) as *const _);
+
+    let (texture, target) = renderer::offscreen_target(&mut factory, size)?;
+
+    let encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
+    let mut renderer = renderer::Renderer::new(factory, encoder, target.clone());
+
+    renderer.clear_target(&target, scene.bg_color());
+    renderer.render_scene_to(&target, scene, [width as f32, height as f32], &mut device);
+    let pixels = renderer.read_pixels(&texture, &mut device)?;
+
+    let mut image = image::RgbaImage::from_raw(width, height, pixels)
+        .ok_or("The rendered image has a wrong size")?;
+    // GL puts the bottom row first
+    image::imageops::flip_vertical_in_place(&mut image);
+
+    Ok(image)
+}
+
+/// Counts the pixels of two images that differ by more than `tolerance` in
+/// any channel. Images of different sizes are an error.
+pub fn compare_png(path: &Path, reference: &Path, tolerance: u8) -> Result<usize, String> {
+    let image = image::open(path).map_err(|e| e.to_string())?.to_rgba8();
+    let reference = image::open(reference).map_err(|e| e.to_string())?.to_rgba8();
+
+    compare_images(&image, &reference, tolerance)
+}
+
+fn compare_images(image: &image::RgbaImage, reference: &image::RgbaImage, tolerance: u8) -> Result<usize, String> {
+    if image.dimensions() != reference.dimensions() {
+        return Err(format!(
+            "The image is {:?}, the reference is {:?}", image.dimensions(), reference.dimensions()
+        ))
+    }
+
+    let differing = image.pixels().zip(reference.pixels())
+        .filter(|(a, b)| a.0.iter().zip(b.0.iter()).any(|(&a, &b)| a.abs_diff(b) > tolerance))
+        .count();
+
+    Ok(differing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pianoroll::{PianoRoll, Score};
+    use crate::{ui, Msg};
+
+    const SIZE: [u32; 2] = [320, 240];
+
+    /// A few notes on two channels over a couple of measures
+    fn golden_scene(style: ui::Style) -> renderer::Scene {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(Score::load("Score(
+            version: 3,
+            measure_ticks: 16,
+            notes: [
+                (channel: 0, time: (0, 4), pitch: 124),
+                (channel: 0, time: (4, 8), pitch: 134),
+                (channel: 0, time: (8, 16), pitch: 142),
+                (channel: 1, time: (2, 10), pitch: 109),
+                (channel: 1, time: (16, 20), pitch: 155),
+            ],
+            tempo: [(0, 240.0)],
+            length: None,
+        )").unwrap());
+        pianoroll.set_style(style);
+        pianoroll.set_view(cgmath::Vector2::new(-0.25, 93.0), cgmath::Vector2::new(2.0, 186.0));
+
+        let resized = glutin::WindowEvent::Resized((SIZE[0] as f64, SIZE[1] as f64).into());
+        pianoroll.handle_msg(Msg::WindowEvent(resized), &mut vec![]);
+
+        let mut scene = renderer::Scene::new();
+        pianoroll.draw(&mut scene);
+        scene
+    }
+
+    /// Renders each style and compares it with its reference in tests/golden.
+    /// Run with DIESEQ_UPDATE_GOLDEN=1 to write the references instead, after
+    /// an intended change. Without a headless OpenGL (OSMesa) there's nothing
+    /// to compare, and the test only says so.
+    #[test]
+    fn renders_like_the_golden_images() {
+        let update = std::env::var_os("DIESEQ_UPDATE_GOLDEN").is_some();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+
+        for &(style, name) in &[(ui::Style::Light, "light.png"), (ui::Style::Dark, "dark.png")] {
+            let image = match render(&golden_scene(style), SIZE) {
+                Ok(image) => image,
+                Err(e) if !update => {
+                    eprintln!("Skipping the golden images, can't render offscreen: {}", e);
+                    return
+                },
+                Err(e) => panic!("Can't render offscreen: {}", e),
+            };
+            let path = dir.join(name);
+
+            if update {
+                image.save(&path).unwrap();
+                continue
+            }
+
+            let reference = image::open(&path).unwrap().to_rgba8();
+            let differing = compare_images(&image, &reference, 2).unwrap();
+            if differing > 0 {
+                let actual = std::env::temp_dir().join(format!("dieseq-{}", name));
+                let _ = image.save(&actual);
+                panic!("{} pixels differ from {}, the render is in {}", differing, path.display(), actual.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::path::Path;
+use glutin::VirtualKeyCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    ArrowTool,
+    PencilTool,
+    EraserTool,
+    LineTool,
+    StepTool,
+    PlayStop,
+    PlayFromStart,
+    /// Plays from the play position, putting the notes played on the MIDI input into the score
+    Record,
+    Delete,
+    Save,
+    Panic,
+    FitPitch,
+    TogglePreview,
+    ToggleTheme,
+    ToggleDrawReplaces,
+    CycleMeter,
+    BeatBack,
+    BeatForward,
+    /// Steps to the next grid line, a beat with snapping on and a tick without
+    GridLineBack,
+    GridLineForward,
+    MeasureBack,
+    MeasureForward,
+    GoToStart,
+    GoToEnd,
+    ToggleSnap,
+    /// Switches the note value the pencil snaps notes to
+    CycleSnapResolution,
+    QuantizeLengths,
+    RepeatSelection,
+    /// Asks how many times to repeat the selected notes, and how far apart
+    RepeatSelectionTimes,
+    /// Copies the selected notes, also as text to the system clipboard
+    Copy,
+    /// Pastes notes at the play position, from the system clipboard if it holds any
+    Paste,
+    Retrograde,
+    /// Reverses the selected notes within their measures
+    MirrorInMeasure,
+    Invert,
+    ToggleSelectMode,
+    ToggleLoop,
+    ToggleRounded,
+    /// Selects the notes sounding at the play position
+    SelectAtPlayhead,
+    /// Moves the selected notes to a channel
+    SetChannel(u16),
+    /// Opens the property panel of the selected note
+    EditNote,
+    /// Zoom in time around the play position
+    ZoomIn,
+    ZoomOut,
+    /// Zoom in pitch around the middle of the view
+    ZoomInPitch,
+    ZoomOutPitch,
+    /// Goes back to the view the piano roll starts with
+    ResetView,
+    /// Shades the rows of the pitches in the key
+    ToggleKey,
+    /// Shades every second octave
+    ToggleOctaveBands,
+    /// Shows how many notes overlap each beat and whole tone instead of the notes
+    ToggleHeatmap,
+    /// Highlights the lines of the next preset scale
+    CycleScale,
+    /// Shows the recent input events and commands, and prints them to stderr
+    ToggleEventLog,
+    /// Chooses the channel to draw on and to mute or solo
+    ActiveChannel(u16),
+    ToggleMute,
+    ToggleSolo,
+    /// Keeps the notes of the active channel from being selected, erased or resized
+    ToggleLock,
+    /// Turns the playback swing on or off
+    ToggleSwing,
+    /// Shortens or lengthens how long notes sound in playback
+    GateDown,
+    GateUp,
+    /// Turns the typing keys into a keyboard that enters notes
+    ToggleMusicalTyping,
+    /// Moves the view an octave up or down
+    OctaveUp,
+    OctaveDown,
+}
+
+/// Every virtual key code, whose `Debug` names are the key names of the bindings
+const KEY_CODES: [VirtualKeyCode; 152] = {
+    use glutin::VirtualKeyCode::*;
+    [
+        Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, A, B, C, D, E,
+        F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Escape, F1,
+        F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15, Snapshot,
+        Scroll, Pause, Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right,
+        Down, Back, Return, Space, Compose, Caret, Numlock, Numpad0, Numpad1,
+        Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+        AbntC1, AbntC2, Add, Apostrophe, Apps, At, Ax, Backslash, Calculator,
+        Capital, Colon, Comma, Convert, Decimal, Divide, Equals, Grave, Kana, Kanji,
+        LAlt, LBracket, LControl, LShift, LWin, Mail, MediaSelect, MediaStop, Minus,
+        Multiply, Mute, MyComputer, NavigateForward, NavigateBackward, NextTrack,
+        NoConvert, NumpadComma, NumpadEnter, NumpadEquals, OEM102, Period,
+        PlayPause, Power, PrevTrack, RAlt, RBracket, RControl, RShift, RWin,
+        Semicolon, Slash, Sleep, Stop, Subtract, Sysrq, Tab, Underline, Unlabeled,
+        VolumeDown, VolumeUp, Wake, WebBack, WebFavorites, WebForward, WebHome,
+        WebRefresh, WebSearch, WebStop, Yen, Copy, Paste, Cut,
+    ]
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Key {
+    /// A glutin `VirtualKeyCode` by name, e.g. `Space` or `Key1`
+    Code(String),
+    /// A raw scancode, for keys that have no virtual key code
+    Scancode(u32),
+}
+
+impl Key {
+    /// Whether the key can ever be pressed, which a misspelled name can't
+    fn is_known(&self) -> bool {
+        match *self {
+            Key::Code(ref name) => KEY_CODES.iter().any(|c| format!("{:?}", c) == *name),
+            Key::Scancode(_) => true,
+        }
+    }
+
+    fn matches(&self, code: Option<&str>, input: &glutin::KeyboardInput) -> bool {
+        match *self {
+            Key::Code(ref name) => code == Some(name.as_str()),
+            Key::Scancode(scancode) => scancode == input.scancode,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Binding {
+    pub key: Key,
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub alt: bool,
+}
+
+impl Binding {
+    fn code(name: &str) -> Self {
+        Binding {
+            key: Key::Code(name.to_string()),
+            shift: false,
+            ctrl: false,
+            alt: false,
+        }
+    }
+
+    fn shift(self) -> Self {
+        Binding { shift: true, ..self }
+    }
+
+    fn ctrl(self) -> Self {
+        Binding { ctrl: true, ..self }
+    }
+
+    fn alt(self) -> Self {
+        Binding { alt: true, ..self }
+    }
+
+    fn matches(&self, code: Option<&str>, input: &glutin::KeyboardInput) -> bool {
+        let m = input.modifiers;
+
+        self.key.matches(code, input)
+        && self.shift == m.shift
+        && self.ctrl == m.ctrl
+        && self.alt == m.alt
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyMap {
+    bindings: Vec<(Binding, Action)>,
+    /// Keys that enter notes in musical typing, and the degree of the
+    /// highlighted scale each one plays, from the octave in the middle of the view
+    #[serde(default = "default_typing_keys")]
+    typing: Vec<(Key, i16)>,
+}
+
+/// Two rows like the white keys of a piano, the upper one an octave above
+/// the lower one in a seven note scale
+fn default_typing_keys() -> Vec<(Key, i16)> {
+    let lower = ["Z", "X", "C", "V", "B", "N", "M", "Comma", "Period", "Slash"];
+    let upper = ["Q", "W", "E", "R", "T", "Y", "U", "I", "O", "P"];
+
+    lower.iter().enumerate()
+        .chain(upper.iter().enumerate().map(|(i, key)| (i + 7, key)))
+        .map(|(degree, key)| (Key::Code(key.to_string()), degree as i16))
+        .collect()
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::new()
+    }
+}
+
+impl KeyMap {
+    pub fn new() -> Self {
+        use self::Action::*;
+
+        let channel_keys = ["Key1", "Key2", "Key3", "Key4", "Key5", "Key6", "Key7", "Key8"];
+        let channels = channel_keys.iter()
+            .enumerate()
+            .flat_map(|(i, key)| vec![
+                (Binding::code(key).alt(), SetChannel(i as u16)),
+                (Binding::code(key).ctrl(), ActiveChannel(i as u16)),
+            ]);
+
+        KeyMap {
+            bindings: channels.chain(vec![
+                (Binding::code("Key1"), ArrowTool),
+                (Binding::code("Key2"), PencilTool),
+                (Binding::code("Key3"), EraserTool),
+                (Binding::code("Key4"), LineTool),
+                (Binding::code("Key5"), StepTool),
+                (Binding::code("Space"), PlayStop),
+                (Binding::code("Space").shift(), PlayFromStart),
+                (Binding::code("R").ctrl(), Record),
+                (Binding::code("D"), Delete),
+                (Binding::code("S"), Save),
+                (Binding::code("Escape"), Panic),
+                (Binding::code("F"), FitPitch),
+                (Binding::code("P"), TogglePreview),
+                (Binding::code("T"), ToggleTheme),
+                (Binding::code("R"), ToggleDrawReplaces),
+                (Binding::code("M"), CycleMeter),
+                (Binding::code("Left"), BeatBack),
+                (Binding::code("Right"), BeatForward),
+                (Binding::code("Tab").shift(), GridLineBack),
+                (Binding::code("Tab"), GridLineForward),
+                (Binding::code("Left").ctrl(), MeasureBack),
+                (Binding::code("Right").ctrl(), MeasureForward),
+                (Binding::code("Home"), GoToStart),
+                (Binding::code("End"), GoToEnd),
+                (Binding::code("G"), ToggleSnap),
+                (Binding::code("G").shift(), CycleSnapResolution),
+                (Binding::code("L"), QuantizeLengths),
+                (Binding::code("C"), RepeatSelection),
+                (Binding::code("C").shift(), RepeatSelectionTimes),
+                (Binding::code("C").ctrl(), Copy),
+                (Binding::code("V").ctrl(), Paste),
+                (Binding::code("B"), Retrograde),
+                (Binding::code("B").shift(), MirrorInMeasure),
+                (Binding::code("I"), Invert),
+                (Binding::code("X"), ToggleSelectMode),
+                (Binding::code("O"), ToggleLoop),
+                (Binding::code("N"), ToggleRounded),
+                (Binding::code("V"), SelectAtPlayhead),
+                (Binding::code("Return"), EditNote),
+                // + is = with shift on most layouts
+                (Binding::code("Equals"), ZoomIn),
+                (Binding::code("Equals").shift(), ZoomIn),
+                (Binding::code("Add"), ZoomIn),
+                (Binding::code("Minus"), ZoomOut),
+                (Binding::code("Subtract"), ZoomOut),
+                (Binding::code("Equals").ctrl(), ZoomInPitch),
+                (Binding::code("Equals").shift().ctrl(), ZoomInPitch),
+                (Binding::code("Add").ctrl(), ZoomInPitch),
+                (Binding::code("Minus").ctrl(), ZoomOutPitch),
+                (Binding::code("Subtract").ctrl(), ZoomOutPitch),
+                // Home and plain R are taken by GoToStart and ToggleDrawReplaces
+                (Binding::code("R").shift(), ResetView),
+                (Binding::code("F12"), ToggleEventLog),
+                (Binding::code("K"), ToggleKey),
+                (Binding::code("K").shift(), ToggleOctaveBands),
+                (Binding::code("H"), CycleScale),
+                (Binding::code("H").shift(), ToggleHeatmap),
+                (Binding::code("Q"), ToggleMute),
+                (Binding::code("Q").shift(), ToggleSolo),
+                (Binding::code("L").ctrl(), ToggleLock),
+                (Binding::code("W"), ToggleSwing),
+                (Binding::code("LBracket"), GateDown),
+                (Binding::code("RBracket"), GateUp),
+                (Binding::code("Grave"), ToggleMusicalTyping),
+                (Binding::code("Up"), OctaveUp),
+                (Binding::code("Down"), OctaveDown),
+            ]).collect(),
+            typing: default_typing_keys(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let keymap: KeyMap = ron::de::from_reader(file).map_err(|e| e.to_string())?;
+
+        let unknown: Vec<String> = keymap.bindings.iter().map(|(b, _)| &b.key)
+            .chain(keymap.typing.iter().map(|(key, _)| key))
+            .filter(|key| !key.is_known())
+            .map(|key| format!("{:?}", key))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!("unknown key names: {}", unknown.join(", ")))
+        }
+
+        Ok(keymap)
+    }
+
+    /// All the actions bound to a key press, in the order of the bindings
+    pub fn actions(&self, input: &glutin::KeyboardInput) -> Vec<Action> {
+        // Virtual key codes are matched by their `Debug` names, which are also
+        // the names used in the bindings file
+        let code = input.virtual_keycode.map(|c| format!("{:?}", c));
+
+        self.bindings.iter()
+            .filter(|(b, _)| b.matches(code.as_deref(), input))
+            .map(|&(_, a)| a)
+            .collect()
+    }
+
+    /// The scale degree a key plays in musical typing, if it's a typing key
+    pub fn typing_degree(&self, input: &glutin::KeyboardInput) -> Option<i16> {
+        let code = input.virtual_keycode.map(|c| format!("{:?}", c));
+
+        self.typing.iter()
+            .find(|(key, _)| key.matches(code.as_deref(), input))
+            .map(|&(_, degree)| degree)
+    }
+}
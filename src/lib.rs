@@ -0,0 +1,295 @@
+//! A microtonal piano roll editor. `PianoRollWidget` is the editor itself:
+//! feed it `Msg`s, made from window events by an `Intent`, draw it into a
+//! `renderer::Scene`, and carry out the `Command`s it returns.
+//! `app::Model` ties it to a score file and the system clipboard.
+
+#[macro_use] extern crate gfx;
+#[macro_use] extern crate serde_derive;
+
+use crate::pianoroll::Note;
+use glutin::ModifiersState;
+use std::time::Instant;
+use cgmath::Vector2;
+
+pub mod renderer;
+pub mod ui;
+mod font;
+pub mod tuning;
+pub mod export;
+pub mod pianoroll;
+pub mod keymap;
+pub mod app;
+
+pub use crate::pianoroll::{PianoRoll as PianoRollWidget, Score};
+
+pub fn duration_seconds(duration: ::std::time::Duration) -> f32 {
+    let int = duration.as_secs() as f32;
+    let frac = duration.subsec_nanos() as f32 * 1e-9;
+
+    int + frac
+}
+
+fn min_max<T: PartialOrd>(v0: T, v1: T) -> (T, T) {
+    if v0 > v1 { (v1, v0) }
+    else { (v0, v1) }
+}
+
+fn normalize_square(a0: Vector2<f32>, a1: Vector2<f32>) -> (Vector2<f32>, Vector2<f32>) {
+    let (x0, x1) = min_max(a0.x, a1.x);
+    let (y0, y1) = min_max(a0.y, a1.y);
+
+    (
+        [x0, y0].into(),
+        [x1, y1].into(),
+    )
+}
+
+/// Even-odd test of whether a point is inside a polygon
+fn point_in_polygon(point: Vector2<f32>, polygon: &[Vector2<f32>]) -> bool {
+    let mut inside = false;
+
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+
+        if (a.y > point.y) != (b.y > point.y)
+        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside
+        }
+    }
+
+    inside
+}
+
+fn rects_overlap(a0: Vector2<f32>, a1: Vector2<f32>, b0: Vector2<f32>, b1: Vector2<f32>) -> bool {
+    a0.x < b1.x && a1.x > b0.x &&
+    a0.y < b1.y && a1.y > b0.y
+}
+
+/// Whether the rect `a` contains the rect `b`
+fn rect_contains(a0: Vector2<f32>, a1: Vector2<f32>, b0: Vector2<f32>, b1: Vector2<f32>) -> bool {
+    a0.x <= b0.x && a1.x >= b1.x &&
+    a0.y <= b0.y && a1.y >= b1.y
+}
+
+#[derive(Debug, Clone)]
+pub enum Msg {
+    WindowEvent(glutin::WindowEvent),
+    MouseWheel {
+        position: Vector2<f32>,
+        modifiers: ModifiersState,
+        delta: (f32, f32),
+    },
+    LeftPressed {
+        position: Vector2<f32>,
+        modifiers: ModifiersState,
+    },
+    LeftReleased {
+        position: Vector2<f32>,
+    },
+    /// Sent right after the `LeftPressed` of a second click
+    LeftDoubleClick {
+        position: Vector2<f32>,
+        modifiers: ModifiersState,
+    },
+    LeftDrag {
+        position: Vector2<f32>,
+        vector: Vector2<f32>,
+    },
+    RightPressed {
+        position: Vector2<f32>,
+    },
+    RightReleased {
+        position: Vector2<f32>,
+    },
+    RightDrag {
+        vector: Vector2<f32>,
+    },
+    /// Pans the view just like `RightDrag`
+    MiddleDrag {
+        vector: Vector2<f32>,
+    },
+    Time(std::time::Duration),
+    /// A key pressed on an input device, as a pitch of the score
+    InputNoteOn {
+        pitch: i16,
+        velocity: u8,
+        at: Instant,
+    },
+    InputNoteOff {
+        pitch: i16,
+        at: Instant,
+    },
+}
+
+/// The longest time between the presses of a double click
+const DOUBLE_CLICK_TIME: std::time::Duration = std::time::Duration::from_millis(400);
+/// How far apart in pixels the presses of a double click may be
+const DOUBLE_CLICK_DISTANCE: f32 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct Intent {
+    mailbox: Vec<Msg>,
+    screen_size: Vector2<f32>,
+    mouse_pos: Vector2<f32>,
+    lbutton_pressed: Option<std::time::Instant>,
+    rbutton_pressed: Option<std::time::Instant>,
+    mbutton_pressed: Option<std::time::Instant>,
+    /// The time and position of the last left press, to detect double clicks
+    last_click: Option<(std::time::Instant, Vector2<f32>)>,
+}
+
+impl Default for Intent {
+    fn default() -> Self {
+        Intent::new()
+    }
+}
+
+impl Intent {
+    pub fn new() -> Self {
+        Intent {
+            mailbox: vec![],
+            screen_size: [1024.0, 768.0].into(),
+            mouse_pos: Vector2::new(0.0, 0.0),
+            lbutton_pressed: None,
+            rbutton_pressed: None,
+            mbutton_pressed: None,
+            last_click: None,
+        }
+    }
+
+    pub fn intent(&mut self, event: glutin::WindowEvent) {
+        use glutin::WindowEvent::*;
+        match event {
+            MouseWheel {delta, modifiers, ..} => {
+                if let glutin::MouseScrollDelta::LineDelta(x, y) = delta {
+                    self.mailbox.push(Msg::MouseWheel {
+                        position: self.mouse_pos,
+                        modifiers,
+                        delta: (x, y),
+                    })
+                }
+            },
+            MouseInput { device_id, modifiers, button, state, ..} => {
+                use glutin::{MouseButton as Mb, ElementState as Es};
+                match (button, state) {
+                    (Mb::Right, Es::Pressed) => {
+                        self.rbutton_pressed = Some(std::time::Instant::now());
+                        self.mailbox.push(Msg::RightPressed {
+                            position: self.mouse_pos,
+                        })
+                    },
+                    (Mb::Right, Es::Released) => {
+                        self.rbutton_pressed = None;
+                        self.mailbox.push(Msg::RightReleased {
+                            position: self.mouse_pos,
+                        })
+                    },
+                    (Mb::Middle, Es::Pressed) => {
+                        self.mbutton_pressed = Some(std::time::Instant::now())
+                    },
+                    (Mb::Middle, Es::Released) => {
+                        self.mbutton_pressed = None
+                    },
+                    (Mb::Left, Es::Pressed) => {
+                        let now = std::time::Instant::now();
+                        self.lbutton_pressed = Some(now);
+                        self.mailbox.push(Msg::LeftPressed {
+                            position: self.mouse_pos,
+                            modifiers,
+                        });
+
+                        let double = self.last_click.is_some_and(|(time, pos)| {
+                            let d = self.mouse_pos - pos;
+                            now - time <= DOUBLE_CLICK_TIME
+                                && d.x.abs() <= DOUBLE_CLICK_DISTANCE
+                                && d.y.abs() <= DOUBLE_CLICK_DISTANCE
+                        });
+
+                        if double {
+                            self.mailbox.push(Msg::LeftDoubleClick {
+                                position: self.mouse_pos,
+                                modifiers,
+                            });
+                            // A third click starts over instead of making another double click
+                            self.last_click = None
+                        }
+                        else {
+                            self.last_click = Some((now, self.mouse_pos))
+                        }
+                    },
+                    (Mb::Left, Es::Released) => {
+                        self.lbutton_pressed = None;
+                        self.mailbox.push(Msg::LeftReleased {
+                            position: self.mouse_pos,
+                        })
+                    },
+                    _ => {},
+                }
+
+                self.mailbox.push(Msg::WindowEvent(
+                    MouseInput { device_id, modifiers, button, state }
+                ));
+
+            },
+            CursorMoved { position, ..} => {
+                let position = Vector2::new(position.x as f32, self.screen_size.y - position.y as f32);
+
+                if let Some(instant) = self.lbutton_pressed {
+                    if instant.elapsed() >= std::time::Duration::from_millis(50) {
+                        self.mailbox.push(Msg::LeftDrag {
+                            position,
+                            vector: position - self.mouse_pos
+                        });
+                    }
+                }
+                if let Some(instant) = self.rbutton_pressed {
+                    if instant.elapsed() >= std::time::Duration::from_millis(50) {
+                        self.mailbox.push(Msg::RightDrag {
+                            vector: position - self.mouse_pos
+                        });
+                    }
+                }
+                if let Some(instant) = self.mbutton_pressed {
+                    if instant.elapsed() >= std::time::Duration::from_millis(50) {
+                        self.mailbox.push(Msg::MiddleDrag {
+                            vector: position - self.mouse_pos
+                        });
+                    }
+                }
+                self.mouse_pos = position;
+            },
+            Resized(sz) => {
+                self.screen_size = [sz.width as f32, sz.height as f32].into();
+                self.mailbox.push(Msg::WindowEvent(Resized(sz)))
+            },
+            ev =>
+                self.mailbox.push(Msg::WindowEvent(ev))
+        }
+    }
+
+    pub fn messages(&mut self) -> Vec<Msg> {
+        let mut mb = vec![];
+        ::std::mem::swap(&mut mb, &mut self.mailbox);
+        mb
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// A note and the moment it's meant to sound at
+    NoteOn(Note, Instant),
+    NoteOff(Note, Instant),
+    AllNotesOff,
+    Stop,
+    Save,
+    /// The selection was copied, `PianoRoll::clipboard_text` has it as text
+    Copy,
+    /// Asks for the text on the system clipboard, to be given to `PianoRoll::paste_text`
+    Paste,
+    /// The autosaved score was chosen over the file, to be read and given to
+    /// `PianoRoll::load_score`
+    Recover,
+    SubTime,
+    UnsubTime,
+}
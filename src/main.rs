@@ -1,346 +1,848 @@
 #![allow(dead_code)]
 
-#[macro_use] extern crate gfx;
-#[macro_use] extern crate serde_derive;
-
-use crate::pianoroll::Note;
-use glutin::ModifiersState;
 use std::time::Instant;
 use gfx::Device;
 use gfx_window_glutin::init as gfx_init;
-use cgmath::{Vector2, ElementWise};
 
-use crate::renderer::{ColorFormat, DepthFormat};
-use crate::pianoroll::PianoRoll;
+use dieseq::renderer::{self, ColorFormat, DepthFormat};
+use dieseq::pianoroll;
+use dieseq::app::{draw, model, Model};
+use dieseq::{export, keymap, tuning, ui, Command, Intent, Msg};
 
-mod renderer;
-mod ui;
-mod pianoroll;
+#[cfg(feature = "midi")]
+mod midi;
+#[cfg(feature = "synth")]
+mod synth;
 
-fn duration_seconds(duration: ::std::time::Duration) -> f32 {
-    let int = duration.as_secs() as f32;
-    let frac = duration.subsec_nanos() as f32 * 1e-9;
+/// Parses a size like `1024x768`
+fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.split('x');
+    let width = parts.next()?.parse().ok()?;
+    let height = parts.next()?.parse().ok()?;
 
-    int + frac
+    if parts.next().is_some() || width == 0 || height == 0 { None }
+    else { Some((width, height)) }
 }
 
-fn min_max<T: PartialOrd>(v0: T, v1: T) -> (T, T) {
-    if v0 > v1 { (v1, v0) }
-    else { (v0, v1) }
+/// Parses line widths like `2,4`, or `2` for a thick line twice as wide
+fn parse_line_widths(text: &str) -> Option<(f32, f32)> {
+    let mut parts = text.split(',').map(|w| w.trim().parse::<f32>().ok().filter(|&w| w > 0.0));
+    let thin = parts.next()??;
+    let thick = match parts.next() {
+        Some(thick) => thick?,
+        None => 2.0 * thin,
+    };
+
+    if parts.next().is_some() { None }
+    else { Some((thin, thick)) }
 }
 
-fn normalize_square(a0: Vector2<f32>, a1: Vector2<f32>) -> (Vector2<f32>, Vector2<f32>) {
-    let (x0, x1) = min_max(a0.x, a1.x);
-    let (y0, y1) = min_max(a0.y, a1.y);
-
-    (
-        [x0, y0].into(),
-        [x1, y1].into(),
-    )
+/// Parses a score length like `32` or `32,3`, in measures and beats per
+/// measure, with 4 beats when they're left out
+fn parse_length(text: &str) -> Option<(u16, u8)> {
+    let mut parts = text.split(',').map(|p| p.trim());
+    let measures = parts.next()?.parse().ok().filter(|&m| m > 0)?;
+    let beats = match parts.next() {
+        Some(beats) => beats.parse().ok().filter(|&b| b > 0)?,
+        None => 4,
+    };
+
+    if parts.next().is_some() { None }
+    else { Some((measures, beats)) }
 }
 
-fn rects_overlap(a0: Vector2<f32>, a1: Vector2<f32>, b0: Vector2<f32>, b1: Vector2<f32>) -> bool {
-    a0.x < b1.x && a1.x > b0.x &&
-    a0.y < b1.y && a1.y > b0.y
+/// Parses comma separated pitches like `0,5,10` into pitch classes
+fn parse_pitch_classes(text: &str) -> Option<Vec<i16>> {
+    text.split(',')
+        .map(|p| p.trim().parse::<i16>().ok().map(|p| p.rem_euclid(31)))
+        .collect()
 }
 
+// #[derive(Debug, Clone, Serialize, Deserialize)]
+// struct Project {
+//     score: Score,
+//     grid: ui::Grid,
+//     play_pos: f32,
+// }
 
+struct MainState {
 
-#[derive(Debug, Clone)]
-pub enum Msg {
-    WindowEvent(glutin::WindowEvent),
-    MouseWheel {
-        position: Vector2<f32>,
-        modifiers: ModifiersState,
-        delta: (f32, f32),
-    },
-    LeftPressed {
-        position: Vector2<f32>,
-    },
-    LeftReleased {
-        position: Vector2<f32>,
-    },
-    LeftDrag {
-        position: Vector2<f32>,
-        vector: Vector2<f32>,
-    },
-    RightDrag {
-        vector: Vector2<f32>,
-    },
-    Time(std::time::Duration),
 }
 
-#[derive(Debug, Clone)]
-struct Intent {
-    mailbox: Vec<Msg>,
-    screen_size: Vector2<f32>,
-    mouse_pos: Vector2<f32>,
-    lbutton_pressed: Option<std::time::Instant>,
-    rbutton_pressed: Option<std::time::Instant>,
+impl MainState {
+
 }
 
-impl Intent {
-    fn new() -> Self {
-        Intent {
-            mailbox: vec![],
-            screen_size: [1024.0, 768.0].into(),
-            mouse_pos: Vector2::new(0.0, 0.0),
-            lbutton_pressed: None,
-            rbutton_pressed: None,
-        }
+enum Output {
+    Med(::std::process::Child),
+    #[cfg(feature = "midi")]
+    Midi(midi::MidiOut),
+    #[cfg(feature = "synth")]
+    Synth(synth::Synth),
+}
+
+impl Output {
+    fn med() -> Self {
+        use std::process::{Command, Stdio};
+        use std::io::Write;
+
+        let mut med = Command::new("med")
+            .args(["--pipe"])
+            .stdin(Stdio::piped())
+            .spawn().unwrap();
+
+        let stdin = med.stdin.as_mut().unwrap();
+        stdin.write_all(b"31edo\n").unwrap();
+
+        Output::Med(med)
     }
 
-    fn intent(&mut self, event: glutin::WindowEvent) {
-        use glutin::WindowEvent::*;
-        match event {
-            MouseWheel {delta, modifiers, ..} => {
-                if let glutin::MouseScrollDelta::LineDelta(x, y) = delta {
-                    self.mailbox.push(Msg::MouseWheel {
-                        position: self.mouse_pos,
-                        modifiers,
-                        delta: (x, y),
-                    })
-                }
-            },
-            MouseInput { device_id, modifiers, button, state, ..} => {
-                use glutin::{MouseButton as Mb, ElementState as Es};
-                match (button, state) {
-                    (Mb::Right, Es::Pressed) => {
-                        self.rbutton_pressed = Some(std::time::Instant::now())
+    fn send(&mut self, c: Command) {
+        match self {
+            Output::Med(med) => {
+                use std::io::Write;
+                let stdin = med.stdin.as_mut().unwrap();
+
+                match c {
+                    Command::NoteOn(n, _) => {
+                        match n.pitch / 31 {
+                            0 => drop(stdin.write_all(format!("0a{}_+\n", n.pitch % 31).as_bytes())),
+                            1 => drop(stdin.write_all(format!("0b{}_+\n", n.pitch % 31).as_bytes())),
+                            2 => drop(stdin.write_all(format!("0c{}_+\n", n.pitch % 31).as_bytes())),
+                            3 => drop(stdin.write_all(format!("0d{}_+\n", n.pitch % 31).as_bytes())),
+                            4 => drop(stdin.write_all(format!("0e{}_+\n", n.pitch % 31).as_bytes())),
+                            5 => drop(stdin.write_all(format!("0f{}_+\n", n.pitch % 31).as_bytes())),
+                            6 => drop(stdin.write_all(format!("0g{}_+\n", n.pitch % 31).as_bytes())),
+                            7 => drop(stdin.write_all(format!("0h{}_+\n", n.pitch % 31).as_bytes())),
+                            _ => (),
+                        }
                     },
-                    (Mb::Right, Es::Released) => {
-                        self.rbutton_pressed = None
+                    Command::NoteOff(n, _) => {
+                        match n.pitch / 31 {
+                            0 => drop(stdin.write_all(format!("0a{}-\n", n.pitch % 31).as_bytes())),
+                            1 => drop(stdin.write_all(format!("0b{}-\n", n.pitch % 31).as_bytes())),
+                            2 => drop(stdin.write_all(format!("0c{}-\n", n.pitch % 31).as_bytes())),
+                            3 => drop(stdin.write_all(format!("0d{}-\n", n.pitch % 31).as_bytes())),
+                            4 => drop(stdin.write_all(format!("0e{}-\n", n.pitch % 31).as_bytes())),
+                            5 => drop(stdin.write_all(format!("0f{}-\n", n.pitch % 31).as_bytes())),
+                            6 => drop(stdin.write_all(format!("0g{}-\n", n.pitch % 31).as_bytes())),
+                            7 => drop(stdin.write_all(format!("0h{}-\n", n.pitch % 31).as_bytes())),
+                            _ => (),
+                        }
                     },
-                    (Mb::Left, Es::Pressed) => {
-                        self.lbutton_pressed = Some(std::time::Instant::now());
-                        self.mailbox.push(Msg::LeftPressed {
-                            position: self.mouse_pos,
-                        })
+                    Command::AllNotesOff => {
+                        for octave in "abcdefgh".chars() {
+                            for pitch in 0..31 {
+                                drop(stdin.write_all(format!("0{}{}-\n", octave, pitch).as_bytes()))
+                            }
+                        }
                     },
-                    (Mb::Left, Es::Released) => {
-                        self.lbutton_pressed = None;
-                        self.mailbox.push(Msg::LeftReleased {
-                            position: self.mouse_pos,
-                        })
+                    Command::Stop => {
+                        drop(stdin.write_all(b"s\n"))
                     },
-                    _ => {},
+                    _ => (),
                 }
-
-                self.mailbox.push(Msg::WindowEvent(
-                    MouseInput { device_id, modifiers, button, state }
-                ));
-
             },
-            CursorMoved { position, ..} => {
-                let position = Vector2::new(position.x as f32, self.screen_size.y - position.y as f32);
-
-                if let Some(instant) = self.lbutton_pressed {
-                    if instant.elapsed() >= std::time::Duration::from_millis(50) {
-                        self.mailbox.push(Msg::LeftDrag {
-                            position,
-                            vector: position - self.mouse_pos
-                        });
-                    }
+            #[cfg(feature = "midi")]
+            Output::Midi(midi) => {
+                match c {
+                    Command::NoteOn(n, _) => midi.note_on(n),
+                    Command::NoteOff(n, _) => midi.note_off(n),
+                    Command::Stop | Command::AllNotesOff => midi.all_notes_off(),
+                    _ => (),
                 }
-                if let Some(instant) = self.rbutton_pressed {
-                    if instant.elapsed() >= std::time::Duration::from_millis(50) {
-                        self.mailbox.push(Msg::RightDrag {
-                            vector: position - self.mouse_pos
-                        });
-                    }
-                }
-                self.mouse_pos = position;
             },
-            Resized(sz) => {
-                self.screen_size = [sz.width as f32, sz.height as f32].into();
-                self.mailbox.push(Msg::WindowEvent(Resized(sz)))
+            #[cfg(feature = "synth")]
+            Output::Synth(synth) => {
+                match c {
+                    Command::NoteOn(n, _) => synth.note_on(n),
+                    Command::NoteOff(n, _) => synth.note_off(n),
+                    Command::Stop | Command::AllNotesOff => synth.all_notes_off(),
+                    _ => (),
+                }
             },
-            ev =>
-                self.mailbox.push(Msg::WindowEvent(ev))
         }
     }
+}
 
-    fn messages(&mut self) -> Vec<Msg> {
-        let mut mb = vec![];
-        ::std::mem::swap(&mut mb, &mut self.mailbox);
-        mb
-    }
+struct Backend {
+    output: Output,
+    #[cfg(feature = "midi")]
+    input: Option<midi::MidiIn>,
+    moment: Option<Instant>,
+    pending: Vec<(Instant, Command)>,
 }
 
+impl Backend {
+    fn new(output: Output) -> Self {
+        Backend {
+            output,
+            #[cfg(feature = "midi")]
+            input: None,
+            moment: None,
+            pending: vec![],
+        }
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Command {
-    NoteOn(Note),
-    NoteOff(Note),
-    Stop,
-    Save,
-    SubTime,
-    UnsubTime,
-}
+    #[cfg(feature = "midi")]
+    fn set_input(&mut self, input: midi::MidiIn) {
+        self.input = Some(input)
+    }
 
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// struct Project {
-//     score: Score,
-//     grid: ui::Grid,
-//     play_pos: f32,
-// }
+    /// Whether playback is running or notes are still scheduled
+    fn is_busy(&self) -> bool {
+        self.moment.is_some() || !self.pending.is_empty()
+    }
+
+    fn subscriptions(&mut self) -> Vec<Msg> {
+        #[allow(unused_mut)]
+        let mut msgs: Vec<Msg> = self.moment.map(|i| Msg::Time(i.elapsed())).into_iter().collect();
+
+        #[cfg(feature = "midi")]
+        {
+            if let Some(ref mut input) = self.input {
+                msgs.extend(input.messages())
+            }
+        }
+
+        msgs
+    }
+
+    fn run(&mut self, commands: &mut Vec<Command>) {
+        let now = Instant::now();
 
-struct Model {
-    file: Option<::std::path::PathBuf>,
-    pianoroll: PianoRoll,
+        for c in commands.drain(..) {
+            match c {
+                Command::NoteOn(_, at) | Command::NoteOff(_, at) if at > now => {
+                    self.pending.push((at, c))
+                },
+                Command::Stop | Command::AllNotesOff => {
+                    // Notes scheduled ahead must not start after the stop, but
+                    // the scheduled releases still have to go out
+                    let mut pending = vec![];
+                    ::std::mem::swap(&mut pending, &mut self.pending);
+                    for (_, p) in pending {
+                        if let Command::NoteOff(_, _) = p {
+                            self.send(p)
+                        }
+                    }
+
+                    self.send(c)
+                },
+                c => self.send(c),
+            }
+        }
+
+        self.pending.sort_by_key(|&(at, _)| at);
+        let due = self.pending.iter().take_while(|&&(at, _)| at <= now).count();
+        let due: Vec<_> = self.pending.drain(..due).collect();
+        for (_, c) in due {
+            self.send(c)
+        }
+    }
+
+    fn send(&mut self, c: Command) {
+        match c {
+            // Handled by `model`
+            Command::Save | Command::Copy | Command::Paste | Command::Recover => (),
+            Command::SubTime => {
+                self.moment = Some(Instant::now())
+            },
+            Command::UnsubTime => {
+                self.moment = None
+            },
+            c => self.output.send(c),
+        }
+    }
 }
 
-impl Model {
-    fn new() -> Self {
-        Self::with_file(None)
+/// The tuning chosen on the command line, or None after printing why it's invalid
+fn tuning_arg(matches: &clap::ArgMatches) -> Option<tuning::Tuning> {
+    match matches.value_of("tuning") {
+        Some(text) => match tuning::Tuning::parse(text) {
+            Some(tuning) => Some(tuning),
+            None => {
+                eprintln!("Invalid tuning: {}", text);
+                None
+            }
+        },
+        None => Some(tuning::Tuning::default()),
     }
+}
 
-    fn with_file(path: Option<::std::path::PathBuf>) -> Self {
-        Model {
-            file: path,
-            pianoroll: PianoRoll::new(),
+/// Opens the output chosen on the command line, or prints why it can't be
+/// opened and returns None
+#[allow(unused_variables)]
+fn open_output(matches: &clap::ArgMatches) -> Option<Output> {
+    let tuning = tuning_arg(matches)?;
+
+    #[allow(unused_mut)]
+    let mut output = None;
+    #[cfg(feature = "synth")]
+    {
+        if matches.is_present("synth") {
+            let wave = match matches.value_of("synth") {
+                Some("saw") => synth::Wave::Saw,
+                _ => synth::Wave::Sine,
+            };
+            let curve = match matches.value_of("velocity-curve") {
+                Some("exp") => synth::VelocityCurve::Exponential,
+                Some("log") => synth::VelocityCurve::Logarithmic,
+                _ => synth::VelocityCurve::Linear,
+            };
+
+            match synth::Synth::new(wave, tuning, curve) {
+                Ok(synth) => output = Some(Output::Synth(synth)),
+                Err(e) => {
+                    eprintln!("Failed to start the synth: {}", e);
+                    return None
+                }
+            }
+        }
+    }
+    #[cfg(feature = "midi")]
+    {
+        if let Some(port) = matches.value_of("midi-port").filter(|_| output.is_none()) {
+            let port = port.parse().map_err(|_| format!("Invalid MIDI port: {}", port));
+            match port.and_then(|port| midi::MidiOut::open(port, tuning)) {
+                Ok(midi) => output = Some(Output::Midi(midi)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Available MIDI ports:");
+                    for (i, name) in midi::MidiOut::port_names().iter().enumerate() {
+                        eprintln!("  {}: {}", i, name);
+                    }
+                    return None
+                }
+            }
         }
     }
 
-    fn from_file(path: ::std::path::PathBuf) -> Self {
-        let mut file = std::fs::File::open(&path).unwrap();
-        // let proj: Project = ron::de::from_reader(&mut file).unwrap();
+    Some(output.unwrap_or_else(Output::med))
+}
 
-        Model {
-            file: Some(path.clone()),
-            pianoroll: PianoRoll::new(),
-            ..Self::with_file(Some(path))
+/// Connects the MIDI input chosen on the command line to the backend, or
+/// prints why it can't be opened and returns false
+#[cfg(feature = "midi")]
+fn connect_input(matches: &clap::ArgMatches, backend: &mut Backend) -> bool {
+    let port = match matches.value_of("midi-in") {
+        Some(port) => port,
+        None => return true,
+    };
+    let tuning = match tuning_arg(matches) {
+        Some(tuning) => tuning,
+        None => return false,
+    };
+
+    let port = port.parse().map_err(|_| format!("Invalid MIDI input port: {}", port));
+    match port.and_then(|port| midi::MidiIn::open(port, tuning)) {
+        Ok(input) => {
+            backend.set_input(input);
+            true
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Available MIDI input ports:");
+            for (i, name) in midi::MidiIn::port_names().iter().enumerate() {
+                eprintln!("  {}: {}", i, name);
+            }
+            false
         }
     }
 }
 
-fn model(mut model: Model, msg: Msg, cmds: &mut Vec<Command>) -> Model {
-    model.pianoroll.model(msg, cmds);
+/// Plays the whole score through an output, without a window
+fn play_headless(mut the_model: Model, output: Output) {
+    let mut backend = Backend::new(output);
+    let mut cmds = vec![];
 
-    model
-}
+    the_model.pianoroll.play_from_start(&mut cmds);
+    backend.run(&mut cmds);
 
-fn draw(model: &Model, screen_size: [f32; 2], renderer: &mut renderer::Renderer, scene: &mut renderer::Scene) {
-    renderer.clear(ui::Style::Dark.base3());
-    scene.clear();
+    // Playback stops by itself shortly after the last note, and the backend
+    // is done once the releases scheduled by then are sent
+    while backend.is_busy() {
+        for s in backend.subscriptions() {
+            the_model = model(the_model, s, &mut cmds);
+        }
 
-    model.pianoroll.draw(screen_size, scene)
+        backend.run(&mut cmds);
+        ::std::thread::sleep(::std::time::Duration::from_millis(2))
+    }
 }
 
-struct MainState {
+pub fn main() {
+    let app = clap::App::new("Dieseq")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A mictotonal sequencer")
+        .arg(
+            clap::Arg::with_name("file")
+            .help("Dieseq project file")
+            .index(1)
+        )
+        .arg(
+            clap::Arg::with_name("keys")
+            .help("Key bindings file")
+            .long("keys")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("lookahead")
+            .help("How early notes are sent to the backend during playback, in milliseconds")
+            .long("lookahead")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("click-threshold")
+            .help("How far in pixels the mouse may move during a click before it counts as a drag (default 4)")
+            .long("click-threshold")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("scale")
+            .help("Scale whose lines the grid highlights: major, minor, dorian, neutral, harmonic, or pitch classes like 0,5,10,13,18,23,28")
+            .long("scale")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("key")
+            .help("Pitch classes of the key to shade on the grid, from 0 to 30, e.g. 0,5,10,13,18,23,28 for C major")
+            .long("key")
+            .takes_value(true)
+            .value_name("PITCHES")
+        )
+        .arg(
+            clap::Arg::with_name("line-width")
+            .help("Width of the thin and thick grid lines in pixels, e.g. 2,4 (default one and two physical pixels)")
+            .long("line-width")
+            .takes_value(true)
+            .value_name("THIN[,THICK]")
+        )
+        .arg(
+            clap::Arg::with_name("feather")
+            .help("Soften note edges by fading their borders out over this many pixels, up to 4 (default 0, hard edges)")
+            .long("feather")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("subdivision")
+            .help("Divide every beat into this many parts with faint lines the pencil snaps to, e.g. 3 for triplets")
+            .long("subdivision")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("swing")
+            .help("Move every second subdivision line later by this fraction of a subdivision, from 0 to 1")
+            .long("swing")
+            .takes_value(true)
+            .requires("subdivision")
+        )
+        .arg(
+            clap::Arg::with_name("length")
+            .help("Start a new score this many measures long, optionally with the beats per measure, e.g. 32,3 (default 4 beats)")
+            .long("length")
+            .takes_value(true)
+            .value_name("MEASURES[,BEATS]")
+        )
+        .arg(
+            clap::Arg::with_name("max-notes")
+            .help("Load at most this many notes of a score, the earliest ones, and refuse pastes and repeats that would go over (default 500000)")
+            .long("max-notes")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("gate")
+            .help("How long notes sound in playback as a part of their length, from 0.1 (staccato) to 2 (legato, default 1)")
+            .long("gate")
+            .takes_value(true)
+            .global(true)
+        )
+        .arg(
+            clap::Arg::with_name("playback-swing")
+            .help("Play every second subdivision (eighths without --subdivision) this part of one late, from 0 to 0.9. The notes don't move.")
+            .long("playback-swing")
+            .takes_value(true)
+            .global(true)
+        )
+        .arg(
+            clap::Arg::with_name("count-in")
+            .help("Measures of metronome clicks before playing or recording starts (default 0)")
+            .long("count-in")
+            .takes_value(true)
+            .value_name("MEASURES")
+        )
+        .arg(
+            clap::Arg::with_name("preview-channel")
+            .help("Channel to play note previews on, instead of the note's own")
+            .long("preview-channel")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("autosave")
+            .help("Save unsaved edits to <file>.autosave after this many seconds, 0 to turn autosaving off (default 60)")
+            .long("autosave")
+            .takes_value(true)
+            .value_name("SECONDS")
+        )
+        .arg(
+            clap::Arg::with_name("autosave-edits")
+            .help("Autosave after this many edits, even if the interval hasn't passed (default 50)")
+            .long("autosave-edits")
+            .takes_value(true)
+            .value_name("EDITS")
+        )
+        .arg(
+            clap::Arg::with_name("export")
+            .help("Render the score to a PNG file instead of opening a window")
+            .long("export")
+            .takes_value(true)
+            .value_name("PNG")
+        )
+        .arg(
+            clap::Arg::with_name("export-size")
+            .help("Size of the exported image")
+            .long("export-size")
+            .takes_value(true)
+            .value_name("WIDTHxHEIGHT")
+            .default_value("1024x768")
+        )
+        .arg(
+            clap::Arg::with_name("reference")
+            .help("Compare the exported image to a reference PNG and exit with an error if they differ")
+            .long("reference")
+            .takes_value(true)
+            .value_name("PNG")
+            .requires("export")
+        )
+        .arg(
+            clap::Arg::with_name("tolerance")
+            .help("How much a color channel may differ from the reference")
+            .long("tolerance")
+            .takes_value(true)
+            .default_value("2")
+        )
+        .arg(
+            clap::Arg::with_name("tuning")
+            .help("Reference pitch and its frequency for the MIDI and synth outputs (default 124=261.6256, the middle C at 261.63 Hz)")
+            .long("tuning")
+            .takes_value(true)
+            .value_name("PITCH=HZ")
+            .global(true)
+        )
+        .subcommand(
+            clap::SubCommand::with_name("play")
+            .about("Play a score without opening a window, then exit")
+            .arg(
+                clap::Arg::with_name("file")
+                .help("Dieseq project file")
+                .required(true)
+                .index(1)
+            )
+        );
+    #[cfg(feature = "midi")]
+    let app = app
+        .arg(
+            clap::Arg::with_name("midi-port")
+            .help("Play through the MIDI output port with this number instead of med")
+            .long("midi-port")
+            .takes_value(true)
+            .global(true)
+        )
+        .arg(
+            clap::Arg::with_name("midi-in")
+            .help("Record from the MIDI input port with this number (Ctrl+R starts recording)")
+            .long("midi-in")
+            .takes_value(true)
+        )
+        .arg(
+            clap::Arg::with_name("quantize-recording")
+            .help("Move recorded notes to the nearest beat, or subdivision with --subdivision")
+            .long("quantize-recording")
+        );
+    #[cfg(feature = "synth")]
+    let app = app
+        .arg(
+            clap::Arg::with_name("synth")
+            .help("Play through the built-in synth instead of med")
+            .long("synth")
+            .takes_value(true)
+            .global(true)
+            .min_values(0)
+            .possible_values(&["sine", "saw"])
+            .value_name("WAVE")
+        )
+        .arg(
+            clap::Arg::with_name("velocity-curve")
+            .help("How the synth maps note velocity to loudness (default linear)")
+            .long("velocity-curve")
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["linear", "exp", "log"])
+            .requires("synth")
+        );
+    let matches = app.get_matches();
+
+    let play = matches.subcommand_matches("play");
+    let file = match play {
+        Some(play) => play.value_of("file"),
+        None => matches.value_of("file"),
+    };
+    let keymap =
+        if let Some(path) = matches.value_of("keys") {
+            match keymap::KeyMap::from_file(::std::path::Path::new(path)) {
+                Ok(keymap) => keymap,
+                Err(e) => {
+                    eprintln!("Failed to load key bindings: {}", e);
+                    return
+                }
+            }
+        }
+        else {
+            keymap::KeyMap::new()
+        };
 
-}
+    let blank_score = match matches.value_of("length") {
+        Some(length) => match parse_length(length) {
+            Some((measures, beats)) => pianoroll::Score::with_length(measures, 16, beats),
+            None => {
+                eprintln!("Invalid length: {}", length);
+                return
+            }
+        },
+        None => pianoroll::Score::new(),
+    };
+
+    let max_notes = match matches.value_of("max-notes") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Invalid note limit: {}", n);
+                return
+            }
+        },
+        None => pianoroll::DEFAULT_MAX_NOTES,
+    };
 
-impl MainState {
+    let mut the_model =
+        if let Some(path) = file {
+            let path = ::std::path::Path::new(path);
 
-}
+            if path.is_file() {
+                match Model::from_file(path.to_owned(), max_notes) {
+                    Ok(model) => model,
+                    Err(e) => {
+                        eprintln!("Failed to load {}: {}", path.to_string_lossy(), e);
+                        return
+                    }
+                }
+            }
+            else if !path.exists() && play.is_none() {
+                Model::with_file(Some(path.to_owned())).with_score(blank_score)
+            }
+            else {
+                eprintln!("Invalid file name: {}", path.to_string_lossy());
+                return
+            }
+        }
+        else {
+            Model::new().with_score(blank_score)
+        };
 
-struct Backend {
-    med: ::std::process::Child,
-    moment: Option<Instant>,
-}
+    the_model.pianoroll.set_keymap(keymap);
+    the_model.pianoroll.set_max_notes(max_notes);
+    if let Some(ms) = matches.value_of("lookahead") {
+        match ms.parse() {
+            Ok(ms) => the_model.pianoroll.set_lookahead(::std::time::Duration::from_millis(ms)),
+            Err(_) => {
+                eprintln!("Invalid lookahead: {}", ms);
+                return
+            }
+        }
+    }
 
-impl Backend {
-    fn new() -> Self {
-        use std::process::{Command, Stdio};
-        use std::io::Write;
+    if let Some(pixels) = matches.value_of("click-threshold") {
+        match pixels.parse() {
+            Ok(pixels) => the_model.pianoroll.set_click_threshold(pixels),
+            Err(_) => {
+                eprintln!("Invalid click threshold: {}", pixels);
+                return
+            }
+        }
+    }
 
-        let mut med = Command::new("med")
-            .args(&["--pipe"])
-            .stdin(Stdio::piped())
-            .spawn().unwrap();
+    if let Some(pixels) = matches.value_of("feather") {
+        match pixels.parse() {
+            Ok(pixels) => the_model.pianoroll.set_note_feather(pixels),
+            Err(_) => {
+                eprintln!("Invalid feather: {}", pixels);
+                return
+            }
+        }
+    }
 
-        let stdin = med.stdin.as_mut().unwrap();
-        stdin.write_all(format!("31edo\n").as_bytes()).unwrap();
+    the_model.pianoroll.set_quantize_recording(matches.is_present("quantize-recording"));
 
-        Backend {
-            med,
-            moment: None,
+    if let Some(gate) = play.unwrap_or(&matches).value_of("gate") {
+        match gate.parse() {
+            Ok(gate) => the_model.pianoroll.set_gate(gate),
+            Err(_) => {
+                eprintln!("Invalid gate: {}", gate);
+                return
+            }
         }
     }
 
-    fn subscriptions(&mut self) -> Option<Msg> {
-        if let Some(i) = self.moment {
-            let msg = Msg::Time(i.elapsed());
+    if let Some(swing) = play.unwrap_or(&matches).value_of("playback-swing") {
+        match swing.parse() {
+            Ok(swing) => the_model.pianoroll.set_swing(swing),
+            Err(_) => {
+                eprintln!("Invalid swing: {}", swing);
+                return
+            }
+        }
+    }
 
-            Some(msg)
-        } else {
-            None
+    if let Some(measures) = matches.value_of("count-in") {
+        match measures.parse() {
+            Ok(measures) => the_model.pianoroll.set_count_in(measures),
+            Err(_) => {
+                eprintln!("Invalid count-in: {}", measures);
+                return
+            }
         }
     }
 
-    fn run(&mut self, commands: &mut Vec<Command>) {
-        for c in commands.drain(..) {
-            use std::io::Write;
-            let stdin = self.med.stdin.as_mut().unwrap();
+    if let Some(scale) = matches.value_of("scale") {
+        match ui::scale_preset(scale).or_else(|| parse_pitch_classes(scale)) {
+            Some(scale) => the_model.pianoroll.set_scale(scale),
+            None => {
+                eprintln!("Invalid scale: {}", scale);
+                return
+            }
+        }
+    }
 
-            match c {
-                Command::NoteOn(n) => {
-                    match n.pitch / 31 {
-                        0 => drop(stdin.write_all(format!("0a{}_+\n", n.pitch % 31).as_bytes())),
-                        1 => drop(stdin.write_all(format!("0b{}_+\n", n.pitch % 31).as_bytes())),
-                        2 => drop(stdin.write_all(format!("0c{}_+\n", n.pitch % 31).as_bytes())),
-                        3 => drop(stdin.write_all(format!("0d{}_+\n", n.pitch % 31).as_bytes())),
-                        4 => drop(stdin.write_all(format!("0e{}_+\n", n.pitch % 31).as_bytes())),
-                        5 => drop(stdin.write_all(format!("0f{}_+\n", n.pitch % 31).as_bytes())),
-                        6 => drop(stdin.write_all(format!("0g{}_+\n", n.pitch % 31).as_bytes())),
-                        7 => drop(stdin.write_all(format!("0h{}_+\n", n.pitch % 31).as_bytes())),
-                        _ => (),
-                    }
-                },
-                Command::NoteOff(n) => {
-                    match n.pitch / 31 {
-                        0 => drop(stdin.write_all(format!("0a{}-\n", n.pitch % 31).as_bytes())),
-                        1 => drop(stdin.write_all(format!("0b{}-\n", n.pitch % 31).as_bytes())),
-                        2 => drop(stdin.write_all(format!("0c{}-\n", n.pitch % 31).as_bytes())),
-                        3 => drop(stdin.write_all(format!("0d{}-\n", n.pitch % 31).as_bytes())),
-                        4 => drop(stdin.write_all(format!("0e{}-\n", n.pitch % 31).as_bytes())),
-                        5 => drop(stdin.write_all(format!("0f{}-\n", n.pitch % 31).as_bytes())),
-                        6 => drop(stdin.write_all(format!("0g{}-\n", n.pitch % 31).as_bytes())),
-                        7 => drop(stdin.write_all(format!("0h{}-\n", n.pitch % 31).as_bytes())),
-                        _ => (),
-                    }
-                },
-                Command::Stop => {
-                    drop(stdin.write_all(format!("s\n").as_bytes()))
-                },
-                Command::Save => {
-                    // if let Some(ref path) = model.file {
-                    //     let proj = Project {
-                    //         score: model.score.clone(),
-                    //         grid: model.grid.clone(),
-                    //         play_pos: model.play_pos,
-                    //     };
-
-                    //     let output = ron::ser::to_string(&proj).unwrap();
-
-                    //     let mut file = ::std::fs::File::create(path).unwrap();
-                    //     drop(file.write_all(output.as_bytes()));
-                    // }
-                },
-                Command::SubTime => {
-                    self.moment = Some(Instant::now())
+    if let Some(key) = matches.value_of("key") {
+        match parse_pitch_classes(key) {
+            Some(key) => the_model.pianoroll.set_key(key),
+            None => {
+                eprintln!("Invalid key: {}", key);
+                return
+            }
+        }
+    }
+
+    if let Some(widths) = matches.value_of("line-width") {
+        match parse_line_widths(widths) {
+            Some((thin, thick)) => the_model.pianoroll.set_line_widths(thin, thick),
+            None => {
+                eprintln!("Invalid line width: {}", widths);
+                return
+            }
+        }
+    }
+
+    if let Some(subdivision) = matches.value_of("subdivision") {
+        let swing = matches.value_of("swing").unwrap_or("0");
+
+        match (subdivision.parse(), swing.parse::<f32>()) {
+            (Ok(subdivision), Ok(swing)) if subdivision > 0 && (0.0..1.0).contains(&swing) =>
+                the_model.pianoroll.set_subdivision(subdivision, swing),
+            _ => {
+                eprintln!("Invalid subdivision or swing: {} {}", subdivision, swing);
+                return
+            }
+        }
+    }
+
+    if let Some(channel) = matches.value_of("preview-channel") {
+        match channel.parse() {
+            Ok(channel) => the_model.pianoroll.set_preview_channel(Some(channel)),
+            Err(_) => {
+                eprintln!("Invalid preview channel: {}", channel);
+                return
+            }
+        }
+    }
+
+    if let Some(seconds) = matches.value_of("autosave") {
+        match seconds.parse() {
+            Ok(seconds) => the_model.set_autosave_interval(::std::time::Duration::from_secs(seconds)),
+            Err(_) => {
+                eprintln!("Invalid autosave interval: {}", seconds);
+                return
+            }
+        }
+    }
+
+    if let Some(edits) = matches.value_of("autosave-edits") {
+        match edits.parse() {
+            Ok(edits) => the_model.set_autosave_edits(edits),
+            Err(_) => {
+                eprintln!("Invalid number of edits: {}", edits);
+                return
+            }
+        }
+    }
+
+    if let Some(play) = play {
+        // The output options may come before or after the subcommand
+        if let Some(output) = open_output(play) {
+            play_headless(the_model, output)
+        }
+        return
+    }
+
+    if let Some(path) = matches.value_of("export") {
+        let size = matches.value_of("export-size").unwrap();
+        let (width, height) = match parse_size(size) {
+            Some(size) => size,
+            None => {
+                eprintln!("Invalid image size: {}", size);
+                return
+            }
+        };
+
+        let resized = glutin::WindowEvent::Resized((width as f64, height as f64).into());
+        the_model = model(the_model, Msg::WindowEvent(resized), &mut vec![]);
+
+        let mut scene = renderer::Scene::new();
+        the_model.pianoroll.draw(&mut scene);
+
+        let path = ::std::path::Path::new(path);
+        if let Err(e) = export::export_png(&scene, [width, height], path) {
+            eprintln!("Failed to export the image: {}", e);
+            ::std::process::exit(1)
+        }
+
+        if let Some(reference) = matches.value_of("reference") {
+            let tolerance = matches.value_of("tolerance").unwrap();
+            let tolerance = match tolerance.parse() {
+                Ok(tolerance) => tolerance,
+                Err(_) => {
+                    eprintln!("Invalid tolerance: {}", tolerance);
+                    ::std::process::exit(1)
+                }
+            };
+
+            match export::compare_png(path, ::std::path::Path::new(reference), tolerance) {
+                Ok(0) => (),
+                Ok(differing) => {
+                    eprintln!("{} pixels differ from {}", differing, reference);
+                    ::std::process::exit(1)
                 },
-                Command::UnsubTime => {
-                    self.moment = None
+                Err(e) => {
+                    eprintln!("Failed to compare with {}: {}", reference, e);
+                    ::std::process::exit(1)
                 },
             }
         }
+        return
     }
-}
 
-pub fn main() {
-    let matches = clap::App::new("Dieseq")
-        .version(env!("CARGO_PKG_VERSION"))
-        .about("A mictotonal sequencer")
-        .arg(
-            clap::Arg::with_name("file")
-            .help("Dieseq project file")
-            .index(1)
-        )
-        .get_matches();
-
-    let file = matches.value_of("file");
+    if the_model.has_recovery() {
+        the_model.pianoroll.ask_recovery()
+    }
 
     use glutin::GlContext;
 
@@ -355,34 +857,31 @@ pub fn main() {
     let (window, mut device, mut factory, main_color, mut main_depth) =
         gfx_init::<ColorFormat, DepthFormat>(builder, context, &events_loop);
 
+    // Sizes and positions are in logical pixels, the grid needs the factor
+    // to keep its lines sharp
+    the_model.pianoroll.set_hidpi_factor(window.get_hidpi_factor());
+
     let encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
     let mut renderer = renderer::Renderer::new(factory, encoder, main_color);
     let mut scene = renderer::Scene::new();
 
-    let mut backend = Backend::new();
-    let mut intent = Intent::new();
-    let mut the_model =
-        if let Some(path) = file {
-            let path = ::std::path::Path::new(path);
+    let output = match open_output(&matches) {
+        Some(output) => output,
+        None => return,
+    };
 
-            if path.is_file() {
-                Model::from_file(path.to_owned())
-            }
-            else if !path.exists() {
-                Model::with_file(Some(path.to_owned()))
-            }
-            else {
-                eprintln!("Invalid file name: {}", path.to_string_lossy());
-                return
-            }
-        }
-        else {
-            Model::new()
-        };
+    #[allow(unused_mut)]
+    let mut backend = Backend::new(output);
+    #[cfg(feature = "midi")]
+    {
+        if !connect_input(&matches, &mut backend) { return }
+    }
+    let mut intent = Intent::new();
 
     let mut running = true;
     let mut screen_size = [1024.0, 768.0];
     let mut cmds: Vec<Command> = vec![];
+    let mut cursor = glutin::MouseCursor::Default;
 
     while running {
         events_loop.poll_events(|ev| {
@@ -391,6 +890,8 @@ pub fn main() {
                 match event {
                     CloseRequested =>
                         running = false,
+                    // Minimizing may resize the window to nothing
+                    Resized(sz) if !pianoroll::is_usable_size(sz) => (),
                     Resized(sz) => {
                         screen_size = [sz.width as f32, sz.height as f32];
                         intent.intent(Resized(sz));
@@ -410,10 +911,16 @@ pub fn main() {
             the_model = model(the_model, m, &mut cmds);
         }
 
-        draw(&the_model, screen_size, &mut renderer, &mut scene);
-
-        renderer.render_scene(&scene, screen_size, &mut device);
-        window.swap_buffers().unwrap();
+        the_model.autosave();
+        if the_model.pianoroll.cursor() != cursor {
+            cursor = the_model.pianoroll.cursor();
+            window.set_cursor(cursor)
+        }
+        // An unchanged frame is left on screen as it is
+        if draw(&mut the_model, &mut renderer, &mut scene) {
+            renderer.render_scene(&scene, screen_size, &mut device);
+            window.swap_buffers().unwrap();
+        }
         device.cleanup();
 
         backend.run(&mut cmds);
@@ -0,0 +1,160 @@
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Instant;
+
+use dieseq::Msg;
+use dieseq::pianoroll::Note;
+use dieseq::tuning::Tuning;
+
+/// The synth's pitch bend range, in semitones
+const BEND_RANGE: f32 = 2.0;
+
+/// Every sounding note gets a channel of its own, so that each can be bent
+/// independently. Channel 10 is left alone since it's usually drums.
+const CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+
+pub struct MidiOut {
+    conn: MidiOutputConnection,
+    voices: Vec<(Note, u8)>,
+    next_channel: usize,
+    tuning: Tuning,
+}
+
+/// Splits a pitch into the nearest MIDI note and a 14-bit pitch bend value
+fn note_and_bend(pitch: i16, tuning: Tuning) -> (u8, u16) {
+    // MIDI note 69 is A4 at 440 Hz
+    let semitones = 69.0 + 12.0 * (tuning.freq(pitch) / 440.0).log2();
+    let note = semitones.round();
+    let bend = 8192.0 + (semitones - note) / BEND_RANGE * 8192.0;
+
+    (note.clamp(0.0, 127.0) as u8, bend.clamp(0.0, 16383.0) as u16)
+}
+
+impl MidiOut {
+    pub fn port_names() -> Vec<String> {
+        let out = match MidiOutput::new("dieseq") {
+            Ok(out) => out,
+            Err(_) => return vec![],
+        };
+
+        out.ports().iter()
+            .map(|p| out.port_name(p).unwrap_or_default())
+            .collect()
+    }
+
+    pub fn open(port: usize, tuning: Tuning) -> Result<Self, String> {
+        let out = MidiOutput::new("dieseq").map_err(|e| e.to_string())?;
+        let ports = out.ports();
+        let port = ports.get(port).ok_or_else(|| format!("No MIDI port {}", port))?;
+        let conn = out.connect(port, "dieseq").map_err(|e| e.to_string())?;
+
+        Ok(MidiOut {
+            conn,
+            voices: vec![],
+            next_channel: 0,
+            tuning,
+        })
+    }
+
+    /// The next channel in turn that has no note sounding on it, so a bend
+    /// doesn't detune a held note. With every channel taken, the one in turn
+    /// is shared.
+    fn free_channel(&mut self) -> u8 {
+        let count = CHANNELS.len();
+        let voices = &self.voices;
+        let next = (0..count)
+            .map(|i| (self.next_channel + i) % count)
+            .find(|&i| voices.iter().all(|&(_, c)| c != CHANNELS[i]))
+            .unwrap_or(self.next_channel);
+        self.next_channel = (next + 1) % count;
+
+        CHANNELS[next]
+    }
+
+    pub fn note_on(&mut self, note: Note) {
+        let channel = self.free_channel();
+
+        let (key, bend) = note_and_bend(note.pitch, self.tuning);
+        let _ = self.conn.send(&[0xe0 | channel, (bend & 0x7f) as u8, (bend >> 7) as u8]);
+        let _ = self.conn.send(&[0x90 | channel, key, note.velocity.min(127)]);
+
+        self.voices.push((note, channel));
+    }
+
+    pub fn note_off(&mut self, note: Note) {
+        if let Some(i) = self.voices.iter().position(|&(n, _)| n == note) {
+            let (_, channel) = self.voices.swap_remove(i);
+            let (key, _) = note_and_bend(note.pitch, self.tuning);
+
+            let _ = self.conn.send(&[0x80 | channel, key, 0]);
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        for &channel in CHANNELS.iter() {
+            let _ = self.conn.send(&[0xb0 | channel, 123, 0]);
+        }
+
+        self.voices.clear();
+    }
+}
+
+/// The pitch of the tuning closest to a MIDI note in 12-EDO
+fn nearest_pitch(key: u8, tuning: Tuning) -> i16 {
+    let freq = 440.0 * 2f32.powf((key as f32 - 69.0) / 12.0);
+    let distance = |pitch: i16| (tuning.freq(pitch) / freq).log2().abs();
+
+    (0..=31 * 8)
+        .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+        .unwrap_or(0)
+}
+
+/// Notes played on a MIDI input device, turned into messages as they come
+pub struct MidiIn {
+    _conn: MidiInputConnection<()>,
+    messages: Receiver<Msg>,
+}
+
+impl MidiIn {
+    pub fn port_names() -> Vec<String> {
+        let input = match MidiInput::new("dieseq") {
+            Ok(input) => input,
+            Err(_) => return vec![],
+        };
+
+        input.ports().iter()
+            .map(|p| input.port_name(p).unwrap_or_default())
+            .collect()
+    }
+
+    pub fn open(port: usize, tuning: Tuning) -> Result<Self, String> {
+        let input = MidiInput::new("dieseq").map_err(|e| e.to_string())?;
+        let ports = input.ports();
+        let port = ports.get(port).ok_or_else(|| format!("No MIDI input port {}", port))?;
+
+        let (sender, messages) = channel();
+        let conn = input.connect(port, "dieseq", move |_, bytes, _| {
+            let at = Instant::now();
+            let msg = match *bytes {
+                [status, key, velocity] if status & 0xf0 == 0x90 && velocity > 0 =>
+                    Msg::InputNoteOn { pitch: nearest_pitch(key, tuning), velocity, at },
+                // A note on with no velocity is a note off
+                [status, key, _] if status & 0xf0 == 0x80 || status & 0xf0 == 0x90 =>
+                    Msg::InputNoteOff { pitch: nearest_pitch(key, tuning), at },
+                _ => return,
+            };
+
+            let _ = sender.send(msg);
+        }, ()).map_err(|e| e.to_string())?;
+
+        Ok(MidiIn {
+            _conn: conn,
+            messages,
+        })
+    }
+
+    /// The notes played since the last call
+    pub fn messages(&mut self) -> Vec<Msg> {
+        self.messages.try_iter().collect()
+    }
+}
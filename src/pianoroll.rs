@@ -1,36 +1,473 @@
 use crate::ui;
-use crate::{Vector2, rects_overlap, normalize_square, duration_seconds};
+use crate::{Vector2, rects_overlap, rect_contains, normalize_square, point_in_polygon, duration_seconds};
 use crate::{Msg, Command};
-use crate::renderer;
+use crate::renderer::{self, Draw};
+use crate::keymap::{Action, KeyMap};
+use std::time::{Duration, Instant};
+use glutin::ModifiersState;
+use std::collections::HashSet;
+
+/// How long an auditioned note sounds
+const AUDITION_TIME: Duration = Duration::from_millis(250);
+
+/// Limits of the gate, notes are never cut to nothing
+const MIN_GATE: f32 = 0.1;
+const MAX_GATE: f32 = 2.0;
+
+/// Playback swing when it's turned on without an amount, a triplet feel
+const DEFAULT_SWING: f32 = 1.0 / 3.0;
+
+/// The widest fade of note borders, wider ones would blur the notes away
+const MAX_NOTE_FEATHER: f32 = 4.0;
+
+/// The pitch of the count-in clicks, the first beat of a measure is an octave higher
+const CLICK_PITCH: i16 = 124 + 2 * 31;
+const CLICK_TIME: Duration = Duration::from_millis(50);
+/// How far past the last note playback goes before it stops, in measures
+const PLAYBACK_TAIL: f32 = 0.25;
+/// The largest wheel amount a single event zooms by
+const MAX_ZOOM_STEP: f32 = 5.0;
+/// How close in pixels to the playhead a press grabs it
+const PLAYHEAD_GRAB: f32 = 4.0;
+/// How many lines the event log keeps
+const EVENT_LOG_LEN: usize = 16;
+/// The wheel amount a zoom key press is worth
+const KEY_ZOOM_STEP: f32 = 3.0;
+/// Smaller windows, like minimized ones, keep the last usable size
+const MIN_WINDOW_SIZE: f64 = 16.0;
+/// Steps of a whole note that the pencil and moved notes snap to without a
+/// snap resolution or subdivisions, eighths
+const FREE_SNAP_STEPS: f32 = 8.0;
+/// The highest pitch, eight octaves above pitch 0 where the view stops
+const MAX_PITCH: i16 = 31 * 8;
+/// Channels a note can be on, as many as MIDI has
+const CHANNELS: u16 = 16;
 
 #[derive(Debug, Clone, PartialEq)]
 enum State {
     Idle,
     Drawing(Brick),
-    Playing(f32, i16),
-    PointSelected(Vector2<f32>),
+    /// Notes drawn with shift held, all sharing the time of the first one.
+    /// They're placed once shift is released.
+    DrawingChord(Vec<Brick>),
+    Playing(Playback),
+    /// Playing while the notes played on the input are put into the score,
+    /// with the notes still held as started but not ended yet
+    Recording(Playback, Vec<Note>),
+    PointSelected(Vector2<f32>, Combine),
     NotesSelected(Vec<Note>),
-    SelectFrame(Vector2<f32>, Vector2<f32>),
-    MovingNotes(),
+    SelectFrame(Vector2<f32>, Vector2<f32>, Combine),
+    Lasso(Vec<Vector2<f32>>),
+    MovingNotes(Move),
+    /// Dragging with the eraser
+    Erasing,
+    /// Dragging with the line tool, from and to a screen point
+    DrawingLine(Vector2<f32>, Vector2<f32>),
+    /// A note as it was, the edge being dragged and the note's new time
+    Resizing(Note, Edge, (i16, i16)),
+    /// Dragging across the ruler: the press position until the mouse has
+    /// moved far enough for a drag, and the snapped start and end of the
+    /// range in measures
+    RulerSelect(Option<Vector2<f32>>, f32, f32),
+    /// Whether playback goes on from the new position on release
+    DraggingPlayhead(bool),
+    /// The menu's top left corner, its items and the notes they act on
+    ContextMenu(Vector2<f32>, Vec<MenuItem>, Vec<Note>),
+    EditingNote(NoteEdit),
+    EditingRepeat(RepeatEdit),
+    /// Asking whether to recover an autosaved score, before anything else
+    AskingRecovery,
+}
+
+/// A note whose values are being typed in
+#[derive(Debug, Clone, PartialEq)]
+struct NoteEdit {
+    note: Note,
+    /// The text of every field, in the order of `NoteEdit::LABELS`
+    fields: Vec<String>,
+    active: usize,
+}
+
+impl NoteEdit {
+    const LABELS: [&'static str; 5] = ["start", "end", "pitch", "channel", "velocity"];
+
+    fn new(note: Note) -> Self {
+        NoteEdit {
+            note,
+            fields: vec![
+                note.time.0.to_string(),
+                note.time.1.to_string(),
+                note.pitch.to_string(),
+                (note.channel + 1).to_string(),
+                note.velocity.to_string(),
+            ],
+            active: 0,
+        }
+    }
+
+    /// The note with the typed values, or None if some value is invalid
+    fn parse(&self) -> Option<Note> {
+        let start: i16 = self.fields[0].parse().ok()?;
+        let end: i16 = self.fields[1].parse().ok()?;
+        let pitch: i16 = self.fields[2].parse().ok()?;
+        let channel: u16 = self.fields[3].parse().ok()?;
+        let velocity: u8 = self.fields[4].parse().ok()?;
+
+        let note = Note {
+            channel: channel.checked_sub(1)?,
+            time: (start, end),
+            pitch, velocity,
+        };
+
+        Some(note).filter(|n| start >= 0 && n.is_valid())
+    }
+}
+
+/// Repeats of the selected notes whose count and spacing are being typed in
+#[derive(Debug, Clone, PartialEq)]
+struct RepeatEdit {
+    notes: Vec<Note>,
+    /// The text of every field, in the order of `RepeatEdit::LABELS`
+    fields: Vec<String>,
+    active: usize,
+}
+
+impl RepeatEdit {
+    const LABELS: [&'static str; 2] = ["times", "spacing"];
+
+    /// Starts with a single copy right after the notes
+    fn new(notes: Vec<Note>) -> Self {
+        let (start, end) = span(&notes);
+
+        RepeatEdit {
+            fields: vec!["1".into(), (end - start).to_string()],
+            active: 0,
+            notes,
+        }
+    }
+
+    /// The count and the spacing in ticks, or None if some value is invalid
+    /// or the last copy would end past the last tick
+    fn parse(&self) -> Option<(u32, i16)> {
+        let count: i16 = self.fields[0].parse().ok()?;
+        let spacing: i16 = self.fields[1].parse().ok()?;
+        if count <= 0 || spacing <= 0 {
+            return None
+        }
+
+        let (_, end) = span(&self.notes);
+        end.checked_add(count.checked_mul(spacing)?)?;
+
+        Some((count as u32, spacing))
+    }
+
+    /// The copies the values would add
+    fn copies(&self) -> Vec<Note> {
+        self.parse()
+            .and_then(|(count, spacing)| repeated(&self.notes, count, spacing))
+            .unwrap_or_default()
+    }
+}
+
+/// The first tick of the notes and the tick the last one ends at
+fn span(notes: &[Note]) -> (i16, i16) {
+    let start = notes.iter().map(|n| n.time.0).min().unwrap_or(0);
+    let end = notes.iter().map(|n| n.time.1).max().unwrap_or(0);
+
+    (start, end)
+}
+
+/// `count` copies of the notes, each `spacing_ticks` after the previous one,
+/// or None if the last one would end past the last tick
+fn repeated(notes: &[Note], count: u32, spacing_ticks: i16) -> Option<Vec<Note>> {
+    if count > i16::MAX as u32 {
+        return None
+    }
+
+    (1..=count as i16)
+        .flat_map(|i| notes.iter().map(move |&n| {
+            let shift = i.checked_mul(spacing_ticks)?;
+            Some(Note {
+                time: (n.time.0.checked_add(shift)?, n.time.1.checked_add(shift)?),
+                ..n
+            })
+        }))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MenuItem {
+    Delete,
+    Duplicate,
+    /// Moves the notes to a channel
+    Channel(u16),
+    /// Selects the notes, which shows their properties
+    Properties,
+}
+
+impl MenuItem {
+    fn label(self) -> String {
+        match self {
+            MenuItem::Delete => "Delete".into(),
+            MenuItem::Duplicate => "Duplicate".into(),
+            MenuItem::Channel(channel) => format!("To channel {}", channel + 1),
+            MenuItem::Properties => "Properties".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Playback {
+    /// The moment playback started
+    start: Instant,
+    /// Seconds into the song at `start`
+    start_seconds: f32,
+    /// Current position in measures
+    pos: f32,
+    /// The last tick already played
+    last_tick: i16,
+    /// Seconds of count-in clicks before the song starts at `start`
+    count_in: f32,
+}
+
+/// The end of a note that's being resized
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Start,
+    End,
+}
+
+impl Edge {
+    /// Moves the edge to a tick, the other one stays. The note is kept at
+    /// least a tick long, an edge dragged past the other stops there.
+    fn resize(self, time: (i16, i16), tick: i16) -> (i16, i16) {
+        match self {
+            Edge::Start => (tick.max(0).min(time.1 - 1), time.1),
+            Edge::End => (time.0, tick.max(time.0 + 1)),
+        }
+    }
+}
+
+/// Which notes a selection frame takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SelectMode {
+    /// Notes touching the frame
+    Intersect,
+    /// Notes entirely inside the frame
+    Contain,
+}
+
+/// How the framed notes combine with the notes selected before
+#[derive(Debug, Clone, PartialEq)]
+enum Combine {
+    Replace,
+    Add(Vec<Note>),
+    Toggle(Vec<Note>),
+}
+
+impl Combine {
+    fn new(modifiers: ModifiersState, selected: &[Note]) -> Self {
+        if modifiers.shift { Combine::Add(selected.to_vec()) }
+        else if modifiers.ctrl { Combine::Toggle(selected.to_vec()) }
+        else { Combine::Replace }
+    }
+
+    fn previous(&self) -> &[Note] {
+        match *self {
+            Combine::Replace => &[],
+            Combine::Add(ref notes) | Combine::Toggle(ref notes) => notes,
+        }
+    }
+
+    fn apply(&self, framed: Vec<Note>) -> Vec<Note> {
+        match *self {
+            Combine::Replace => framed,
+            Combine::Add(ref previous) => {
+                let mut notes = previous.clone();
+                notes.extend(framed.into_iter().filter(|n| !previous.contains(n)));
+                notes
+            },
+            Combine::Toggle(ref previous) => {
+                let mut notes: Vec<Note> = previous.iter()
+                    .filter(|n| !framed.contains(n))
+                    .cloned().collect();
+                notes.extend(framed.into_iter().filter(|n| !previous.contains(n)));
+                notes
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Move {
+    /// The selection as it was before the move
+    notes: Vec<Note>,
+    /// The note the drag started on
+    grabbed: Note,
+    from: Vector2<f32>,
+    /// Snapped offset in ticks and pitch
+    shift: (i16, i16),
+    /// The note currently sounding as a preview of the target pitch
+    audition: Option<Note>,
+}
+
+impl Move {
+    /// The offset limited so that every moved note still starts at or after
+    /// tick 0, ends within the ticks and has a pitch from 0 to `MAX_PITCH`
+    fn clamp_shift(&self, shift: (i16, i16)) -> (i16, i16) {
+        let start = self.notes.iter().map(|n| n.time.0).min().unwrap_or(0) as i32;
+        let end = self.notes.iter().map(|n| n.time.1).max().unwrap_or(0) as i32;
+        let low = self.notes.iter().map(|n| n.pitch).min().unwrap_or(0) as i32;
+        let high = self.notes.iter().map(|n| n.pitch).max().unwrap_or(0) as i32;
+
+        (
+            (shift.0 as i32).max(-start).min(i16::MAX as i32 - end) as i16,
+            (shift.1 as i32).max(-low).min(MAX_PITCH as i32 - high) as i16,
+        )
+    }
+
+    fn shifted(&self, note: Note) -> Option<Note> {
+        let moved = Note {
+            time: (note.time.0.checked_add(self.shift.0)?, note.time.1.checked_add(self.shift.0)?),
+            pitch: note.pitch.checked_add(self.shift.1)?,
+            ..note
+        };
+
+        Some(moved).filter(|n| n.time.0 >= 0 && n.is_valid())
+    }
+
+    /// The selection at its new place, or None if some note would leave the score
+    fn moved(&self) -> Option<Vec<Note>> {
+        self.notes.iter().map(|&n| self.shifted(n)).collect()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum Tool {
     Arrow,
     Pencil,
+    Eraser,
+    /// Draws a run of notes along a drag
+    Line,
+    /// Toggles beat long notes, like a step sequencer
+    Step,
+}
+
+impl Tool {
+    fn name(self) -> &'static str {
+        match self {
+            Tool::Arrow => "arrow",
+            Tool::Pencil => "pencil",
+            Tool::Eraser => "eraser",
+            Tool::Line => "line",
+            Tool::Step => "step",
+        }
+    }
+}
+
+/// The note value the pencil snaps the start and the end of notes to,
+/// counted from the start of the score in whole notes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapResolution {
+    /// Starts on eighths or subdivision lines, ends anywhere
+    Free,
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    QuarterTriplet,
+    EighthTriplet,
+}
+
+impl SnapResolution {
+    /// How many steps a whole note has, none for free snapping
+    fn steps(self) -> Option<f32> {
+        match self {
+            SnapResolution::Free => None,
+            SnapResolution::Whole => Some(1.0),
+            SnapResolution::Half => Some(2.0),
+            SnapResolution::Quarter => Some(4.0),
+            SnapResolution::Eighth => Some(8.0),
+            SnapResolution::Sixteenth => Some(16.0),
+            SnapResolution::QuarterTriplet => Some(6.0),
+            SnapResolution::EighthTriplet => Some(12.0),
+        }
+    }
+
+    /// Whether its steps fall on whole ticks, with `measure_ticks` ticks to a whole note
+    fn fits(self, measure_ticks: u16) -> bool {
+        self.steps().is_none_or(|steps| measure_ticks as f32 % steps == 0.0)
+    }
+
+    fn next(self) -> Self {
+        match self {
+            SnapResolution::Free => SnapResolution::Whole,
+            SnapResolution::Whole => SnapResolution::Half,
+            SnapResolution::Half => SnapResolution::Quarter,
+            SnapResolution::Quarter => SnapResolution::Eighth,
+            SnapResolution::Eighth => SnapResolution::Sixteenth,
+            SnapResolution::Sixteenth => SnapResolution::QuarterTriplet,
+            SnapResolution::QuarterTriplet => SnapResolution::EighthTriplet,
+            SnapResolution::EighthTriplet => SnapResolution::Free,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SnapResolution::Free => "",
+            SnapResolution::Whole => "1/1",
+            SnapResolution::Half => "1/2",
+            SnapResolution::Quarter => "1/4",
+            SnapResolution::Eighth => "1/8",
+            SnapResolution::Sixteenth => "1/16",
+            SnapResolution::QuarterTriplet => "1/4t",
+            SnapResolution::EighthTriplet => "1/8t",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Note {
     pub channel: u16,
     pub time: (i16, i16),
     pub pitch: i16,
+    /// MIDI velocity, from 1 to 127
+    #[serde(default = "default_velocity")]
+    pub velocity: u8,
+}
+
+const DEFAULT_VELOCITY: u8 = 100;
+
+impl Note {
+    /// The tick the note is released at in playback, its length scaled by `gate`
+    fn release_tick(&self, gate: f32) -> f32 {
+        let (t0, t1) = self.time;
+
+        t0 as f32 + (t1 - t0) as f32 * gate
+    }
+
+    /// Whether the note has a length, and a pitch, channel and velocity the editor can show
+    fn is_valid(&self) -> bool {
+        self.time.0 < self.time.1
+            && (0..=MAX_PITCH).contains(&self.pitch)
+            && self.channel < CHANNELS
+            && (1..=127).contains(&self.velocity)
+    }
+}
+
+fn default_velocity() -> u8 {
+    DEFAULT_VELOCITY
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct Brick {
     time: (f32, f32),
     pitch: f32,
+    channel: u16,
+    /// Where on the screen the drawing started, until the mouse has moved
+    /// far enough for a drag
+    press: Option<Vector2<f32>>,
 }
 
 impl From<Brick> for Note {
@@ -46,347 +483,3809 @@ impl From<Brick> for Note {
             };
 
         Note {
-            channel: 0,
+            channel: brick.channel,
+            velocity: DEFAULT_VELOCITY,
             time, pitch
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Score {
+/// Quarter notes per minute of a score without tempo changes, a whole note a second
+const DEFAULT_BPM: f32 = 240.0;
+
+fn default_tempo() -> Vec<(i16, f32)> {
+    vec![(0, DEFAULT_BPM)]
+}
+
+/// Scores with more notes than this are slow to draw and play, loading one
+/// gives a warning
+pub const MANY_NOTES: usize = 50_000;
+/// Notes a score may have unless `PianoRoll::set_max_notes` says otherwise
+pub const DEFAULT_MAX_NOTES: usize = 500_000;
+
+/// The version of the score format this build writes
+const SCORE_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    version: u32,
+    /// Ticks in a whole note, the length of a measure of 4/4
     measure_ticks: u16,
     notes: Vec<Note>,
+    /// Tempo changes as the tick they happen at and quarter notes per minute,
+    /// sorted by tick. The first tempo also holds before its tick.
+    tempo: Vec<(i16, f32)>,
+    /// Measures the score is meant to last, or none for an open end
+    length: Option<u16>,
+    /// The time signatures, which set how long every measure is
+    #[serde(default)]
+    meter: ui::Meter,
 }
 
-impl Score {
-    pub fn new() -> Self {
-        Score {
-            measure_ticks: 16,
-            notes: vec![],
+/// Just the version of a score in RON, to tell which layout to read it with
+#[derive(Deserialize)]
+#[serde(rename = "Score")]
+struct ScoreVersion {
+    /// Missing (0) in files from before it was recorded
+    #[serde(default)]
+    version: u32,
+}
+
+/// Scores up to version 2. A measure was always `measure_ticks` long, the
+/// beats only divided it. In RON the fields added over these versions have
+/// defaults.
+#[derive(Deserialize)]
+#[serde(rename = "Score")]
+struct ScoreV2 {
+    #[serde(default)]
+    version: u32,
+    measure_ticks: u16,
+    notes: Vec<Note>,
+    #[serde(default = "default_tempo")]
+    tempo: Vec<(i16, f32)>,
+    #[serde(default)]
+    length: Option<u16>,
+    /// Beats per measure the score was started with
+    #[serde(default)]
+    beats: Option<u8>,
+}
+
+/// The binary layout of version 1 scores. Bincode stores no field names, so
+/// unlike RON every older version has to be read with a layout of its own.
+#[derive(Deserialize)]
+struct ScoreV1 {
+    version: u32,
+    measure_ticks: u16,
+    notes: Vec<Note>,
+    tempo: Vec<(i16, f32)>,
+}
+
+impl From<ScoreV1> for ScoreV2 {
+    fn from(score: ScoreV1) -> Self {
+        ScoreV2 {
+            version: score.version,
+            measure_ticks: score.measure_ticks,
+            notes: score.notes,
+            tempo: score.tempo,
+            length: None,
+            beats: None,
         }
     }
 }
 
-pub struct PianoRoll {
-    state: State,
-    tool: Tool,
-    grid: ui::Grid,
-    play_pos: f32,
-    score: Score,
-}
+impl From<ScoreV2> for Score {
+    fn from(mut score: ScoreV2) -> Self {
+        if score.version < 1 {
+            // Tempo changes weren't kept sorted
+            score.tempo.sort_by_key(|&(tick, _)| tick);
+            if score.tempo.is_empty() {
+                score.tempo = default_tempo()
+            }
+        }
 
-impl PianoRoll {
-    pub fn new() -> Self {
-        let score = Score::new();
+        // The notes keep their ticks, and a tick its duration, so the score
+        // sounds the same. A measure that didn't have four beats gets shorter
+        // or longer, a bounded score keeps at least its old length.
+        let meter = score.beats.map_or_else(ui::Meter::default, ui::Meter::new);
+        let length = score.length.map(|old| {
+            let end = old as f32;
+            let mut measures = meter.measure_at(end);
+            if meter.measure_start(measures) < end {
+                measures += 1
+            }
 
-        let grid = ui::Grid::new(
-            Vector2::new(1024.0, 768.0),
-            (Vector2::new(-0.25, 31.0), Vector2::new(12.0, 155.0))
-        );
+            measures.clamp(1, u16::MAX as i32) as u16
+        });
 
-        PianoRoll {
-            state: State::Idle,
-            tool: Tool::Arrow,
-            play_pos: 0.0,
-            score, grid
+        Score {
+            version: SCORE_VERSION,
+            measure_ticks: score.measure_ticks,
+            notes: score.notes,
+            tempo: score.tempo,
+            length, meter,
         }
     }
+}
 
-    fn scale_y(&mut self, fixed_point: f32, amount: f32) {
-        let start = self.grid.view.0.y;
-        let end = self.grid.view.1.y;
-        let y = -amount;
-
-        let split_ratio = fixed_point / self.grid.size.y;
-        let split_point = start + (end - start) * split_ratio;
+/// Tempo segments as their start tick, start time in seconds, and seconds per
+/// tick. Never empty.
+struct TempoMap(Vec<(f32, f32, f32)>);
 
-        let k =
-            if y < 0.0 || (end - start) <= 8.0 * 31.0 {
-                (1.07_f32).powf(y)
-            }
-            else {
-                1.0
-            };
+impl TempoMap {
+    /// Seconds from tick 0 to the given tick
+    fn seconds_at(&self, tick: f32) -> f32 {
+        let &(t0, s0, rate) = self.0.iter()
+            .rev()
+            .find(|s| s.0 <= tick)
+            .unwrap_or(&self.0[0]);
 
-        self.grid.view.0.y = (start - split_point) * k + split_point;
-        self.grid.view.1.y = (end - split_point) * k + split_point;
+        s0 + (tick - t0) * rate
     }
 
-    fn scale_x(&mut self, fixed_point: f32, amount: f32) {
-        let start = self.grid.view.0.x;
-        let end = self.grid.view.1.x;
-        let y = -amount;
+    /// The tick played the given number of seconds after tick 0
+    fn tick_at(&self, seconds: f32) -> f32 {
+        let &(t0, s0, rate) = self.0.iter()
+            .rev()
+            .find(|s| s.1 <= seconds)
+            .unwrap_or(&self.0[0]);
 
-        let k =
-            if y < 0.0 || (end - start) <= self.grid.size.x / 16.0 {
-                (1.07_f32).powf(y)
-            }
-            else {
-                1.0
-            };
+        t0 + (seconds - s0) / rate
+    }
+}
 
-        let split_ratio = fixed_point / self.grid.size.x;
-        let split_point = start + (end - start) * split_ratio;
+impl Default for Score {
+    fn default() -> Self {
+        Score::new()
+    }
+}
 
-        self.grid.view.0.x = (start - split_point) * k + split_point;
-        self.grid.view.1.x = (end - split_point) * k + split_point;
+impl Score {
+    pub fn new() -> Self {
+        Score {
+            version: SCORE_VERSION,
+            measure_ticks: 16,
+            notes: vec![],
+            tempo: default_tempo(),
+            length: None,
+            meter: ui::Meter::default(),
+        }
     }
 
-    fn st_select_point(&mut self, position: Vector2<f32>) {
-        if self.tool == Tool::Arrow  {
-            self.state = State::PointSelected(position);
+    /// A blank score of a given number of measures of `beats` quarter notes,
+    /// with `measure_ticks` ticks to a whole note
+    pub fn with_length(measures: u16, measure_ticks: u16, beats: u8) -> Self {
+        Score {
+            measure_ticks: measure_ticks.max(1),
+            length: Some(measures.max(1)),
+            meter: ui::Meter::new(beats),
+            ..Score::new()
         }
     }
-    fn st_draw_brick(&mut self, position: Vector2<f32>) {
-        if self.tool == Tool::Pencil {
-            let view_pos = self.grid.view_position(position);
 
-            let time = (view_pos.x * self.score.measure_ticks as f32 / 2.0).round() * 2.0;
-            let pitch = view_pos.y;
+    pub fn note_count(&self) -> usize {
+        self.notes.len()
+    }
 
-            self.state = State::Drawing(Brick {
-                time: (time, time),
-                pitch,
-            });
+    /// Keeps only the `max` earliest notes, and returns how many were dropped
+    pub fn truncate_notes(&mut self, max: usize) -> usize {
+        let count = self.notes.len();
+        if count <= max {
+            return 0
         }
+
+        self.notes.sort_by_key(|n| (n.time.0, n.pitch));
+        self.notes.truncate(max);
+        count - max
     }
-    fn st_set_time(&mut self) {
-        if let State::PointSelected(point) = self.state {
-            let time = self.grid.view_position(point).x;
 
-            self.play_pos = time;
-            self.state = State::Idle;
-        }
+    /// The number of measures the score is meant to last, if it's bounded
+    pub fn length(&self) -> Option<u16> {
+        self.length
     }
-    fn st_select_framed(&mut self) {
-        if let State::SelectFrame(v0, v1) = self.state {
-            let (v0, v1) = normalize_square(
-                self.grid.view_position(v0),
-                self.grid.view_position(v1)
-            );
 
-            let ticks = { self.score.measure_ticks as f32 };
-            let framed: Vec<Note> = self.score.notes.iter().filter(|n| {
-                let n0 = Vector2::new(n.time.0 as f32 / ticks, n.pitch as f32 - 0.5);
-                let n1 = Vector2::new(n.time.1 as f32 / ticks, n.pitch as f32 + 0.5);
+    fn tick_seconds(&self, bpm: f32) -> f32 {
+        // A quarter note is a quarter of a whole note
+        60.0 / bpm / (self.measure_ticks as f32 / 4.0)
+    }
 
-                rects_overlap(v0, v1, n0, n1)
-            }).cloned().collect();
+    /// Reads a score, upgrading older formats to the current one
+    pub fn load(text: &str) -> Result<Self, String> {
+        let version = ron::de::from_str::<ScoreVersion>(text).map_err(|e| e.to_string())?.version;
+        Score::check_version(version)?;
 
-            if framed.len() == 0 {
-                self.state = State::Idle
+        let score: Score =
+            if version < 3 {
+                ron::de::from_str::<ScoreV2>(text).map_err(|e| e.to_string())?.into()
             }
             else {
-                self.state = State::NotesSelected(framed)
-            }
-        };
-    }
-    fn st_create_brick(&mut self) {
-        if let State::Drawing(brick) = self.state {
-            if brick.time.0.round() != brick.time.1.round() {
-                self.score.notes.push(brick.into())
-            }
-            else if brick.time.0 == brick.time.1 {
-                let (time, pitch) = (brick.time.0, brick.pitch);
+                ron::de::from_str(text).map_err(|e| e.to_string())?
+            };
 
-                self.score.notes.retain(|n|
-                    (n.pitch as f32 - pitch).abs() >= 0.5
-                    || {
-                        (n.time.0 as f32) > time
-                        || (n.time.1 as f32) < time
-                    }
-                )
-            }
+        score.checked()
+    }
 
-            self.state = State::Idle
+    /// The score with its tempo changes sorted, or why it can't be played
+    fn checked(mut self) -> Result<Self, String> {
+        if self.measure_ticks == 0 {
+            return Err("the score has no ticks in a measure".into())
+        }
+        if self.tempo.is_empty() {
+            return Err("the score has no tempo".into())
+        }
+        if let Some(&(tick, bpm)) = self.tempo.iter().find(|t| !(t.1.is_finite() && t.1 > 0.0)) {
+            return Err(format!("invalid tempo {} at tick {}", bpm, tick))
         }
-    }
-    //st_change_brick
 
-    fn on_left_press(&mut self, position: Vector2<f32>) {
-        self.st_select_point(position);
-        self.st_draw_brick(position)
+        self.tempo.sort_by_key(|&(tick, _)| tick);
+        Ok(self)
     }
 
-    fn on_left_release(&mut self) {
-        self.st_set_time();
-        self.st_select_framed();
-        self.st_create_brick();
-        
+    fn check_version(version: u32) -> Result<(), String> {
+        if version > SCORE_VERSION {
+            return Err(format!(
+                "the score has format version {}, newer than the supported {}",
+                version, SCORE_VERSION
+            ))
+        }
+
+        Ok(())
     }
 
-    fn on_time(&mut self, time: std::time::Duration, cmds: &mut Vec<crate::Command>) {
-        if let State::Playing(_pos, mut ipos) = self.state {
-            let pos = self.play_pos + duration_seconds(time);
-            let ticks = pos * self.score.measure_ticks as f32;
+    pub fn save(&self) -> Result<String, String> {
+        ron::ser::to_string_pretty(self, Default::default()).map_err(|e| e.to_string())
+    }
 
-            if ticks as i16 > ipos {
-                ipos = ticks as i16;
+    /// Reads a score in the binary format, upgrading older versions like `load`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        // The version comes first, so it tells the layout of the rest
+        let version: u32 = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+        Score::check_version(version)?;
 
-                for &n in &self.score.notes {
-                    if n.time.0 == ipos {
-                        cmds.push(Command::NoteOn(n))
-                    }
+        let read_error = |e: bincode::Error| e.to_string();
+        let score: Score = match version {
+            // Version 0 predates the binary format
+            0 => return Err("the binary score has no format version".into()),
+            1 => ScoreV2::from(bincode::deserialize::<ScoreV1>(bytes).map_err(read_error)?).into(),
+            2 => bincode::deserialize::<ScoreV2>(bytes).map_err(read_error)?.into(),
+            _ => bincode::deserialize(bytes).map_err(read_error)?,
+        };
 
-                    if n.time.1 == ipos {
-                        cmds.push(Command::NoteOff(n))
-                    }
-                }
-            }
+        score.checked()
+    }
 
-            self.state = State::Playing(pos, ipos);
-        }
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| e.to_string())
     }
 
-    fn on_key_press(&mut self, key: u32, cmds: &mut Vec<Command>) {
-        match (key, &self.state) {
-            (0x02, _) => {
-                self.tool = Tool::Arrow;
-            },
-            (0x03, _) => {
-                self.tool = Tool::Pencil;
-            },
-            (0x39, &State::Playing(_, _)) => {
-                cmds.push(Command::Stop);
-                cmds.push(Command::UnsubTime);
+    /// Where a bounded score ends, in whole notes
+    fn length_pos(&self) -> Option<f32> {
+        self.length.map(|m| self.meter.measure_start(m as i32))
+    }
 
-                self.state = State::Idle
-            },
-            (0x39, _) => {
-                self.state = State::Playing(
-                    self.play_pos,
-                    (self.play_pos * self.score.measure_ticks as f32).round() as i16 - 1
-                );
+    /// The tick the last note ends at, or the end of the last measure of a
+    /// bounded score if that's later
+    fn song_end(&self) -> i16 {
+        let last_note = self.notes.iter().map(|n| n.time.1).max().unwrap_or(0);
+        let length = self.length_pos().map_or(0.0, |pos| (pos * self.measure_ticks as f32).round());
 
-                cmds.push(Command::SubTime)
-            },
-            (0x20, &State::NotesSelected(ref selected)) => {
-                self.score.notes.retain(|n| !selected.contains(n));
+        last_note.max(length.min(i16::MAX as f32) as i16)
+    }
 
-                self.state = State::Idle;
-            },
-            (0x1f, _) => {
-                // self.commands.push(Command::Save)
-            },
-            //(code, _) => { println!("{:x}", code); },
-            _ => (),
-        }
+    /// The notes sounding at a tick
+    fn notes_at_time(&self, tick: i16) -> Vec<Note> {
+        self.notes.iter()
+            .filter(|n| n.time.0 <= tick && tick < n.time.1)
+            .cloned()
+            .collect()
     }
 
-    pub fn model(&mut self, msg: Msg, cmds: &mut Vec<Command>) {
-        use glutin::WindowEvent::*;
-        use self::Msg::*;
+    /// The tempo changes laid out in time, to convert between ticks and seconds
+    fn tempo_map(&self) -> TempoMap {
+        let mut segments: Vec<(f32, f32, f32)> = vec![];
+
+        for &(tick, bpm) in &self.tempo {
+            let tick = tick as f32;
+            let seconds = match segments.last() {
+                Some(&(t0, s0, rate)) => s0 + (tick - t0) * rate,
+                None => tick * self.tick_seconds(bpm),
+            };
+
+            segments.push((tick, seconds, self.tick_seconds(bpm)))
+        }
+
+        if segments.is_empty() {
+            segments.push((0.0, 0.0, self.tick_seconds(DEFAULT_BPM)))
+        }
+
+        TempoMap(segments)
+    }
+
+    /// Seconds from tick 0 to the given tick
+    fn seconds_at(&self, tick: f32) -> f32 {
+        self.tempo_map().seconds_at(tick)
+    }
+
+    /// The tick played the given number of seconds after tick 0
+    fn tick_at(&self, seconds: f32) -> f32 {
+        self.tempo_map().tick_at(seconds)
+    }
+
+    /// Cuts the parts of the notes on the same pitch and channel that overlap
+    /// with `note` out, splitting the notes that enclose it
+    fn trim_overlaps(&mut self, note: Note) {
+        let (t0, t1) = note.time;
+        let mut trimmed = Vec::with_capacity(self.notes.len());
+
+        for &n in &self.notes {
+            let overlaps =
+                n.pitch == note.pitch && n.channel == note.channel
+                && n.time.0 < t1 && n.time.1 > t0;
+
+            if !overlaps {
+                trimmed.push(n);
+                continue
+            }
+
+            if n.time.0 < t0 {
+                trimmed.push(Note { time: (n.time.0, t0), ..n })
+            }
+            if n.time.1 > t1 {
+                trimmed.push(Note { time: (t1, n.time.1), ..n })
+            }
+        }
+
+        self.notes = trimmed
+    }
+
+    /// Adds a note, trimming the notes under it first if `replace` is set
+    fn place(&mut self, note: Note, replace: bool) {
+        if replace {
+            self.trim_overlaps(note)
+        }
+
+        self.notes.push(note)
+    }
+}
+
+pub struct PianoRoll {
+    state: State,
+    tool: Tool,
+    grid: ui::Grid,
+    play_pos: f32,
+    score: Score,
+    sounding: Vec<Note>,
+    /// The notes sounding for the keys held on the input, as they were sent,
+    /// so their releases match them
+    monitored: Vec<Note>,
+    keymap: KeyMap,
+    lookahead: Duration,
+    /// How far in pixels the mouse has to move before a press counts as a drag
+    click_threshold: f32,
+    /// Where the right button was pressed, to tell a click from a drag
+    right_press: Option<Vector2<f32>>,
+    preview: bool,
+    /// Whether a drawn note replaces the parts of the notes it overlaps
+    draw_replaces: bool,
+    /// Whether a click puts the play position on the nearest beat
+    snap: bool,
+    /// What the pencil snaps notes to
+    snap_resolution: SnapResolution,
+    /// Whether the typing keys enter notes at the play position
+    musical_typing: bool,
+    /// Whether recorded notes are moved to the nearest grid lines
+    quantize_recording: bool,
+    /// Measures of clicks before playing or recording starts
+    count_in_measures: u8,
+    /// How long notes sound in playback, as a part of their length
+    gate: f32,
+    /// How far playback delays every second subdivision, as a part of one
+    swing: f32,
+    /// Whether playback swings
+    swinging: bool,
+    /// The notes copied last
+    clipboard: Vec<Note>,
+    view_mode: ui::ViewMode,
+    /// The channel auditioned notes are played on, their own one if none
+    preview_channel: Option<u16>,
+    select_mode: SelectMode,
+    /// Zoom limits, as the view size in measures and pitches
+    min_view_span: Vector2<f32>,
+    max_view_span: Vector2<f32>,
+    /// Whether playback starts over when it reaches the end of the song
+    looping: bool,
+    /// The part of the song in measures that playback repeats, if any
+    loop_range: Option<(f32, f32)>,
+    /// Recent messages and commands, while the event log is on
+    event_log: Option<Vec<String>>,
+    /// Pitch classes of the key the grid shades when it's turned on
+    key: Vec<i16>,
+    rounded_notes: bool,
+    /// How many pixels plain note borders fade out over
+    note_feather: f32,
+    /// The channel new notes are drawn on, and muted or soloed by the keys
+    active_channel: u16,
+    muted: HashSet<u16>,
+    solo: Option<u16>,
+    /// Channels whose notes can't be selected, erased or resized
+    locked: HashSet<u16>,
+    /// How many times the score was changed, so edits can be noticed
+    /// without comparing scores
+    score_changes: u64,
+    /// How many notes the score may have, pasting or repeating more is refused
+    max_notes: usize,
+    /// Whether anything shown changed since the last `draw_changes`
+    dirty: bool,
+    /// Whether only the play position changed since the last `draw_changes`
+    play_bar_moved: bool,
+    /// The play bar's layer in the scene `draw_changes` keeps up to date
+    play_bar_layer: usize,
+}
+
+/// The view a new piano roll starts with: twelve measures and four octaves
+fn default_view() -> (Vector2<f32>, Vector2<f32>) {
+    (Vector2::new(-0.25, 31.0), Vector2::new(12.0, 155.0))
+}
+
+/// Whether a window is big enough to draw the editor in, unlike a
+/// minimized one
+pub fn is_usable_size(size: glutin::dpi::LogicalSize) -> bool {
+    size.width >= MIN_WINDOW_SIZE && size.height >= MIN_WINDOW_SIZE
+}
+
+impl Default for PianoRoll {
+    fn default() -> Self {
+        PianoRoll::new()
+    }
+}
+
+impl PianoRoll {
+    pub fn new() -> Self {
+        let score = Score::new();
+
+        let grid = ui::Grid::new(
+            Vector2::new(1024.0, 768.0),
+            default_view()
+        );
+
+        PianoRoll {
+            state: State::Idle,
+            tool: Tool::Arrow,
+            play_pos: 0.0,
+            sounding: vec![],
+            monitored: vec![],
+            keymap: KeyMap::new(),
+            lookahead: Duration::from_millis(50),
+            click_threshold: 4.0,
+            right_press: None,
+            preview: true,
+            musical_typing: false,
+            quantize_recording: false,
+            count_in_measures: 0,
+            gate: 1.0,
+            swing: DEFAULT_SWING,
+            swinging: false,
+            draw_replaces: true,
+            snap: true,
+            snap_resolution: SnapResolution::Free,
+            clipboard: vec![],
+            view_mode: ui::ViewMode::Notes,
+            preview_channel: None,
+            select_mode: SelectMode::Intersect,
+            min_view_span: Vector2::new(0.25, 6.0),
+            max_view_span: Vector2::new(64.0, 8.0 * 31.0),
+            looping: false,
+            loop_range: None,
+            event_log: None,
+            // The white keys, C major
+            key: vec![0, 5, 10, 13, 18, 23, 28],
+            rounded_notes: false,
+            note_feather: 0.0,
+            active_channel: 0,
+            muted: HashSet::new(),
+            solo: None,
+            locked: HashSet::new(),
+            score_changes: 0,
+            max_notes: DEFAULT_MAX_NOTES,
+            dirty: true,
+            play_bar_moved: false,
+            play_bar_layer: 0,
+            score, grid
+        }
+    }
+
+    pub fn set_keymap(&mut self, keymap: KeyMap) {
+        self.keymap = keymap
+    }
+
+    pub fn score(&self) -> &Score {
+        &self.score
+    }
+
+    pub fn score_changes(&self) -> u64 {
+        self.score_changes
+    }
+
+    /// Asks whether to recover an autosaved score. Agreeing sends
+    /// `Command::Recover`, declining keeps the score.
+    pub fn ask_recovery(&mut self) {
+        self.state = State::AskingRecovery;
+        self.dirty = true;
+    }
+
+    /// The notes copied last, as JSON
+    pub fn clipboard_text(&self) -> String {
+        serde_json::to_string(&self.clipboard).unwrap_or_default()
+    }
+
+    /// Pastes the notes in a JSON text at the play position and selects them.
+    /// Without a text that has valid notes, the ones copied last are pasted.
+    /// Nothing is pasted if the notes don't fit in the score or in the note limit.
+    pub fn paste_text(&mut self, text: Option<&str>) {
+        let parsed = text
+            .and_then(|t| serde_json::from_str::<Vec<Note>>(t).ok())
+            .filter(|notes| !notes.is_empty() && notes.iter().all(Note::is_valid));
+        let notes = parsed.unwrap_or_else(|| self.clipboard.clone());
+
+        match self.state {
+            State::Idle | State::NotesSelected(_) if !notes.is_empty() => (),
+            _ => return,
+        }
+        if !self.has_room(notes.len()) {
+            return
+        }
+
+        let start = notes.iter().map(|n| n.time.0).min().unwrap_or(0);
+        let at = (self.play_pos * self.score.measure_ticks as f32).round() as i16;
+        let shift = |t: i16| t.checked_sub(start)?.checked_add(at);
+        let pasted: Option<Vec<Note>> = notes.iter()
+            .map(|&n| Some(Note { time: (shift(n.time.0)?, shift(n.time.1)?), ..n }))
+            .collect();
+        let pasted = match pasted {
+            Some(pasted) => pasted,
+            None => return,
+        };
+
+        let placed = self.place_all(pasted);
+        self.select(placed);
+        self.dirty = true;
+    }
+
+    /// Replaces the edited score, dropping whatever was in progress
+    pub fn load_score(&mut self, score: Score) {
+        self.score = score;
+        if !self.snap_resolution.fits(self.score.measure_ticks) {
+            self.snap_resolution = SnapResolution::Free
+        }
+        self.state = State::Idle;
+        self.play_pos = 0.0;
+        self.dirty = true;
+    }
+
+    /// Shows the part of the score between two corners, in measures and pitches
+    pub fn set_view(&mut self, from: Vector2<f32>, to: Vector2<f32>) {
+        self.grid.view = (from, to);
+        self.grid.normalize_view();
+        self.dirty = true;
+    }
+
+    /// How many notes the score may have, a paste or repeat that would go over is refused
+    pub fn set_max_notes(&mut self, max_notes: usize) {
+        self.max_notes = max_notes
+    }
+
+    pub fn set_lookahead(&mut self, lookahead: Duration) {
+        self.lookahead = lookahead
+    }
+
+    /// Sets the key and turns its shading on
+    pub fn set_key(&mut self, pitch_classes: Vec<i16>) {
+        self.grid.key = Some(pitch_classes.clone());
+        self.key = pitch_classes;
+        self.dirty = true;
+    }
+
+    pub fn set_scale(&mut self, pitch_classes: Vec<i16>) {
+        self.grid.scale = pitch_classes;
+        self.dirty = true;
+    }
+
+    /// Physical pixels per logical pixel, for crisp lines on HiDPI screens
+    pub fn set_hidpi_factor(&mut self, factor: f64) {
+        self.grid.set_pixel_ratio(factor as f32);
+        self.dirty = true;
+    }
+
+    /// Divides every beat into parts the pencil snaps to, with every second
+    /// part moved later by `swing` of a part
+    pub fn set_subdivision(&mut self, subdivision: u8, swing: f32) {
+        self.grid.subdivision = subdivision.max(1);
+        self.grid.swing = swing;
+        self.dirty = true;
+    }
+
+    /// Widths of the thin and thick grid lines in logical pixels
+    pub fn set_line_widths(&mut self, thin: f32, thick: f32) {
+        self.grid.line_widths = Some((thin, thick));
+        self.dirty = true;
+    }
+
+    /// The mouse cursor for the current tool
+    pub fn cursor(&self) -> glutin::MouseCursor {
+        match self.tool {
+            Tool::Arrow | Tool::Eraser => glutin::MouseCursor::Default,
+            Tool::Pencil | Tool::Line | Tool::Step => glutin::MouseCursor::Crosshair,
+        }
+    }
+
+    /// Softens the edges of plain notes by fading their borders out over
+    /// this many pixels, zero for hard edges
+    pub fn set_note_feather(&mut self, pixels: f32) {
+        self.note_feather = pixels.clamp(0.0, MAX_NOTE_FEATHER);
+        self.dirty = true;
+    }
+
+    /// Values whose steps don't fall on whole ticks of the score are ignored
+    pub fn set_snap_resolution(&mut self, resolution: SnapResolution) {
+        if resolution.fits(self.score.measure_ticks) {
+            self.snap_resolution = resolution;
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_style(&mut self, style: ui::Style) {
+        self.grid.style = style;
+        self.dirty = true;
+    }
+
+    pub fn set_view_mode(&mut self, mode: ui::ViewMode) {
+        self.view_mode = mode;
+        self.dirty = true;
+    }
+
+    pub fn set_quantize_recording(&mut self, quantize: bool) {
+        self.quantize_recording = quantize
+    }
+
+    /// Scales how long notes sound in playback: below 1 is staccato, above is legato
+    pub fn set_gate(&mut self, gate: f32) {
+        self.gate = gate.clamp(MIN_GATE, MAX_GATE)
+    }
+
+    /// Swings playback by delaying every second subdivision by `swing` of one
+    pub fn set_swing(&mut self, swing: f32) {
+        self.swing = swing.clamp(0.0, 0.9);
+        self.swinging = true
+    }
+
+    pub fn set_count_in(&mut self, measures: u8) {
+        self.count_in_measures = measures
+    }
+
+    pub fn set_click_threshold(&mut self, pixels: f32) {
+        self.click_threshold = pixels
+    }
+
+    pub fn set_preview_channel(&mut self, channel: Option<u16>) {
+        self.preview_channel = channel
+    }
+
+    fn preview_note(&self, note: Note) -> Note {
+        Note {
+            channel: self.preview_channel.unwrap_or(note.channel),
+            ..note
+        }
+    }
+
+    /// Zoom factor for a wheel amount, limited so that a view of size `span`
+    /// scaled by it stays between `min` and `max`
+    fn zoom_factor(amount: f32, span: f32, min: f32, max: f32) -> f32 {
+        let amount = amount.clamp(-MAX_ZOOM_STEP, MAX_ZOOM_STEP);
+        let k = (1.07_f32).powf(-amount);
+
+        (span * k).max(min).min(max) / span
+    }
+
+    /// Zooms the pitches by `amount` around a fixed point. The fixed point is
+    /// a screen position, the same view position stays under it after the
+    /// zoom. It's mapped by the grid, like the cursor for any other
+    /// interaction, so it's right whatever the window size is.
+    fn scale_y(&mut self, fixed_point: Vector2<f32>, amount: f32) {
+        let start = self.grid.view.0.y;
+        let end = self.grid.view.1.y;
+
+        let split_point = self.grid.view_position(fixed_point).y;
+
+        let k = Self::zoom_factor(amount, end - start, self.min_view_span.y, self.max_view_span.y);
+
+        self.grid.view.0.y = (start - split_point) * k + split_point;
+        self.grid.view.1.y = (end - split_point) * k + split_point;
+    }
+
+    fn scale_x(&mut self, fixed_point: Vector2<f32>, amount: f32) {
+        let start = self.grid.view.0.x;
+        let end = self.grid.view.1.x;
+
+        let k = Self::zoom_factor(amount, end - start, self.min_view_span.x, self.max_view_span.x);
+
+        let split_point = self.grid.view_position(fixed_point).x;
+
+        self.grid.view.0.x = (start - split_point) * k + split_point;
+        self.grid.view.1.x = (end - split_point) * k + split_point;
+    }
+
+    fn scale_both(&mut self, fixed_point: Vector2<f32>, amount: f32) {
+        let (start, end) = self.grid.view;
+        let span = end - start;
+
+        // A shared factor keeps the aspect, so zooming stops as soon as
+        // either axis reaches its limit
+        let kx = Self::zoom_factor(amount, span.x, self.min_view_span.x, self.max_view_span.x);
+        let ky = Self::zoom_factor(amount, span.y, self.min_view_span.y, self.max_view_span.y);
+        let k =
+            if amount < 0.0 { kx.min(ky) }
+            else { kx.max(ky) };
+
+        let split_point = self.grid.view_position(fixed_point);
+
+        self.grid.view.0 = (start - split_point) * k + split_point;
+        self.grid.view.1 = (end - split_point) * k + split_point;
+    }
+
+    /// Zooms with the keys, in time around the play position and in pitch
+    /// around the middle of the view
+    fn key_zoom(&mut self, pitch: bool, amount: f32) {
+        let (v0, v1) = self.grid.view;
+        let center = Vector2::new(self.play_pos, (v0.y + v1.y) / 2.0);
+        let fixed_point = self.grid.screen_position(center);
+
+        if pitch { self.scale_y(fixed_point, amount) }
+        else { self.scale_x(fixed_point, amount) }
+
+        self.grid.normalize_view()
+    }
+
+    fn fit_pitch_range(&mut self) {
+        let pitches = self.score.notes.iter().map(|n| n.pitch);
+        let (low, high) = match (pitches.clone().min(), pitches.max()) {
+            (Some(low), Some(high)) => (low as f32, high as f32),
+            _ => return,
+        };
+
+        let margin =
+            if low == high { 2.0 * 31.0 }
+            else { 1.0 };
+        let (low, high) = (low - margin, high + margin);
+
+        // Same limit as the one scale_y zooms out to
+        let max_span = self.max_view_span.y;
+        let (low, high) =
+            if high - low > max_span {
+                let center = (low + high) / 2.0;
+                (center - max_span / 2.0, center + max_span / 2.0)
+            }
+            else { (low, high) };
+
+        self.grid.view.0.y = low;
+        self.grid.view.1.y = high;
+    }
+
+    fn audition(&self, note: Note, cmds: &mut Vec<Command>) {
+        if self.preview {
+            let now = Instant::now();
+            let note = self.preview_note(note);
+
+            cmds.push(Command::NoteOn(note, now));
+            cmds.push(Command::NoteOff(note, now + AUDITION_TIME));
+        }
+    }
+
+    fn note_contains(&self, note: &Note, view_pos: Vector2<f32>) -> bool {
+        let ticks = self.score.measure_ticks as f32;
+
+        view_pos.x >= note.time.0 as f32 / ticks
+        && view_pos.x <= note.time.1 as f32 / ticks
+        && (view_pos.y - note.pitch as f32).abs() < 0.5
+    }
+
+    fn st_grab_edge(&mut self, position: Vector2<f32>, modifiers: ModifiersState) {
+        if self.tool != Tool::Arrow || modifiers.shift || modifiers.ctrl || modifiers.alt { return }
+        if self.is_playing() { return }
+
+        let view_pos = self.grid.view_position(position);
+        let ticks = self.score.measure_ticks as f32;
+        let screen_x = |tick: i16| self.grid.screen_position(Vector2::new(tick as f32 / ticks, 0.0)).x;
+
+        for &note in self.score.notes.iter().filter(|n| self.note_contains(n, view_pos) && !self.is_locked(n)) {
+            let (start, end) = (screen_x(note.time.0), screen_x(note.time.1));
+            // On short notes the handles meet in the middle
+            let handle = ui::snap_to_pixels(ui::HANDLE_WIDTH, self.grid.pixel_ratio()).min((end - start) / 2.0);
+
+            let edge =
+                if position.x - start <= handle { Edge::Start }
+                else if end - position.x <= handle { Edge::End }
+                else { continue };
+
+            self.state = State::Resizing(note, edge, note.time);
+            return
+        }
+    }
+    fn st_resize_note(&mut self, position: Vector2<f32>) {
+        let view_pos = self.grid.view_position(position);
+
+        let (note, edge) = match self.state {
+            State::Resizing(note, edge, _) => (note, edge),
+            _ => return,
+        };
+        // Snapped like the ends of drawn notes
+        let tick = match edge {
+            Edge::Start => self.pencil_tick(view_pos.x),
+            Edge::End => self.pencil_end(view_pos.x),
+        };
+
+        self.state = State::Resizing(note, edge, edge.resize(note.time, tick.round() as i16))
+    }
+    fn st_drop_resized(&mut self) {
+        if let State::Resizing(note, _, time) = self.state {
+            let resized = Note { time, ..note };
+
+            self.score.notes.retain(|&n| n != note);
+            self.place(resized);
+
+            self.state = State::NotesSelected(vec![resized])
+        }
+    }
+    fn st_grab_selection(&mut self, position: Vector2<f32>, modifiers: ModifiersState) {
+        // With a modifier the press starts a frame that edits the selection
+        if self.tool != Tool::Arrow || modifiers.shift || modifiers.ctrl || modifiers.alt { return }
+
+        if let State::NotesSelected(ref selected) = self.state {
+            let view_pos = self.grid.view_position(position);
+
+            if let Some(&grabbed) = selected.iter().find(|n| self.note_contains(n, view_pos) && !self.is_locked(n)) {
+                self.state = State::MovingNotes(Move {
+                    notes: selected.iter().filter(|n| !self.is_locked(n)).cloned().collect(),
+                    grabbed,
+                    from: view_pos,
+                    shift: (0, 0),
+                    audition: None,
+                })
+            }
+        }
+    }
+    fn st_select_point(&mut self, position: Vector2<f32>, modifiers: ModifiersState) {
+        if self.tool == Tool::Arrow  {
+            if modifiers.alt {
+                self.state = State::Lasso(vec![position]);
+                return
+            }
+
+            let combine = match self.state {
+                State::MovingNotes(_) | State::Resizing(..) => return,
+                State::NotesSelected(ref selected) => Combine::new(modifiers, selected),
+                _ => Combine::new(modifiers, &[]),
+            };
+
+            self.state = State::PointSelected(position, combine);
+        }
+    }
+    fn st_move_notes(&mut self, position: Vector2<f32>, cmds: &mut Vec<Command>) {
+        let view_pos = self.grid.view_position(position);
+        let ticks = self.score.measure_ticks as f32;
+        let preview = self.preview;
+        let preview_channel = self.preview_channel;
+
+        if let State::MovingNotes(ref mut m) = self.state {
+            let delta = view_pos - m.from;
+            let shift = m.clamp_shift((
+                ((delta.x * FREE_SNAP_STEPS).round() / FREE_SNAP_STEPS * ticks).round() as i16,
+                delta.y.round() as i16
+            ));
+
+            if shift.1 != m.shift.1 && preview {
+                let now = Instant::now();
+                if let Some(prev) = m.audition.take() {
+                    cmds.push(Command::NoteOff(prev, now))
+                }
+
+                let target = Note {
+                    pitch: m.grabbed.pitch + shift.1,
+                    channel: preview_channel.unwrap_or(m.grabbed.channel),
+                    ..m.grabbed
+                };
+                cmds.push(Command::NoteOn(target, now));
+                m.audition = Some(target);
+            }
+
+            m.shift = shift;
+        }
+    }
+    fn st_drop_notes(&mut self, cmds: &mut Vec<Command>) {
+        let m = match self.state {
+            State::MovingNotes(ref m) => m.clone(),
+            _ => return,
+        };
+        if let Some(prev) = m.audition {
+            cmds.push(Command::NoteOff(prev, Instant::now()))
+        }
+
+        let moved = match m.moved() {
+            Some(moved) => moved,
+            None => {
+                self.state = State::NotesSelected(m.notes);
+                return
+            }
+        };
+
+        // Dropped notes cut the ones they land on, like drawn ones
+        self.score.notes.retain(|n| !m.notes.contains(n));
+        let placed = self.place_all(moved);
+        self.select(placed)
+    }
+    fn st_erase_note(&mut self, position: Vector2<f32>, modifiers: ModifiersState) {
+        if self.tool == Tool::Pencil && modifiers.ctrl {
+            self.erase_at(position)
+        }
+    }
+    fn st_start_erasing(&mut self, position: Vector2<f32>) {
+        if self.tool == Tool::Eraser {
+            self.erase_at(position);
+
+            if self.is_playing() { return }
+            self.state = State::Erasing
+        }
+    }
+    fn st_stop_erasing(&mut self) {
+        if let State::Erasing = self.state {
+            self.state = State::Idle
+        }
+    }
+
+    fn st_start_line(&mut self, position: Vector2<f32>) {
+        if self.tool == Tool::Line {
+            if self.is_playing() { return }
+
+            self.state = State::DrawingLine(position, position)
+        }
+    }
+    fn st_place_line(&mut self) {
+        if let State::DrawingLine(from, to) = self.state {
+            for note in self.line_notes(from, to) {
+                self.place(note)
+            }
+
+            self.state = State::Idle
+        }
+    }
+
+    fn st_toggle_step(&mut self, position: Vector2<f32>, cmds: &mut Vec<Command>) {
+        if self.tool != Tool::Step { return }
+
+        let view_pos = self.grid.view_position(position);
+        let (start, end) = self.step_cell(self.step_index(view_pos.x));
+        let pitch = view_pos.y.round() as i16;
+
+        let hit = self.score.notes.iter()
+            .position(|n| n.pitch == pitch && n.time.0 == start);
+
+        match hit {
+            Some(i) if self.is_locked(&self.score.notes[i]) => (),
+            Some(i) => {
+                self.score.notes.remove(i);
+                self.score_changes += 1;
+            },
+            None => {
+                let note = Note {
+                    time: (start, end),
+                    pitch,
+                    channel: self.active_channel,
+                    velocity: DEFAULT_VELOCITY,
+                };
+
+                self.audition(note, cmds);
+                self.place(note)
+            },
+        }
+    }
+
+    /// How many steps of the step and line tools a whole note has: the
+    /// subdivisions of its beats if they're subdivided, otherwise the beats
+    fn steps_per_whole(&self) -> i32 {
+        4 * self.grid.subdivision.max(1) as i32
+    }
+
+    /// The step a position in whole notes falls in, counted from the start
+    fn step_index(&self, pos: f32) -> i32 {
+        (pos.max(0.0) * self.steps_per_whole() as f32).floor() as i32
+    }
+
+    /// The start and end tick of a step, at least a tick apart
+    fn step_cell(&self, step: i32) -> (i16, i16) {
+        let ticks = self.score.measure_ticks as f32 / self.steps_per_whole() as f32;
+        let tick = |step: i32| (step as f32 * ticks).round() as i16;
+
+        (tick(step), tick(step + 1).max(tick(step) + 1))
+    }
+
+    /// Notes a step apart along the line between two screen points, their
+    /// pitches moved to the nearest ones in the highlighted scale
+    fn line_notes(&self, from: Vector2<f32>, to: Vector2<f32>) -> Vec<Note> {
+        let (from, to) = (self.grid.view_position(from), self.grid.view_position(to));
+
+        let snap = |x: f32| (x.max(0.0) * self.steps_per_whole() as f32).round() as i32;
+        let (s0, s1) = (snap(from.x), snap(to.x));
+
+        (s0.min(s1)..=s0.max(s1))
+            .map(|step| {
+                let along =
+                    if s0 == s1 { 0.0 }
+                    else { (step - s0) as f32 / (s1 - s0) as f32 };
+                let pitch = from.y + along * (to.y - from.y);
+
+                Note {
+                    time: self.step_cell(step),
+                    pitch: self.nearest_in_scale(pitch.round() as i16),
+                    channel: self.active_channel,
+                    velocity: DEFAULT_VELOCITY,
+                }
+            })
+            .collect()
+    }
+
+    /// The closest pitch to `pitch` in the highlighted scale, higher on ties
+    fn nearest_in_scale(&self, pitch: i16) -> i16 {
+        let in_scale = |p: i16| self.grid.scale.contains(&p.rem_euclid(31));
+
+        (0..16)
+            .flat_map(|d| vec![pitch + d, pitch - d])
+            .find(|&p| in_scale(p))
+            .unwrap_or(pitch)
+    }
+
+    /// Enters a note of the default length at the play position, on a degree
+    /// of the highlighted scale counted from the octave in the middle of the view
+    fn type_note(&mut self, degree: i16, advance: bool, cmds: &mut Vec<Command>) {
+        let center = (self.grid.view.0.y + self.grid.view.1.y) / 2.0;
+        let base = (center / 31.0).floor() as i16 * 31;
+
+        let mut scale = self.grid.scale.clone();
+        scale.sort();
+        scale.dedup();
+        let pitch =
+            if scale.is_empty() { base + degree }
+            else {
+                let len = scale.len() as i16;
+                base + 31 * degree.div_euclid(len) + scale[degree.rem_euclid(len) as usize]
+            };
+
+        let start = (self.play_pos * self.score.measure_ticks as f32).round() as i16;
+        let note = Note {
+            channel: self.active_channel,
+            time: (start, start + self.default_note_len()),
+            pitch,
+            velocity: DEFAULT_VELOCITY,
+        };
+
+        self.state = State::Idle;
+        self.place(note);
+        self.audition(note, cmds);
+
+        if advance {
+            self.play_pos = note.time.1 as f32 / self.score.measure_ticks as f32
+        }
+    }
+
+    /// Removes the notes under a screen point
+    fn erase_at(&mut self, position: Vector2<f32>) {
+        let view_pos = self.grid.view_position(position);
+        let erased: Vec<Note> = self.score.notes.iter()
+            .filter(|n| self.note_contains(n, view_pos) && !self.is_locked(n))
+            .cloned()
+            .collect();
+
+        if !erased.is_empty() {
+            self.score.notes.retain(|n| !erased.contains(n));
+            self.score_changes += 1;
+        }
+    }
+    /// The tick the pencil snaps a position in measures to: the nearest step of
+    /// the snap resolution, or the nearest subdivision line if the beats are
+    /// subdivided, otherwise the nearest eighth
+    fn pencil_tick(&self, pos: f32) -> f32 {
+        match self.snap_resolution.steps() {
+            Some(steps) => self.snapped_tick(pos, steps),
+            None if self.grid.subdivision > 1 =>
+                (self.grid.nearest_subdivision(&self.score.meter, pos) * self.score.measure_ticks as f32).round(),
+            None => self.snapped_tick(pos, FREE_SNAP_STEPS),
+        }
+    }
+
+    /// The tick of the nearest step to a position in measures, with `steps` steps to a whole note
+    fn snapped_tick(&self, pos: f32, steps: f32) -> f32 {
+        ((pos * steps).round() / steps * self.score.measure_ticks as f32).round()
+    }
+
+    /// How long a note entered without a length is, a quarter note
+    fn default_note_len(&self) -> i16 {
+        (self.score.measure_ticks / 4).max(1) as i16
+    }
+
+    /// Where the pencil puts the end of a dragged note, snapped unless snapping is free
+    fn pencil_end(&self, pos: f32) -> f32 {
+        if self.snap_resolution != SnapResolution::Free || self.grid.subdivision > 1 {
+            self.pencil_tick(pos)
+        }
+        else { pos * self.score.measure_ticks as f32 }
+    }
+
+    /// How long a note placed with a click is, one step of the snap resolution if it has one
+    fn click_note_len(&self) -> i16 {
+        match self.snap_resolution.steps() {
+            Some(steps) => ((self.score.measure_ticks as f32 / steps).round() as i16).max(1),
+            None => self.default_note_len(),
+        }
+    }
+    fn st_draw_brick(&mut self, position: Vector2<f32>, modifiers: ModifiersState, cmds: &mut Vec<Command>) {
+        if self.tool == Tool::Pencil && !modifiers.ctrl && !modifiers.shift {
+            let view_pos = self.grid.view_position(position);
+
+            let time = self.pencil_tick(view_pos.x);
+            let pitch = view_pos.y;
+
+            let brick = Brick {
+                time: (time, time),
+                pitch,
+                channel: self.active_channel,
+                press: Some(position),
+            };
+            self.audition(brick.into(), cmds);
+
+            self.state = State::Drawing(brick);
+        }
+    }
+    fn st_draw_chord(&mut self, position: Vector2<f32>, modifiers: ModifiersState, cmds: &mut Vec<Command>) {
+        if self.tool == Tool::Pencil && !modifiers.ctrl && modifiers.shift {
+            let view_pos = self.grid.view_position(position);
+            let channel = self.active_channel;
+
+            let mut bricks = match self.state {
+                State::DrawingChord(ref bricks) => bricks.clone(),
+                _ => vec![],
+            };
+            let time = match bricks.first() {
+                Some(first) => first.time,
+                None => {
+                    let time = self.pencil_tick(view_pos.x);
+                    (time, time)
+                },
+            };
+
+            // Clicking a pitch that's already in the chord does nothing
+            let pitch = view_pos.y.round();
+            if !bricks.iter().any(|b| b.pitch.round() == pitch) {
+                let brick = Brick { time, pitch, channel, press: Some(position) };
+                self.audition(brick.into(), cmds);
+                bricks.push(brick)
+            }
+
+            self.state = State::DrawingChord(bricks)
+        }
+    }
+    fn st_place_chord(&mut self) {
+        if let State::DrawingChord(ref bricks) = self.state {
+            for brick in bricks.clone() {
+                self.place_brick(brick)
+            }
+
+            self.state = State::Idle
+        }
+    }
+    /// Where the playhead lands when clicking a screen point, in measures
+    fn click_time(&self, point: Vector2<f32>) -> f32 {
+        let time = self.grid.view_position(point).x;
+
+        if self.snap { self.nearest_beat(time) }
+        else { time }
+    }
+
+    fn st_set_time(&mut self) {
+        if let State::PointSelected(point, ref combine) = self.state {
+            let time = self.click_time(point);
+            let previous = combine.previous().to_vec();
+
+            self.play_pos = time;
+            self.state =
+                if previous.is_empty() { State::Idle }
+                else { State::NotesSelected(previous) };
+        }
+    }
+    fn st_drop_playhead(&mut self, cmds: &mut Vec<Command>) {
+        if let State::DraggingPlayhead(resume) = self.state {
+            if resume { self.start_playing(cmds) }
+            else { self.state = State::Idle }
+        }
+    }
+    fn st_set_loop(&mut self) {
+        if let State::RulerSelect(_, from, to) = self.state {
+            // A click without dragging clears the loop
+            self.loop_range =
+                if from == to { None }
+                else { Some((from.min(to), from.max(to))) };
+
+            self.state = State::Idle
+        }
+    }
+    fn st_select_framed(&mut self) {
+        if let State::SelectFrame(v0, v1, ref combine) = self.state {
+            let (v0, v1) = normalize_square(
+                self.grid.view_position(v0),
+                self.grid.view_position(v1)
+            );
+
+            let ticks = { self.score.measure_ticks as f32 };
+            let takes = match self.select_mode {
+                SelectMode::Intersect => rects_overlap,
+                SelectMode::Contain => rect_contains,
+            };
+            let framed: Vec<Note> = self.score.notes.iter().filter(|n| !self.is_locked(n)).filter(|n| {
+                let n0 = Vector2::new(n.time.0 as f32 / ticks, n.pitch as f32 - 0.5);
+                let n1 = Vector2::new(n.time.1 as f32 / ticks, n.pitch as f32 + 0.5);
+
+                takes(v0, v1, n0, n1)
+            }).cloned().collect();
+            let selected = combine.apply(framed);
+
+            if selected.is_empty() {
+                self.state = State::Idle
+            }
+            else {
+                self.state = State::NotesSelected(selected)
+            }
+        };
+    }
+    fn st_select_lassoed(&mut self) {
+        if let State::Lasso(ref points) = self.state {
+            let ticks = self.score.measure_ticks as f32;
+            let selected: Vec<Note> =
+                if points.len() < 3 { vec![] }
+                else {
+                    self.score.notes.iter().filter(|n| !self.is_locked(n)).filter(|n| {
+                        let center = Vector2::new(
+                            (n.time.0 + n.time.1) as f32 / 2.0 / ticks,
+                            n.pitch as f32
+                        );
+
+                        point_in_polygon(self.grid.screen_position(center), points)
+                    }).cloned().collect()
+                };
+
+            if selected.is_empty() {
+                self.state = State::Idle
+            }
+            else {
+                self.state = State::NotesSelected(selected)
+            }
+        }
+    }
+    fn st_create_brick(&mut self) {
+        if let State::Drawing(brick) = self.state {
+            self.place_brick(brick);
+
+            self.state = State::Idle
+        }
+    }
+
+    fn place_brick(&mut self, brick: Brick) {
+        if brick.time.0.round() != brick.time.1.round() {
+            self.place(brick.into())
+        }
+        else if brick.time.0 == brick.time.1 {
+            // A click without dragging places a note of the default length
+            let note: Note = brick.into();
+            let note = Note {
+                time: (note.time.0, note.time.0 + self.click_note_len()),
+                ..note
+            };
+
+            self.place(note)
+        }
+    }
+    //st_change_brick
+
+    /// Whether a note is on a locked channel, and so kept as it is
+    fn is_locked(&self, note: &Note) -> bool {
+        self.locked.contains(&note.channel)
+    }
+
+    /// Adds a note, trimming the ones under it if drawing replaces, unless they're locked
+    fn place(&mut self, note: Note) {
+        let replace = self.draw_replaces && !self.locked.contains(&note.channel);
+
+        self.score.place(note, replace);
+        self.score_changes += 1;
+    }
+
+    /// Whether `count` more notes fit in the note limit
+    fn has_room(&self, count: usize) -> bool {
+        self.score.notes.len().saturating_add(count) <= self.max_notes
+    }
+
+    /// Places notes like drawn ones, and returns the ones that are left whole,
+    /// since notes placed together on one pitch may cut each other
+    fn place_all(&mut self, notes: Vec<Note>) -> Vec<Note> {
+        for &n in &notes {
+            self.place(n)
+        }
+
+        let notes: HashSet<Note> = notes.into_iter().collect();
+        self.score.notes.iter().filter(|n| notes.contains(n)).cloned().collect()
+    }
+
+    /// Whether notes on a channel are played, by the mute and solo settings
+    fn audible(&self, channel: u16) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted.contains(&channel),
+        }
+    }
+
+    fn note_view(&self, notes: Vec<Note>, selected: bool) -> ui::NoteView {
+        let dimmed = notes.iter()
+            .map(|n| n.channel)
+            .filter(|&c| !self.audible(c))
+            .collect();
+        let locked = self.locked.clone();
+
+        ui::NoteView {
+            notes, selected, dimmed, locked,
+            measure_ticks: self.score.measure_ticks,
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+            view: self.grid.view,
+            rounded: self.rounded_notes,
+            feather: self.note_feather,
+        }
+    }
+
+    /// The corners of the minimap, in the bottom right corner of the window
+    fn minimap_rect(&self) -> (Vector2<f32>, Vector2<f32>) {
+        let size = self.grid.size;
+
+        (Vector2::new(size.x - 210.0, 10.0), Vector2::new(size.x - 10.0, 90.0))
+    }
+
+    fn minimap(&self) -> ui::MiniMap {
+        let ticks = self.score.measure_ticks as f32;
+        let end = self.score.notes.iter().map(|n| n.time.1).max().unwrap_or(0);
+
+        ui::MiniMap {
+            notes: self.score.notes.clone(),
+            measure_ticks: self.score.measure_ticks,
+            extent: (
+                Vector2::new(0.0, 0.0),
+                Vector2::new((end as f32 / ticks).max(1.0), 8.0 * 31.0)
+            ),
+            view: self.grid.view,
+            rect: self.minimap_rect(),
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+        }
+    }
+
+    /// Moves the view back within the pitches, and within the ends of a
+    /// bounded score, the same bounds dragging the view keeps to
+    fn keep_view_in_bounds(&mut self) {
+        let (v0, v1) = self.grid.view;
+
+        let dy = (MAX_PITCH as f32 - v1.y).min(0.0).max(-v0.y);
+        let dx = self.score.length_pos()
+            .map_or(0.0, |length| (length + 1.0 - v1.x).min(0.0).max(-1.0 - v0.x));
+
+        let shift = Vector2::new(dx, dy);
+        self.grid.view = (v0 + shift, v1 + shift);
+    }
+
+    /// Lines describing the selected notes: their count, pitch and time span
+    fn selection_info(&self, notes: &[Note]) -> Vec<String> {
+        let low = notes.iter().map(|n| n.pitch).min().unwrap_or(0);
+        let high = notes.iter().map(|n| n.pitch).max().unwrap_or(0);
+        let start = notes.iter().map(|n| n.time.0).min().unwrap_or(0);
+        let end = notes.iter().map(|n| n.time.1).max().unwrap_or(0);
+
+        let position = |t: i16| t as f32 / self.score.measure_ticks as f32;
+        let beats = (position(end) - position(start)) * 4.0;
+
+        vec![
+            format!("{} {}", notes.len(), if notes.len() == 1 { "note" } else { "notes" }),
+            format!("pitch {}-{} ({} steps)", low, high, high - low),
+            format!(
+                "time {}-{} ({} beats)",
+                self.time_label(position(start)), self.time_label(position(end)), beats,
+            ),
+        ]
+    }
+
+    /// A position in whole notes as measure:beat, both counted from 1
+    fn time_label(&self, pos: f32) -> String {
+        let meter = &self.score.meter;
+        let measure = meter.measure_at(pos);
+        let beat = (pos - meter.measure_start(measure)) * 4.0 + 1.0;
+
+        if (beat - beat.round()).abs() < 0.01 {
+            format!("{}:{}", measure + 1, beat.round())
+        }
+        else {
+            format!("{}:{:.2}", measure + 1, beat)
+        }
+    }
+
+    /// Replaces the selected notes with `edit`'s result and selects it
+    fn edit_selection<F>(&mut self, edit: F)
+    where F: FnOnce(&[Note]) -> Vec<Note>
+    {
+        if let State::NotesSelected(ref selected) = self.state {
+            let edited = edit(selected);
+
+            self.score.notes.retain(|n| !selected.contains(n));
+            self.score.notes.extend(edited.iter().cloned());
+            self.score_changes += 1;
+
+            self.select(edited)
+        }
+    }
+
+    /// Selects the notes that aren't locked, e.g. the ones just added
+    fn select(&mut self, mut notes: Vec<Note>) {
+        notes.retain(|n| !self.is_locked(n));
+
+        self.state =
+            if notes.is_empty() { State::Idle }
+            else { State::NotesSelected(notes) };
+    }
+
+    fn set_channel_selection(&mut self, channel: u16) {
+        self.edit_selection(|notes| notes.iter().map(|&n| Note { channel, ..n }).collect())
+    }
+
+    /// Rounds the lengths of the selected notes to a multiple of `grid_ticks`,
+    /// at least one. A note whose rounded end would be past the last tick
+    /// keeps its length.
+    fn quantize_lengths(&mut self, grid_ticks: i16) {
+        let grid_ticks = grid_ticks.max(1);
+
+        self.edit_selection(|notes| notes.iter().map(|&n| {
+            let length = n.time.1 as f32 - n.time.0 as f32;
+            let units = (length / grid_ticks as f32).round().max(1.0) as i16;
+            let end = units.checked_mul(grid_ticks).and_then(|length| n.time.0.checked_add(length));
+
+            match end {
+                Some(end) => Note { time: (n.time.0, end), ..n },
+                None => n,
+            }
+        }).collect())
+    }
+
+    /// Adds `count` copies of the selected notes, each `spacing_ticks` after
+    /// the previous one, and selects the copies. Nothing is added if the
+    /// copies would go past the last tick or the note limit.
+    fn repeat_selection(&mut self, count: u32, spacing_ticks: i16) {
+        let copies = match self.state {
+            State::NotesSelected(ref selected) => repeated(selected, count, spacing_ticks),
+            _ => return,
+        };
+
+        if let Some(copies) = copies.filter(|c| self.has_room(c.len())) {
+            let placed = self.place_all(copies);
+            self.select(placed)
+        }
+    }
+
+    /// Reverses the selected notes in time within the span they cover
+    fn retrograde_selection(&mut self) {
+        self.edit_selection(|notes| {
+            let start = notes.iter().map(|n| n.time.0).min().unwrap_or(0);
+            let end = notes.iter().map(|n| n.time.1).max().unwrap_or(0);
+
+            notes.iter().map(|&n| Note {
+                time: (start + end - n.time.1, start + end - n.time.0),
+                ..n
+            }).collect()
+        })
+    }
+
+    /// Reverses every selected note in time within the measure it starts in.
+    /// Notes running over the bar line are cut at it first.
+    fn mirror_in_measure_selection(&mut self) {
+        let ticks = self.score.measure_ticks as f32;
+        let meter = self.score.meter.clone();
+
+        self.edit_selection(|notes| notes.iter().map(|&n| {
+            let measure = meter.measure_at(n.time.0 as f32 / ticks);
+            let start = (meter.measure_start(measure) * ticks).round() as i16;
+            let end = (meter.measure_start(measure + 1) * ticks).round() as i16;
+
+            Note {
+                time: (start + end - n.time.1.min(end), start + end - n.time.0),
+                ..n
+            }
+        }).collect())
+    }
+
+    /// Reflects the pitches of the selected notes about `pivot`. The pivot is
+    /// rounded to a half step so the result stays on pitches. Ties go to even,
+    /// which keeps inverting twice about the mean pitch an identity. Nothing
+    /// changes if a pitch would fall outside 0 to `MAX_PITCH`.
+    fn invert_selection(&mut self, pivot: f32) {
+        let twice = (2.0 * pivot).round_ties_even() as i32;
+        let fits = match self.state {
+            State::NotesSelected(ref notes) =>
+                notes.iter().all(|n| (0..=MAX_PITCH as i32).contains(&(twice - n.pitch as i32))),
+            _ => false,
+        };
+        if !fits { return }
+
+        self.edit_selection(|notes| notes.iter().map(|&n| Note {
+            pitch: (twice - n.pitch as i32) as i16,
+            ..n
+        }).collect())
+    }
+
+    /// The nearest beat line, every meter counting quarter notes
+    fn nearest_beat(&self, pos: f32) -> f32 {
+        (pos * 4.0).round() / 4.0
+    }
+
+    /// The beat line next to `pos` in the direction of `step`, 1 or -1
+    fn step_beat(&self, pos: f32, step: i32) -> f32 {
+        // Nudged so that a position on a beat line counts as being on it
+        let beat =
+            if step > 0 { (pos * 4.0 + 1e-3).floor() + 1.0 }
+            else { (pos * 4.0 - 1e-3).ceil() - 1.0 };
+
+        beat / 4.0
+    }
+
+    /// The next grid line from `pos` in the direction of `step`, 1 or -1.
+    /// Stops at the start and the end of the song, or with looping on goes
+    /// around to the other one.
+    fn step_grid(&self, pos: f32, step: i32) -> f32 {
+        let ticks = self.score.measure_ticks as f32;
+        let end = self.score.song_end() as f32 / ticks;
+
+        let next =
+            if self.snap { self.step_beat(pos, step) }
+            else if step > 0 { ((pos * ticks + 1e-3).floor() + 1.0) / ticks }
+            else { ((pos * ticks - 1e-3).ceil() - 1.0) / ticks };
+
+        if next > end + 1e-3 && step > 0 {
+            if self.looping { 0.0 } else { pos }
+        }
+        else if next < -1e-3 {
+            if self.looping { end } else { 0.0 }
+        }
+        else {
+            next
+        }
+    }
+
+    /// Moves the play position, auditioning the notes sounding there
+    fn seek(&mut self, pos: f32, cmds: &mut Vec<Command>) {
+        self.play_pos = pos.max(0.0);
+
+        let tick = (self.play_pos * self.score.measure_ticks as f32).round() as i16;
+        for &n in &self.score.notes {
+            if n.time.0 <= tick && n.time.1 > tick {
+                self.audition(n, cmds)
+            }
+        }
+    }
+
+    fn on_left_press(&mut self, position: Vector2<f32>, modifiers: ModifiersState, cmds: &mut Vec<Command>) {
+        // A click anywhere closes the menu, a click on an item also picks it
+        if let State::ContextMenu(corner, ref items, ref notes) = self.state {
+            let item = self.menu(corner, items).item_at(position).map(|i| items[i]);
+
+            self.state = State::NotesSelected(notes.clone());
+            if let Some(item) = item {
+                self.on_menu_item(item, cmds)
+            }
+            return
+        }
+
+        // Jumping to a part of the score, unless the press belongs to a drag,
+        // a prompt or a recording already going on
+        let minimap = self.minimap();
+        let can_jump = matches!(self.state, State::Idle | State::NotesSelected(_) | State::Playing(_));
+        if can_jump && minimap.contains(position) {
+            let center = minimap.view_position(position);
+            let half = (self.grid.view.1 - self.grid.view.0) / 2.0;
+
+            self.grid.view = (center - half, center + half);
+            self.grid.normalize_view();
+            self.keep_view_in_bounds();
+            return
+        }
+
+        if self.tool == Tool::Arrow {
+            let (playing, pos) = match self.playback() {
+                Some(playback) => (true, playback.pos),
+                None => (false, self.play_pos),
+            };
+            let x = self.grid.screen_position(Vector2::new(pos, 0.0)).x;
+
+            if (position.x - x).abs() <= PLAYHEAD_GRAB {
+                if playing { self.stop_playing(cmds) }
+
+                self.play_pos = pos;
+                self.state = State::DraggingPlayhead(playing);
+                return
+            }
+        }
+
+        let on_ruler = position.y >= self.grid.size.y - ui::Ruler::HEIGHT;
+        if on_ruler {
+            if self.is_playing() { return }
+
+            let pos = self.nearest_beat(self.grid.view_position(position).x);
+            self.state = State::RulerSelect(Some(position), pos, pos);
+            return
+        }
+
+        // Any other press ends the chord
+        if !modifiers.shift || self.tool != Tool::Pencil {
+            self.st_place_chord()
+        }
+
+        self.st_grab_edge(position, modifiers);
+        self.st_grab_selection(position, modifiers);
+        self.st_select_point(position, modifiers);
+        self.st_erase_note(position, modifiers);
+        self.st_start_erasing(position);
+        self.st_start_line(position);
+        self.st_toggle_step(position, cmds);
+        self.st_draw_brick(position, modifiers, cmds);
+        self.st_draw_chord(position, modifiers, cmds)
+    }
+
+    fn on_right_click(&mut self, position: Vector2<f32>) {
+        let view_pos = self.grid.view_position(position);
+        let clicked = self.score.notes.iter()
+            .find(|n| self.note_contains(n, view_pos) && !self.is_locked(n))
+            .cloned();
+
+        let selected = match self.state {
+            State::Idle => vec![],
+            State::NotesSelected(ref selected) | State::ContextMenu(_, _, ref selected) => selected.clone(),
+            _ => return,
+        };
+
+        let note = match clicked {
+            Some(note) => note,
+            None => {
+                // Clicking away closes the menu
+                if let State::ContextMenu(..) = self.state {
+                    self.state = State::NotesSelected(selected)
+                }
+                return
+            },
+        };
+
+        // The menu acts on the whole selection if the note is part of it
+        let notes =
+            if selected.contains(&note) { selected }
+            else { vec![note] };
+        let mut items = vec![
+            MenuItem::Delete,
+            MenuItem::Duplicate,
+        ];
+        // The channels in the score and the chosen one, except the one all
+        // the notes are on already, and the locked ones that would take the
+        // notes out of reach
+        let mut channels: Vec<u16> = self.score.notes.iter()
+            .map(|n| n.channel)
+            .chain(Some(self.active_channel))
+            .filter(|&c| !self.locked.contains(&c) && notes.iter().any(|n| n.channel != c))
+            .collect();
+        channels.sort();
+        channels.dedup();
+        items.extend(channels.into_iter().map(MenuItem::Channel));
+        // The property panel edits a single note
+        if notes.len() == 1 {
+            items.push(MenuItem::Properties)
+        }
+
+        // Keep the whole menu on the screen
+        let size = self.menu(position, &items).size();
+        let corner = Vector2::new(
+            position.x.min(self.grid.size.x - size.x).max(0.0),
+            position.y.max(size.y),
+        );
+
+        self.state = State::ContextMenu(corner, items, notes)
+    }
+
+    fn on_menu_item(&mut self, item: MenuItem, cmds: &mut Vec<Command>) {
+        match item {
+            MenuItem::Delete => { self.on_action(Action::Delete, cmds); },
+            MenuItem::Duplicate => { self.on_action(Action::RepeatSelection, cmds); },
+            MenuItem::Channel(channel) => self.set_channel_selection(channel),
+            MenuItem::Properties => { self.on_action(Action::EditNote, cmds); },
+        }
+    }
+
+    fn menu(&self, corner: Vector2<f32>, items: &[MenuItem]) -> ui::Menu {
+        ui::Menu {
+            items: items.iter().map(|i| i.label()).collect(),
+            position: corner,
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+        }
+    }
+
+    fn on_double_click(&mut self, position: Vector2<f32>, modifiers: ModifiersState) {
+        let view_pos = self.grid.view_position(position);
+        let clicked = self.score.notes.iter()
+            .find(|n| self.note_contains(n, view_pos) && !self.is_locked(n))
+            .cloned();
+
+        match (self.tool, &self.state) {
+            // Selects just the clicked note, dropping the rest of the selection
+            (Tool::Arrow, State::PointSelected(..)) | (Tool::Arrow, State::MovingNotes(_))
+            if !modifiers.alt => {
+                if let Some(note) = clicked {
+                    self.state = State::NotesSelected(vec![note])
+                }
+            },
+            // The first click already placed a default note, so only place
+            // one if there's none (e.g. the first click erased it)
+            (Tool::Pencil, State::Drawing(brick)) => {
+                if clicked.is_none() {
+                    let note: Note = (*brick).into();
+                    let note = Note {
+                        time: (note.time.0, note.time.0 + self.click_note_len()),
+                        ..note
+                    };
+
+                    self.place(note)
+                }
+
+                self.state = State::Idle
+            },
+            _ => (),
+        }
+    }
+
+    fn on_left_release(&mut self, cmds: &mut Vec<Command>) {
+        self.st_drop_playhead(cmds);
+        self.st_set_loop();
+        self.st_set_time();
+        self.st_select_framed();
+        self.st_select_lassoed();
+        self.st_create_brick();
+        self.st_drop_resized();
+        self.st_stop_erasing();
+        self.st_place_line();
+        self.st_drop_notes(cmds)
+    }
+
+    /// Where a tick is played with swing: in every pair of subdivisions of a
+    /// beat (eighths without subdivisions), the middle one is moved later by
+    /// `swing` of a subdivision, and the ones around it are stretched to match.
+    /// The notes stay where they are.
+    fn swung_tick(&self, tick: f32) -> f32 {
+        if !self.swinging || self.swing == 0.0 {
+            return tick
+        }
+
+        // Beats are quarter notes in every meter
+        let ticks = self.score.measure_ticks as f32 / 4.0;
+        let parts = if self.grid.subdivision > 1 { self.grid.subdivision as f32 } else { 2.0 };
+
+        // Position in subdivisions from the start of the beat
+        let beat = (tick / ticks).floor();
+        let part = (tick / ticks - beat) * parts;
+        let pair = (part / 2.0).floor();
+        // An odd subdivision left over at the end of the beat isn't swung
+        if 2.0 * pair + 2.0 > parts {
+            return tick
+        }
+
+        let u = part - 2.0 * pair;
+        let swung =
+            if u < 1.0 { u * (1.0 + self.swing) }
+            else { 1.0 + self.swing + (u - 1.0) * (1.0 - self.swing) };
+        (beat + (2.0 * pair + swung) / parts) * ticks
+    }
+
+    /// The playback going on, while playing or recording
+    fn playback(&self) -> Option<&Playback> {
+        match self.state {
+            State::Playing(ref playback) | State::Recording(ref playback, _) => Some(playback),
+            _ => None,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playback().is_some()
+    }
+
+    fn on_time(&mut self, time: Duration, cmds: &mut Vec<crate::Command>) {
+        if let Some(playback) = self.playback() {
+            let (start, start_seconds, last_tick, count_in) =
+                (playback.start, playback.start_seconds, playback.last_tick, playback.count_in);
+            let ticks = self.score.measure_ticks as f32;
+            let tempo = self.score.tempo_map();
+            // `time` is the time since the start, not since the last update,
+            // so late or missed updates don't add up to a drift. It's before
+            // the start of the song during the count-in, which holds the play bar.
+            let seconds = start_seconds + duration_seconds(time) - count_in;
+            let pos = tempo.tick_at(seconds.max(start_seconds)) / ticks;
+            // Notes are sent `lookahead` early, stamped with the moment they
+            // are meant to sound at, so the backend can schedule them precisely
+            let tick = tempo.tick_at(seconds + duration_seconds(self.lookahead)).floor() as i16;
+            // Nothing past the loop is sent ahead, playback starts over before it
+            let loop_end = self.loop_range.map(|(_, end)| (end * ticks).round() as i16);
+            let tick = match loop_end {
+                Some(end) => tick.min(end - 1),
+                None => tick,
+            };
+
+            // Every tick between the last played one and the current one fires
+            // exactly once, even if a slow frame skipped over some of them
+            for t in (last_tick + 1)..=tick {
+                let offset = (tempo.seconds_at(self.swung_tick(t as f32)) - start_seconds).max(0.0);
+                let at = start + Duration::from_secs_f32(offset);
+
+                // Offs first, so a note ending where the next one on the same
+                // pitch starts doesn't cut it off. Every sounding note gets
+                // its off, so muting while playing doesn't leave notes hanging.
+                // The gate moves the off between ticks, it goes out at the
+                // tick right after it. Changing the gate while playing can
+                // move it behind, so the late ones go out too.
+                let gate = self.gate;
+                let (released, sounding): (Vec<Note>, Vec<Note>) = self.sounding.iter()
+                    .partition(|n| n.release_tick(gate).ceil() as i16 <= t);
+                self.sounding = sounding;
+                for n in released {
+                    let release = self.swung_tick(n.release_tick(gate));
+                    let offset = (tempo.seconds_at(release) - start_seconds).max(0.0);
+                    cmds.push(Command::NoteOff(n, start + Duration::from_secs_f32(offset)));
+                }
+
+                for &n in self.score.notes.iter().filter(|n| n.time.0 == t) {
+                    if !self.audible(n.channel) { continue }
+
+                    // An overlapped note on the same pitch and channel is
+                    // released before it's struck again, and its own off is
+                    // skipped later, so it can't end the new note early
+                    let same_voice = |s: &Note| s.channel == n.channel && s.pitch == n.pitch;
+                    if let Some(i) = self.sounding.iter().position(same_voice) {
+                        cmds.push(Command::NoteOff(self.sounding[i], at));
+                        self.sounding.swap_remove(i);
+                    }
+
+                    cmds.push(Command::NoteOn(n, at));
+                    self.sounding.push(n)
+                }
+            }
+
+            let playback = Playback {
+                start, start_seconds, pos, count_in,
+                last_tick: last_tick.max(tick),
+            };
+            let recording = matches!(self.state, State::Recording(..));
+            self.state = match std::mem::replace(&mut self.state, State::Idle) {
+                State::Recording(_, held) => State::Recording(playback, held),
+                _ => State::Playing(playback),
+            };
+
+            // Going around ends the held notes at the end of the loop, a
+            // recording goes on recording over what it's just recorded
+            let end = self.score.song_end() as f32 / ticks;
+            if let Some((from, to)) = self.loop_range {
+                if pos >= to {
+                    self.stop_playing(cmds);
+                    self.play_pos = from;
+                    self.start_playback(recording, cmds)
+                }
+            }
+            else if pos > end + PLAYBACK_TAIL && !recording {
+                self.stop_playing(cmds);
+
+                if self.looping && self.play_pos < end {
+                    self.start_playing(cmds)
+                }
+            }
+        }
+    }
+
+    fn stop_playing(&mut self, cmds: &mut Vec<Command>) {
+        // The host may treat Stop as a transport stop only, so release
+        // the sounding notes explicitly
+        let now = Instant::now();
+        for n in self.sounding.drain(..) {
+            cmds.push(Command::NoteOff(n, now))
+        }
+        cmds.push(Command::Stop);
+        cmds.push(Command::UnsubTime);
+
+        // Notes still held when recording stops end here
+        let state = std::mem::replace(&mut self.state, State::Idle);
+        if let State::Recording(playback, held) = state {
+            let tick = (playback.pos * self.score.measure_ticks as f32).round() as i16;
+            for note in held {
+                self.place_recorded(note, tick)
+            }
+        }
+    }
+
+    pub fn play_from_start(&mut self, cmds: &mut Vec<Command>) {
+        self.play_pos = 0.0;
+        self.start_playing(cmds)
+    }
+
+    fn start_playing(&mut self, cmds: &mut Vec<Command>) {
+        self.start_playback(false, cmds)
+    }
+
+    /// Starts playing from the play position, and with `record` also putting
+    /// the notes played on the input into the score
+    fn start_playback(&mut self, record: bool, cmds: &mut Vec<Command>) {
+        let ticks = self.score.measure_ticks as f32;
+
+        // The first tick to play is the one at or after the play position, so
+        // a note starting exactly there (e.g. at tick 0 when playing from the
+        // start) fires once, with the first time update.
+        let playback = Playback {
+            start: Instant::now(),
+            start_seconds: self.score.seconds_at(self.play_pos * ticks),
+            pos: self.play_pos,
+            last_tick: (self.play_pos * ticks).ceil() as i16 - 1,
+            count_in: 0.0,
+        };
+        self.state =
+            if record { State::Recording(playback, vec![]) }
+            else { State::Playing(playback) };
+
+        cmds.push(Command::SubTime)
+    }
+
+    /// Starts playing or recording like `start_playback`, after the count-in
+    /// clicks if there are any
+    fn start_with_count_in(&mut self, record: bool, cmds: &mut Vec<Command>) {
+        self.start_playback(record, cmds);
+        if self.count_in_measures == 0 { return }
+
+        // The clicks keep the tempo and the meter of the measure the song starts in
+        let ticks = self.score.measure_ticks as f32;
+        let meter = &self.score.meter;
+        let measure = meter.measure_at(self.play_pos);
+        let tick = self.play_pos * ticks;
+        let measure_len = meter.measure_len(measure) * ticks;
+        let measure_seconds = self.score.seconds_at(tick + measure_len) - self.score.seconds_at(tick);
+        let beats = meter.beats_at(measure) as u32;
+        let beat = Duration::from_secs_f32(measure_seconds / beats as f32);
+
+        let now = Instant::now();
+        for i in 0..self.count_in_measures as u32 * beats {
+            let (pitch, velocity) =
+                if i % beats == 0 { (CLICK_PITCH + 31, 127) }
+                else { (CLICK_PITCH, DEFAULT_VELOCITY) };
+            let click = self.preview_note(Note {
+                channel: self.active_channel,
+                time: (0, 0),
+                pitch, velocity,
+            });
+
+            cmds.push(Command::NoteOn(click, now + beat * i));
+            cmds.push(Command::NoteOff(click, now + beat * i + CLICK_TIME));
+        }
+
+        let count_in = beat * (self.count_in_measures as u32 * beats);
+        match self.state {
+            State::Playing(ref mut playback) | State::Recording(ref mut playback, _) => {
+                playback.start = now + count_in;
+                playback.count_in = duration_seconds(count_in);
+            },
+            _ => (),
+        }
+    }
+
+    /// The tick of the song at a moment during playback
+    fn tick_at_moment(&self, playback: &Playback, at: Instant) -> f32 {
+        let elapsed = at.saturating_duration_since(playback.start);
+
+        self.score.tick_at(playback.start_seconds + duration_seconds(elapsed))
+    }
+
+    /// A note played on the input, sounded and recorded when recording
+    fn on_input_note_on(&mut self, pitch: i16, velocity: u8, at: Instant, cmds: &mut Vec<Command>) {
+        let note = Note {
+            channel: self.active_channel,
+            time: (0, 0),
+            pitch, velocity,
+        };
+        let monitored = self.preview_note(note);
+        self.monitored.retain(|n| n.pitch != pitch);
+        self.monitored.push(monitored);
+        cmds.push(Command::NoteOn(monitored, at));
+
+        let tick = match self.state {
+            State::Recording(ref playback, _) => self.tick_at_moment(playback, at).round() as i16,
+            _ => return,
+        };
+        if let State::Recording(_, ref mut held) = self.state {
+            held.retain(|n| n.pitch != pitch);
+            held.push(Note { time: (tick, tick), ..note })
+        }
+    }
+
+    fn on_input_note_off(&mut self, pitch: i16, at: Instant, cmds: &mut Vec<Command>) {
+        // The velocity and channel may differ from the defaults, the output
+        // only releases the exact note it was given
+        if let Some(i) = self.monitored.iter().position(|n| n.pitch == pitch) {
+            cmds.push(Command::NoteOff(self.monitored.swap_remove(i), at))
+        }
+
+        let (tick, held) = match self.state {
+            State::Recording(ref playback, ref held) => (self.tick_at_moment(playback, at).round() as i16, held),
+            _ => return,
+        };
+        if let Some(i) = held.iter().position(|n| n.pitch == pitch) {
+            let note = held[i];
+            if let State::Recording(_, ref mut held) = self.state {
+                held.swap_remove(i);
+            }
+
+            self.place_recorded(note, tick)
+        }
+    }
+
+    /// Puts a recorded note ending at `end` into the score, on the grid if
+    /// recordings are quantized
+    fn place_recorded(&mut self, note: Note, end: i16) {
+        let ticks = self.score.measure_ticks as f32;
+        let (start, end) =
+            if self.quantize_recording {
+                let snap = |tick: i16| {
+                    let pos = tick as f32 / ticks;
+                    let pos =
+                        if self.grid.subdivision > 1 { self.grid.nearest_subdivision(&self.score.meter, pos) }
+                        else { self.nearest_beat(pos) };
+
+                    (pos * ticks).round() as i16
+                };
+                (snap(note.time.0), snap(end))
+            }
+            else { (note.time.0, end) };
+
+        self.place(Note { time: (start, end.max(start + 1)), ..note })
+    }
+
+    fn on_key_press(&mut self, input: glutin::KeyboardInput, cmds: &mut Vec<Command>) {
+        // The property panels take all the keys while they're open
+        if let State::EditingNote(_) | State::EditingRepeat(_) = self.state {
+            return self.on_edit_key(input)
+        }
+        if let State::AskingRecovery = self.state {
+            return self.on_recovery_key(input, cmds)
+        }
+
+        let typing_degree =
+            if self.musical_typing && !input.modifiers.ctrl && !input.modifiers.alt {
+                self.keymap.typing_degree(&input)
+            }
+            else { None };
+        if let Some(degree) = typing_degree {
+            match self.state {
+                State::Idle | State::NotesSelected(_) => {
+                    // Shift keeps the play position, to type chords
+                    return self.type_note(degree, !input.modifiers.shift, cmds)
+                },
+                _ => (),
+            }
+        }
+
+        // Of the actions bound to the key, the first one that applies to the
+        // current state is taken
+        for action in self.keymap.actions(&input) {
+            if self.on_action(action, cmds) { break }
+        }
+    }
+
+    fn on_edit_key(&mut self, input: glutin::KeyboardInput) {
+        use glutin::VirtualKeyCode as Key;
+
+        let (fields, active) = match self.state {
+            State::EditingNote(ref mut edit) => (&mut edit.fields, &mut edit.active),
+            State::EditingRepeat(ref mut edit) => (&mut edit.fields, &mut edit.active),
+            _ => return,
+        };
+        let count = fields.len();
+
+        match input.virtual_keycode {
+            Some(Key::Return) | Some(Key::NumpadEnter) => self.apply_edit(),
+            Some(Key::Escape) => {
+                self.state = match std::mem::replace(&mut self.state, State::Idle) {
+                    State::EditingNote(edit) => State::NotesSelected(vec![edit.note]),
+                    State::EditingRepeat(edit) => State::NotesSelected(edit.notes),
+                    state => state,
+                }
+            },
+            Some(Key::Tab) | Some(Key::Down) => {
+                if input.modifiers.shift { *active = (*active + count - 1) % count }
+                else { *active = (*active + 1) % count }
+            },
+            Some(Key::Up) => {
+                *active = (*active + count - 1) % count
+            },
+            Some(Key::Back) => {
+                fields[*active].pop();
+            },
+            _ => (),
+        }
+    }
+
+    /// Y or Return recovers the autosaved score, N or Escape keeps the loaded one
+    fn on_recovery_key(&mut self, input: glutin::KeyboardInput, cmds: &mut Vec<Command>) {
+        use glutin::VirtualKeyCode as Key;
+
+        match input.virtual_keycode {
+            Some(Key::Y) | Some(Key::Return) | Some(Key::NumpadEnter) => {
+                cmds.push(Command::Recover);
+                self.state = State::Idle
+            },
+            Some(Key::N) | Some(Key::Escape) => {
+                self.state = State::Idle
+            },
+            _ => (),
+        }
+    }
+
+    /// The note with the values typed into the property panel, if they're
+    /// valid and don't put it on a locked channel
+    fn edited_note(&self, edit: &NoteEdit) -> Option<Note> {
+        edit.parse().filter(|n| !self.is_locked(n))
+    }
+
+    /// Applies the values typed into a property panel, invalid values keep it open
+    fn apply_edit(&mut self) {
+        match self.state {
+            State::EditingNote(ref edit) => {
+                if let Some(note) = self.edited_note(edit) {
+                    let original = edit.note;
+                    self.score.notes.retain(|&n| n != original);
+                    self.score.notes.push(note);
+                    self.score_changes += 1;
+
+                    self.select(vec![note])
+                }
+            },
+            State::EditingRepeat(ref edit) => {
+                if let Some((count, spacing)) = edit.parse() {
+                    self.state = State::NotesSelected(edit.notes.clone());
+                    self.repeat_selection(count, spacing)
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn on_char(&mut self, c: char) {
+        let (fields, active) = match self.state {
+            State::EditingNote(ref mut edit) => (&mut edit.fields, edit.active),
+            State::EditingRepeat(ref mut edit) => (&mut edit.fields, edit.active),
+            _ => return,
+        };
+
+        if c.is_ascii_digit() || c == '-' {
+            fields[active].push(c)
+        }
+    }
+
+    /// Returns false if the action doesn't apply to the current state
+    fn on_action(&mut self, action: Action, cmds: &mut Vec<Command>) -> bool {
+        match (action, &self.state) {
+            (Action::ArrowTool, _) => {
+                self.tool = Tool::Arrow;
+            },
+            (Action::PencilTool, _) => {
+                self.tool = Tool::Pencil;
+            },
+            (Action::EraserTool, _) => {
+                self.tool = Tool::Eraser;
+            },
+            (Action::LineTool, _) => {
+                self.tool = Tool::Line;
+            },
+            (Action::StepTool, _) => {
+                self.tool = Tool::Step;
+            },
+            (Action::PlayStop, _) | (Action::PlayFromStart, _) | (Action::Record, _)
+            if self.is_playing() => {
+                self.stop_playing(cmds)
+            },
+            (Action::Record, _) => {
+                self.start_with_count_in(true, cmds)
+            },
+            (Action::PlayFromStart, _) => {
+                self.play_pos = 0.0;
+                self.start_with_count_in(false, cmds)
+            },
+            (Action::PlayStop, _) => {
+                self.start_with_count_in(false, cmds)
+            },
+            (Action::TogglePreview, _) => {
+                self.preview = !self.preview
+            },
+            (Action::ToggleDrawReplaces, _) => {
+                self.draw_replaces = !self.draw_replaces
+            },
+            (Action::BeatBack, State::Idle) => {
+                let pos = self.step_beat(self.play_pos, -1);
+                self.seek(pos, cmds)
+            },
+            (Action::BeatForward, State::Idle) => {
+                let pos = self.step_beat(self.play_pos, 1);
+                self.seek(pos, cmds)
+            },
+            (Action::GridLineBack, State::Idle) => {
+                let pos = self.step_grid(self.play_pos, -1);
+                self.seek(pos, cmds)
+            },
+            (Action::GridLineForward, State::Idle) => {
+                let pos = self.step_grid(self.play_pos, 1);
+                self.seek(pos, cmds)
+            },
+            (Action::MeasureBack, State::Idle) => {
+                let meter = &self.score.meter;
+                let pos = meter.measure_start(meter.measure_at(self.play_pos - 1e-3));
+                self.seek(pos, cmds)
+            },
+            (Action::MeasureForward, State::Idle) => {
+                let meter = &self.score.meter;
+                let pos = meter.measure_start(meter.measure_at(self.play_pos + 1e-3) + 1);
+                self.seek(pos, cmds)
+            },
+            (Action::GoToStart, State::Idle) => {
+                self.seek(0.0, cmds)
+            },
+            (Action::GoToEnd, State::Idle) => {
+                let end = self.score.song_end();
+                self.seek(end as f32 / self.score.measure_ticks as f32, cmds)
+            },
+            (Action::ToggleSelectMode, _) => {
+                self.select_mode = match self.select_mode {
+                    SelectMode::Intersect => SelectMode::Contain,
+                    SelectMode::Contain => SelectMode::Intersect,
+                }
+            },
+            (Action::ToggleLoop, _) => {
+                self.looping = !self.looping
+            },
+            (Action::ToggleSnap, _) => {
+                self.snap = !self.snap
+            },
+            (Action::CycleMeter, _) => {
+                // 2 to 7 beats in the measure under the play position
+                let measure = self.score.meter.measure_at(self.play_pos);
+                let beats = self.score.meter.beats_at(measure) % 7 + 1;
+                self.score.meter.set_beats(measure, beats.max(2));
+                self.score_changes += 1;
+            },
+            (Action::ToggleRounded, _) => {
+                self.rounded_notes = !self.rounded_notes
+            },
+            (Action::ToggleTheme, _) => {
+                self.grid.style = self.grid.style.inverse()
+            },
+            (Action::FitPitch, _) => {
+                self.fit_pitch_range()
+            },
+            (Action::Panic, _) => {
+                self.sounding.clear();
+                cmds.push(Command::AllNotesOff)
+            },
+            (Action::SelectAtPlayhead, State::Idle)
+            | (Action::SelectAtPlayhead, State::NotesSelected(_)) => {
+                let tick = (self.play_pos * self.score.measure_ticks as f32).floor() as i16;
+                let mut notes = self.score.notes_at_time(tick);
+                notes.retain(|n| !self.is_locked(n));
+
+                if !notes.is_empty() {
+                    self.state = State::NotesSelected(notes)
+                }
+            },
+            (Action::Delete, State::NotesSelected(selected)) => {
+                let locked = &self.locked;
+                self.score.notes.retain(|n| !selected.contains(n) || locked.contains(&n.channel));
+                self.score_changes += 1;
+
+                self.state = State::Idle;
+            },
+            (Action::QuantizeLengths, State::NotesSelected(_)) => {
+                // To eighth notes
+                let eighth = self.score.measure_ticks as i16 / 8;
+                self.quantize_lengths(eighth)
+            },
+            (Action::RepeatSelection, State::NotesSelected(selected)) => {
+                // Right after the selection, so repeated presses keep extending it
+                let (start, end) = span(selected);
+                if let Some(length) = end.checked_sub(start) {
+                    self.repeat_selection(1, length)
+                }
+            },
+            (Action::RepeatSelectionTimes, State::NotesSelected(selected)) => {
+                self.state = State::EditingRepeat(RepeatEdit::new(selected.clone()))
+            },
+            (Action::Copy, State::NotesSelected(selected)) => {
+                self.clipboard = selected.clone();
+                cmds.push(Command::Copy)
+            },
+            (Action::Paste, State::Idle) | (Action::Paste, State::NotesSelected(_)) => {
+                cmds.push(Command::Paste)
+            },
+            (Action::Retrograde, State::NotesSelected(_)) => {
+                self.retrograde_selection()
+            },
+            (Action::MirrorInMeasure, State::NotesSelected(_)) => {
+                self.mirror_in_measure_selection()
+            },
+            (Action::Invert, State::NotesSelected(selected)) => {
+                let mean = selected.iter().map(|n| n.pitch as f32).sum::<f32>() / selected.len() as f32;
+                self.invert_selection(mean)
+            },
+            (Action::CycleScale, _) => {
+                self.grid.next_scale()
+            },
+            (Action::ToggleKey, _) => {
+                self.grid.key =
+                    if self.grid.key.is_some() { None }
+                    else { Some(self.key.clone()) };
+            },
+            (Action::CycleSnapResolution, _) => {
+                // Values off the score's ticks are skipped, free always fits
+                let mut resolution = self.snap_resolution.next();
+                while !resolution.fits(self.score.measure_ticks) {
+                    resolution = resolution.next()
+                }
+                self.snap_resolution = resolution
+            },
+            (Action::ToggleHeatmap, _) => {
+                self.view_mode =
+                    if self.view_mode == ui::ViewMode::Heatmap { ui::ViewMode::Notes }
+                    else { ui::ViewMode::Heatmap };
+            },
+            (Action::ToggleOctaveBands, _) => {
+                self.grid.octave_bands = !self.grid.octave_bands
+            },
+            (Action::ToggleSwing, _) => {
+                self.swinging = !self.swinging
+            },
+            (Action::GateDown, _) => {
+                self.set_gate(((self.gate - 0.1) * 10.0).round() / 10.0)
+            },
+            (Action::GateUp, _) => {
+                self.set_gate(((self.gate + 0.1) * 10.0).round() / 10.0)
+            },
+            (Action::ToggleMusicalTyping, _) => {
+                self.musical_typing = !self.musical_typing
+            },
+            // Kept within the pitches the view can be dragged to
+            (Action::OctaveUp, _) => {
+                if self.grid.view.1.y + 31.0 <= MAX_PITCH as f32 {
+                    self.grid.view.0.y += 31.0;
+                    self.grid.view.1.y += 31.0;
+                }
+            },
+            (Action::OctaveDown, _) => {
+                if self.grid.view.0.y - 31.0 >= 0.0 {
+                    self.grid.view.0.y -= 31.0;
+                    self.grid.view.1.y -= 31.0;
+                }
+            },
+            (Action::ToggleEventLog, _) => {
+                self.event_log =
+                    if self.event_log.is_some() { None }
+                    else { Some(vec![]) };
+            },
+            (Action::ResetView, _) => {
+                self.grid.view = default_view()
+            },
+            (Action::ZoomIn, _) => self.key_zoom(false, KEY_ZOOM_STEP),
+            (Action::ZoomOut, _) => self.key_zoom(false, -KEY_ZOOM_STEP),
+            (Action::ZoomInPitch, _) => self.key_zoom(true, KEY_ZOOM_STEP),
+            (Action::ZoomOutPitch, _) => self.key_zoom(true, -KEY_ZOOM_STEP),
+            (Action::EditNote, State::NotesSelected(selected)) if selected.len() == 1 => {
+                self.state = State::EditingNote(NoteEdit::new(selected[0]))
+            },
+            (Action::SetChannel(channel), State::NotesSelected(_)) => {
+                self.set_channel_selection(channel)
+            },
+            (Action::ActiveChannel(channel), _) => {
+                self.active_channel = channel
+            },
+            (Action::ToggleMute, _) => {
+                let channel = self.active_channel;
+                if !self.muted.remove(&channel) {
+                    self.muted.insert(channel);
+                }
+            },
+            (Action::ToggleSolo, _) => {
+                self.solo =
+                    if self.solo == Some(self.active_channel) { None }
+                    else { Some(self.active_channel) };
+            },
+            (Action::ToggleLock, _) => {
+                let channel = self.active_channel;
+                if !self.locked.remove(&channel) {
+                    self.locked.insert(channel);
+                }
+
+                // Locked notes leave the selection
+                if let State::NotesSelected(ref mut selected) = self.state {
+                    selected.retain(|n| n.channel != channel);
+                    if selected.is_empty() {
+                        self.state = State::Idle
+                    }
+                }
+            },
+            (Action::Save, _) => {
+                cmds.push(Command::Save)
+            },
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Advances the editor by one message, pushing whatever it wants done to `cmds`
+    pub fn handle_msg(&mut self, msg: Msg, cmds: &mut Vec<Command>) {
+        let was_playing = self.is_playing();
+        let ticking = matches!(msg, Msg::Time(_));
+
+        if self.event_log.is_none() { self.update(msg, cmds) }
+        else { self.update_logged(msg, cmds) }
+
+        // Time updates only move the play bar, unless playback stopped
+        let playing = self.is_playing();
+        if !ticking || playing != was_playing {
+            self.dirty = true
+        }
+        else if playing {
+            self.play_bar_moved = true
+        }
+    }
+
+    fn update_logged(&mut self, msg: Msg, cmds: &mut Vec<Command>) {
+        // Time updates and axis motion come all the time, they'd drown the rest
+        let noisy = matches!(msg, Msg::Time(_) | Msg::WindowEvent(glutin::WindowEvent::AxisMotion { .. }));
+        let msg_text = format!("{:?}", msg);
+        let first_cmd = cmds.len();
+
+        self.update(msg, cmds);
+
+        let mut lines: Vec<String> = cmds[first_cmd..].iter()
+            .map(|c| format!("> {:?}", c))
+            .collect();
+        if !noisy {
+            lines.insert(0, msg_text)
+        }
+
+        if let Some(ref mut log) = self.event_log {
+            for line in lines {
+                eprintln!("{}", line);
+                log.push(line)
+            }
+
+            let excess = log.len().saturating_sub(EVENT_LOG_LEN);
+            log.drain(..excess);
+        }
+    }
+
+    fn update(&mut self, msg: Msg, cmds: &mut Vec<Command>) {
+        use glutin::WindowEvent::*;
+        use self::Msg::*;
+
+        // The recovery question has to be answered with the keys first
+        let clicked = matches!(msg, LeftPressed { .. } | LeftDoubleClick { .. } | RightPressed { .. });
+        if clicked && self.state == State::AskingRecovery { return }
 
         match msg {
             Msg::MouseWheel { position, modifiers, delta: (_, y) } => {
-                if modifiers.ctrl {
-                    self.scale_y(position.y, y)
+                if modifiers.shift {
+                    self.scale_both(position, y)
+                }
+                else if modifiers.ctrl {
+                    self.scale_y(position, y)
                 }
                 else {
-                    self.scale_x(position.x, y)
+                    self.scale_x(position, y)
                 }
+
+                self.grid.normalize_view()
             },
-            Msg::LeftPressed { position } => {
-                self.on_left_press(position)
+            Msg::LeftPressed { position, modifiers } => {
+                self.on_left_press(position, modifiers, cmds)
+            },
+            Msg::LeftDoubleClick { position, modifiers } => {
+                self.on_double_click(position, modifiers)
             },
             Msg::LeftReleased { .. } => {
-                self.on_left_release()
+                self.on_left_release(cmds)
+            },
+            Msg::LeftDrag { position, .. } => {
+                let view_pos = self.grid.view_position(position);
+
+                // Whether the mouse has moved far enough from a press to
+                // start a drag. Once it has, the press is forgotten, so the
+                // drag goes on when the mouse comes back close to it.
+                let threshold = self.click_threshold;
+                let moved = |press: Vector2<f32>| {
+                    let d = position - press;
+                    d.x.abs().max(d.y.abs()) > threshold
+                };
+                let latch_drag = |press: &mut Option<Vector2<f32>>| {
+                    if press.is_some_and(|p| !moved(p)) { return false }
+
+                    *press = None;
+                    true
+                };
+
+                if let State::Drawing(mut brick) = self.state {
+                    // Tiny moves keep the brick unchanged, so the release
+                    // still places a default note
+                    if !latch_drag(&mut brick.press) { return }
+
+                    let end = self.pencil_end(view_pos.x);
+                    let brick = Brick {
+                        time: (brick.time.0, end),
+                        pitch: view_pos.y,
+                        ..brick
+                    };
+
+                    self.state = State::Drawing(brick)
+                }
+
+                if let State::DrawingChord(ref bricks) = self.state {
+                    let mut last = bricks[bricks.len() - 1];
+                    if !latch_drag(&mut last.press) { return }
+
+                    // Dragging sets the end of the whole chord
+                    let end = self.pencil_end(view_pos.x);
+                    let bricks = bricks.iter()
+                        .map(|&b| Brick { time: (b.time.0, end), press: None, ..b })
+                        .collect();
+
+                    self.state = State::DrawingChord(bricks)
+                }
+
+                if let State::PointSelected(point, ref combine) = self.state {
+                    // Below the threshold the release still sets the time
+                    if !moved(point) { return }
+
+                    self.state = State::SelectFrame(point, position, combine.clone())
+                }
+
+                if let State::SelectFrame(_, ref mut end, _) = self.state {
+                    *end = position
+                }
+
+                if let State::DraggingPlayhead(_) = self.state {
+                    let pos =
+                        if self.snap { self.nearest_beat(view_pos.x) }
+                        else { view_pos.x };
+                    self.play_pos = pos.max(0.0)
+                }
+
+                let pos = self.nearest_beat(view_pos.x);
+                if let State::RulerSelect(ref mut press, _, ref mut to) = self.state {
+                    // Below the threshold the release still clears the loop
+                    if !latch_drag(press) { return }
+
+                    *to = pos
+                }
+
+                if let State::Erasing = self.state {
+                    self.erase_at(position)
+                }
+
+                if let State::DrawingLine(_, ref mut to) = self.state {
+                    *to = position
+                }
+
+                if let State::Lasso(ref mut points) = self.state {
+                    let last = points[points.len() - 1];
+                    // Skip tiny moves to keep the path short
+                    if (position.x - last.x).abs() + (position.y - last.y).abs() >= 3.0 {
+                        points.push(position)
+                    }
+                }
+
+                self.st_resize_note(position);
+                self.st_move_notes(position, cmds)
+            },
+            Msg::RightPressed { position } => {
+                self.right_press = Some(position)
+            },
+            Msg::RightReleased { position } => {
+                let threshold = self.click_threshold;
+                let press = self.right_press.take();
+                let is_click = press.is_some_and(|press| {
+                    let d = position - press;
+                    d.x.abs().max(d.y.abs()) <= threshold
+                });
+
+                if is_click {
+                    self.on_right_click(position)
+                }
+            },
+            Msg::RightDrag { vector } | Msg::MiddleDrag { vector } => {
+                let shift = -self.grid.view_vector(vector);
+
+                let v0 = self.grid.view.0 + shift;
+                let v1 = self.grid.view.1 + shift;
+
+                if
+                    (shift.y > 0.0 && v1.y <= MAX_PITCH as f32)
+                    || (shift.y < 0.0 && v0.y >= 0.0)
+                {
+                    self.grid.view.0.y = v0.y;
+                    self.grid.view.1.y = v1.y;
+                }
+
+                // A bounded score can't be panned far past either end
+                let in_bounds = self.score.length_pos().is_none_or(|length| {
+                    (shift.x > 0.0 && v1.x <= length + 1.0)
+                        || (shift.x < 0.0 && v0.x >= -1.0)
+                });
+
+                if in_bounds {
+                    self.grid.view.0.x = v0.x;
+                    self.grid.view.1.x = v1.x;
+                }
+            },
+            Msg::Time(t) => {
+                self.on_time(t, cmds)
+            }
+            Msg::InputNoteOn { pitch, velocity, at } => {
+                self.on_input_note_on(pitch, velocity, at, cmds)
+            },
+            Msg::InputNoteOff { pitch, at } => {
+                self.on_input_note_off(pitch, at, cmds)
+            },
+            WindowEvent(ReceivedCharacter(c)) => {
+                self.on_char(c)
+            },
+            WindowEvent(KeyboardInput { input, .. })
+            if input.state == glutin::ElementState::Pressed => {
+                self.on_key_press(input, cmds)
+            },
+            WindowEvent(KeyboardInput { input, .. }) => {
+                use glutin::VirtualKeyCode as Key;
+
+                match input.virtual_keycode {
+                    Some(Key::LShift) | Some(Key::RShift) => self.st_place_chord(),
+                    _ => (),
+                }
+            },
+            WindowEvent(HiDpiFactorChanged(factor)) =>
+                self.set_hidpi_factor(factor),
+            // Minimizing may resize the window to nothing
+            WindowEvent(Resized(sz)) if is_usable_size(sz) => {
+                self.grid.size = Vector2::new(sz.width as f32, sz.height as f32)
+            },
+            _ => (),
+        }
+    }
+
+    /// Draws the editor into a scene the size of the last `Resized` message
+    pub fn draw(&self, scene: &mut renderer::Scene) {
+        self.draw_all(scene);
+    }
+
+    /// Brings a scene drawn by earlier calls up to date, rebuilding it only
+    /// when something changed and just the play bar during playback. Returns
+    /// whether the scene changed.
+    pub fn draw_changes(&mut self, scene: &mut renderer::Scene) -> bool {
+        if self.dirty {
+            scene.clear();
+            self.play_bar_layer = self.draw_all(scene);
+        }
+        else if self.play_bar_moved {
+            scene.set_layer(self.play_bar_layer, self.play_bar().mesh(self.grid.size))
+        }
+        else {
+            return false
+        }
+
+        self.dirty = false;
+        self.play_bar_moved = false;
+        true
+    }
+
+    fn play_bar(&self) -> ui::PlayBar {
+        let position =
+            if let Some(playback) = self.playback() { playback.pos }
+            else { self.play_pos };
+
+        ui::PlayBar {
+            position,
+            view: self.grid.view,
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+        }
+    }
+
+    /// Draws everything, and returns the play bar's layer
+    fn draw_all(&self, scene: &mut renderer::Scene) -> usize {
+        let screen_size = self.grid.size;
+        scene.set_bg_color(self.grid.style.base3());
+        self.grid.draw(&self.score.meter, self.score.length_pos(), screen_size, scene);
+
+        let loop_range = match self.state {
+            State::RulerSelect(_, from, to) if from != to => Some((from.min(to), from.max(to))),
+            State::RulerSelect(..) => None,
+            _ => self.loop_range,
+        };
+        if let Some((from, to)) = loop_range {
+            ui::LoopRegion {
+                range: (from, to),
+                view: self.grid.view,
+                style: self.grid.style,
+            }.draw(screen_size, scene)
+        }
+
+        // Under the selection, which is still drawn note by note on top
+        if self.view_mode == ui::ViewMode::Heatmap {
+            ui::Heatmap {
+                notes: &self.score.notes,
+                view: self.grid.view,
+                measure_ticks: self.score.measure_ticks,
+                style: self.grid.style,
+            }.draw(screen_size, scene)
+        }
+
+        // The heatmap has the score's notes, only the ones being drawn are added on top
+        let mut notes =
+            if self.view_mode == ui::ViewMode::Heatmap { vec![] }
+            else { self.score.notes.clone() };
+
+        if let State::Drawing(brick) = self.state {
+            // The end is rounded to a whole tick on release
+            let end = brick.time.1.round() / self.score.measure_ticks as f32;
+
+            ui::SnapGuide {
+                position: end,
+                view: self.grid.view,
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene);
+
+            notes.push(brick.into())
+        }
+
+        if let State::DrawingChord(ref bricks) = self.state {
+            notes.extend(bricks.iter().map(|&b| Note::from(b)))
+        }
+
+        if let State::DrawingLine(from, to) = self.state {
+            self.note_view(self.line_notes(from, to), true).draw(screen_size, scene)
+        }
+
+        if let State::NotesSelected(ref framed) = self.state {
+            notes.retain(|n| !framed.contains(n));
+
+            self.note_view(framed.clone(), true).draw(screen_size, scene)
+        }
+
+        if let State::MovingNotes(ref m) = self.state {
+            notes.retain(|n| !m.notes.contains(n));
+
+            self.note_view(m.moved().unwrap_or_else(|| m.notes.clone()), true).draw(screen_size, scene)
+        }
+
+        if let State::Resizing(note, _, time) = self.state {
+            notes.retain(|&n| n != note);
+
+            self.note_view(vec![Note { time, ..note }], true).draw(screen_size, scene)
+        }
+
+        if let State::EditingNote(ref edit) = self.state {
+            notes.retain(|&n| n != edit.note);
+
+            self.note_view(vec![edit.note], true).draw(screen_size, scene);
+
+            ui::PropertyPanel {
+                fields: NoteEdit::LABELS.iter()
+                    .map(|l| l.to_string())
+                    .zip(edit.fields.iter().cloned())
+                    .collect(),
+                active: edit.active,
+                valid: self.edited_note(edit).is_some(),
+                position: Vector2::new(10.0, screen_size[1] - ui::Ruler::HEIGHT - 10.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        if let State::EditingRepeat(ref edit) = self.state {
+            self.note_view(edit.notes.clone(), true).draw(screen_size, scene);
+            self.note_view(edit.copies(), false).draw(screen_size, scene);
+
+            ui::PropertyPanel {
+                fields: RepeatEdit::LABELS.iter()
+                    .map(|l| l.to_string())
+                    .zip(edit.fields.iter().cloned())
+                    .collect(),
+                active: edit.active,
+                valid: edit.parse().is_some(),
+                position: Vector2::new(10.0, screen_size[1] - ui::Ruler::HEIGHT - 10.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        if let State::ContextMenu(_, _, ref targets) = self.state {
+            notes.retain(|n| !targets.contains(n));
+
+            self.note_view(targets.clone(), true).draw(screen_size, scene)
+        }
+
+        if let State::SelectFrame(v0, v1, ref combine) = self.state {
+            let previous = combine.previous();
+            notes.retain(|n| !previous.contains(n));
+
+            self.note_view(previous.to_vec(), true).draw(screen_size, scene);
+
+            let (from, to) = normalize_square(v0, v1);
+            ui::Frame {
+                from, to,
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        if let State::Lasso(ref points) = self.state {
+            ui::Lasso {
+                points: points.clone(),
+                style: self.grid.style,
+            }.draw(screen_size, scene)
+        }
+
+        self.note_view(notes, false).draw(screen_size, scene);
+
+        ui::Ruler {
+            view: self.grid.view,
+            meter: self.score.meter.clone(),
+            tempo: self.score.tempo.iter()
+                .map(|&(tick, bpm)| (tick as f32 / self.score.measure_ticks as f32, bpm))
+                .collect(),
+            loop_range,
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+        }.draw(screen_size, scene);
+
+        let play_bar_layer = scene.add_layer(self.play_bar().mesh(screen_size));
+
+        if self.active_channel != 0 || self.solo.is_some() || !self.muted.is_empty() || !self.locked.is_empty() {
+            let mut muted: Vec<u16> = self.muted.iter().cloned().collect();
+            muted.sort();
+            let mut lines = vec![format!("channel {}", self.active_channel + 1)];
+            if let Some(solo) = self.solo {
+                lines.push(format!("solo {}", solo + 1))
+            }
+            if !muted.is_empty() {
+                let muted: Vec<String> = muted.iter().map(|c| (c + 1).to_string()).collect();
+                lines.push(format!("muted {}", muted.join(",")))
+            }
+            if !self.locked.is_empty() {
+                let mut locked: Vec<u16> = self.locked.iter().cloned().collect();
+                locked.sort();
+                let locked: Vec<String> = locked.iter().map(|c| (c + 1).to_string()).collect();
+                lines.push(format!("locked {}", locked.join(",")))
+            }
+
+            // Channel status in the bottom left corner
+            ui::InfoBox {
+                lines,
+                position: Vector2::new(10.0, 100.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        // The tool, right above the minimap
+        let mut lines = vec![match self.snap_resolution {
+            SnapResolution::Free => self.tool.name().to_string(),
+            resolution => format!("{} {}", self.tool.name(), resolution.label()),
+        }];
+        if self.musical_typing {
+            lines.push("typing".to_string())
+        }
+        if let State::Recording(..) = self.state {
+            lines.push("recording".to_string())
+        }
+        if self.view_mode == ui::ViewMode::Heatmap {
+            lines.push("heatmap".to_string())
+        }
+        if self.gate != 1.0 {
+            lines.push(format!("gate {:.1}", self.gate))
+        }
+        if self.swinging && self.swing > 0.0 {
+            lines.push(format!("swing {:.0}%", self.swing * 100.0))
+        }
+        let tool = ui::InfoBox {
+            lines,
+            position: Vector2::new(0.0, 0.0),
+            style: self.grid.style,
+            pixel_ratio: self.grid.pixel_ratio(),
+        };
+        // Stacked on the minimap
+        let tool_size = tool.size();
+        let minimap_top = self.minimap_rect().1.y;
+        ui::InfoBox {
+            position: Vector2::new(screen_size[0] - 10.0 - tool_size.x, minimap_top + 10.0 + tool_size.y),
+            ..tool
+        }.draw(screen_size, scene);
+
+        // Where the playhead will land when the click completes
+        if let State::PointSelected(point, _) = self.state {
+            ui::InfoBox {
+                lines: vec![self.time_label(self.click_time(point))],
+                position: point + Vector2::new(12.0, 30.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        let selection = match self.state {
+            State::NotesSelected(ref notes) => Some(notes.clone()),
+            State::MovingNotes(ref m) => Some(m.moved().unwrap_or_else(|| m.notes.clone())),
+            _ => None,
+        };
+        if let Some(notes) = selection {
+            ui::InfoBox {
+                lines: self.selection_info(&notes),
+                position: Vector2::new(10.0, screen_size[1] - ui::Ruler::HEIGHT - 10.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        self.minimap().draw(screen_size, scene);
+
+        if let State::ContextMenu(corner, ref items, _) = self.state {
+            self.menu(corner, items).draw(screen_size, scene)
+        }
+
+        if let State::AskingRecovery = self.state {
+            let mut question = ui::InfoBox {
+                lines: vec![
+                    "Unsaved changes from an earlier session were autosaved.".to_string(),
+                    "Y: recover them, N: keep the file as it was saved".to_string(),
+                ],
+                position: Vector2::new(0.0, 0.0),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            };
+            question.position = (screen_size - question.size()) / 2.0;
+            question.draw(screen_size, scene)
+        }
+
+        if let Some(ref log) = self.event_log {
+            ui::EventLog {
+                lines: log.clone(),
+                style: self.grid.style,
+                pixel_ratio: self.grid.pixel_ratio(),
+            }.draw(screen_size, scene)
+        }
+
+        play_bar_layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(time: (i16, i16), pitch: i16) -> Note {
+        Note { channel: 0, velocity: DEFAULT_VELOCITY, time, pitch }
+    }
+
+    fn score(notes: Vec<Note>) -> Score {
+        Score { notes, ..Score::new() }
+    }
+
+    fn note_ons(cmds: &[Command]) -> Vec<Note> {
+        cmds.iter()
+            .filter_map(|c| match *c { Command::NoteOn(n, _) => Some(n), _ => None })
+            .collect()
+    }
+
+    #[test]
+    fn note_on_start_tick_fires_once() {
+        for &play_pos in &[0.0, 1.0] {
+            let tick = (play_pos * 16.0) as i16;
+            let mut pianoroll = PianoRoll::new();
+            pianoroll.load_score(score(vec![note((tick, tick + 4), 124)]));
+            pianoroll.play_pos = play_pos;
+
+            let mut cmds = vec![];
+            pianoroll.start_playing(&mut cmds);
+            for ms in (0..200).step_by(10) {
+                pianoroll.on_time(Duration::from_millis(ms), &mut cmds)
+            }
+
+            assert_eq!(note_ons(&cmds), vec![note((tick, tick + 4), 124)], "from {}", play_pos);
+        }
+    }
+
+    fn placed(existing: (i16, i16), new: (i16, i16)) -> Vec<(i16, i16)> {
+        let mut score = score(vec![note(existing, 124)]);
+        score.place(note(new, 124), true);
+
+        let mut times: Vec<(i16, i16)> = score.notes.iter().map(|n| n.time).collect();
+        times.sort();
+        times
+    }
+
+    #[test]
+    fn placing_trims_partial_overlap() {
+        assert_eq!(placed((0, 8), (4, 12)), vec![(0, 4), (4, 12)]);
+        assert_eq!(placed((4, 12), (0, 8)), vec![(0, 8), (8, 12)]);
+    }
+
+    #[test]
+    fn placing_splits_containing_note() {
+        assert_eq!(placed((0, 16), (4, 8)), vec![(0, 4), (4, 8), (8, 16)]);
+    }
+
+    #[test]
+    fn placing_removes_contained_and_identical_notes() {
+        assert_eq!(placed((4, 8), (0, 16)), vec![(0, 16)]);
+        assert_eq!(placed((4, 8), (4, 8)), vec![(4, 8)]);
+    }
+
+    #[test]
+    fn placing_keeps_other_pitches_and_channels() {
+        let mut score = score(vec![note((0, 8), 125), Note { channel: 1, ..note((0, 8), 124) }]);
+        score.place(note((0, 8), 124), true);
+
+        assert_eq!(score.notes.len(), 3);
+    }
+
+    #[test]
+    fn repeat_edit_spaces_copies() {
+        let mut edit = RepeatEdit::new(vec![note((0, 4), 124), note((4, 8), 125)]);
+        assert_eq!(edit.parse(), Some((1, 8)));
+
+        edit.fields = vec!["3".into(), "16".into()];
+        let starts: Vec<i16> = edit.copies().iter().map(|n| n.time.0).collect();
+        assert_eq!(starts, vec![16, 20, 32, 36, 48, 52]);
+
+        // The last copy would end past the last tick
+        edit.fields = vec!["5000".into(), "16".into()];
+        assert_eq!(edit.parse(), None);
+    }
+
+    #[test]
+    fn repeating_stays_in_the_ticks_and_the_note_limit() {
+        let mut pianoroll = with_selection(vec![note((0, 4), 124)]);
+        pianoroll.score.notes.push(note((4, 12), 124));
+
+        // The copy cuts the note it lands on
+        pianoroll.on_action(Action::RepeatSelection, &mut vec![]);
+        let mut notes = pianoroll.score.notes.clone();
+        notes.sort_by_key(|n| n.time);
+        assert_eq!(notes, vec![note((0, 4), 124), note((4, 8), 124), note((8, 12), 124)]);
+        assert_eq!(selection(&pianoroll), vec![note((4, 8), 124)]);
+
+        pianoroll.set_max_notes(3);
+        pianoroll.on_action(Action::RepeatSelection, &mut vec![]);
+        assert_eq!(pianoroll.score.notes.len(), 3);
+
+        // Up to the last tick, and no further
+        pianoroll.set_max_notes(DEFAULT_MAX_NOTES);
+        pianoroll.score.notes.push(note((i16::MAX - 8, i16::MAX - 4), 130));
+        pianoroll.state = State::NotesSelected(vec![note((i16::MAX - 8, i16::MAX - 4), 130)]);
+        pianoroll.on_action(Action::RepeatSelection, &mut vec![]);
+        assert_eq!(selection(&pianoroll), vec![note((i16::MAX - 4, i16::MAX), 130)]);
+        pianoroll.on_action(Action::RepeatSelection, &mut vec![]);
+        assert_eq!(selection(&pianoroll), vec![note((i16::MAX - 4, i16::MAX), 130)]);
+        assert_eq!(pianoroll.score.notes.len(), 5);
+    }
+
+    fn with_selection(notes: Vec<Note>) -> PianoRoll {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(score(notes.clone()));
+        pianoroll.state = State::NotesSelected(notes);
+        pianoroll
+    }
+
+    fn selection(pianoroll: &PianoRoll) -> Vec<Note> {
+        match pianoroll.state {
+            State::NotesSelected(ref notes) => {
+                let mut notes = notes.clone();
+                notes.sort_by_key(|n| (n.time, n.pitch));
+                notes
             },
-            Msg::LeftDrag { position, .. } => {
-                let view_pos = self.grid.view_position(position);
+            ref state => panic!("nothing selected in {:?}", state),
+        }
+    }
 
-                if let State::Drawing(brick) = self.state {
-                    let brick = Brick {
-                        time: (brick.time.0, view_pos.x * self.score.measure_ticks as f32),
-                        pitch: view_pos.y,
-                    };
+    fn motif() -> Vec<Note> {
+        vec![note((2, 4), 124), note((4, 10), 130), note((10, 11), 127)]
+    }
 
-                    self.state = State::Drawing(brick)
-                }
+    #[test]
+    fn retrograde_mirrors_about_center() {
+        let mut pianoroll = with_selection(motif());
+        pianoroll.retrograde_selection();
 
-                if let State::PointSelected(point) = self.state {
-                    self.state = State::SelectFrame(point, position)
-                }
+        // Each note's distance from the start becomes its distance from the end
+        assert_eq!(selection(&pianoroll), vec![note((2, 3), 127), note((3, 9), 130), note((9, 11), 124)]);
+        assert_eq!(pianoroll.score.notes.len(), 3);
+    }
 
-                if let State::SelectFrame(start, _) = self.state {
-                    self.state = State::SelectFrame(start, position)
-                }
+    #[test]
+    fn retrograde_twice_is_identity() {
+        let mut pianoroll = with_selection(motif());
+        pianoroll.retrograde_selection();
+        pianoroll.retrograde_selection();
+
+        assert_eq!(selection(&pianoroll), motif());
+    }
+
+    #[test]
+    fn invert_reflects_about_pivot() {
+        let mut pianoroll = with_selection(motif());
+        pianoroll.invert_selection(127.0);
+
+        let pitches: Vec<i16> = selection(&pianoroll).iter().map(|n| n.pitch).collect();
+        assert_eq!(pitches, vec![130, 124, 127]);
+    }
+
+    #[test]
+    fn invert_twice_is_identity() {
+        for &pivot in &[127.0, 127.5, 127.25, 127.75] {
+            let mut pianoroll = with_selection(motif());
+            pianoroll.invert_selection(pivot);
+            pianoroll.invert_selection(pivot);
+
+            assert_eq!(selection(&pianoroll), motif(), "about {}", pivot);
+        }
+    }
+
+    #[test]
+    fn invert_keeps_pitches_in_range() {
+        let low = vec![note((0, 4), 0), note((4, 8), 10)];
+        let high = vec![note((0, 4), MAX_PITCH - 10), note((4, 8), MAX_PITCH)];
+
+        for &(notes, pivot) in &[(&low, 2.0), (&high, MAX_PITCH as f32 - 2.0)] {
+            let mut pianoroll = with_selection(notes.to_vec());
+            pianoroll.invert_selection(pivot);
+            assert_eq!(selection(&pianoroll), *notes, "about {}", pivot);
+        }
+
+        // Right at the edges is fine
+        let mut pianoroll = with_selection(low.clone());
+        pianoroll.invert_selection(5.0);
+        assert_eq!(selection(&pianoroll), vec![note((0, 4), 10), note((4, 8), 0)]);
+    }
+
+    fn resize(pianoroll: &mut PianoRoll, width: f64, height: f64) {
+        let size = glutin::dpi::LogicalSize::new(width, height);
+        pianoroll.handle_msg(Msg::WindowEvent(glutin::WindowEvent::Resized(size)), &mut vec![]);
+    }
+
+    #[test]
+    fn wheel_burst_keeps_view_well_formed() {
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+
+        let shift = ModifiersState { shift: true, ..Default::default() };
+        let ctrl = ModifiersState { ctrl: true, ..Default::default() };
+        let positions = [(0.0, 0.0), (799.0, 599.0), (-50.0, 300.0), (400.0, 10_000.0)];
+
+        for (i, &(x, y)) in positions.iter().cycle().take(400).enumerate() {
+            let modifiers = [Default::default(), shift, ctrl][i % 3];
+            let delta = if i % 7 < 4 { 1000.0 } else { -250.0 * (i % 5) as f32 };
+            pianoroll.handle_msg(Msg::MouseWheel {
+                position: Vector2::new(x, y),
+                delta: (0.0, delta),
+                modifiers,
+            }, &mut vec![]);
+
+            let (v0, v1) = pianoroll.grid.view;
+            let span = v1 - v0;
+            assert!(v0.x < v1.x && v0.y < v1.y, "inverted view {:?} after {} events", (v0, v1), i);
+            assert!(span.x >= pianoroll.min_view_span.x * 0.999 && span.x <= pianoroll.max_view_span.x * 1.001);
+            assert!(span.y >= pianoroll.min_view_span.y * 0.999 && span.y <= pianoroll.max_view_span.y * 1.001);
+        }
+    }
+
+    #[test]
+    fn zoom_keeps_the_cursor_in_place() {
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+
+        let shift = ModifiersState { shift: true, ..Default::default() };
+        let ctrl = ModifiersState { ctrl: true, ..Default::default() };
+        let cursor = Vector2::new(310.0, 220.0);
+
+        for &modifiers in &[Default::default(), shift, ctrl] {
+            for &delta in &[120.0, -120.0, -40.0] {
+                let before = pianoroll.grid.view_position(cursor);
+                pianoroll.handle_msg(Msg::MouseWheel {
+                    position: cursor,
+                    delta: (0.0, delta),
+                    modifiers,
+                }, &mut vec![]);
+                let after = pianoroll.grid.view_position(cursor);
+
+                assert!((after.x - before.x).abs() < 1e-3 && (after.y - before.y).abs() < 1e-3, "{:?} moved to {:?}", before, after);
+            }
+        }
+    }
+
+    /// Plays a score from the start with the clock read at the given
+    /// moments, and returns the note ons as their offsets from the start
+    fn played_with_clock(notes: Vec<Note>, clock: &[u64]) -> (Vec<(Note, Duration)>, f32) {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(score(notes));
+
+        let mut cmds = vec![];
+        pianoroll.play_from_start(&mut cmds);
+        let start = pianoroll.playback().unwrap().start;
+        for &ms in clock {
+            pianoroll.on_time(Duration::from_millis(ms), &mut cmds)
+        }
+
+        let ons = cmds.iter()
+            .filter_map(|c| match *c { Command::NoteOn(n, at) => Some((n, at - start)), _ => None })
+            .collect();
+        (ons, pianoroll.playback().unwrap().pos)
+    }
+
+    #[test]
+    fn playback_follows_the_clock_without_drift() {
+        let notes: Vec<Note> = (0..8).map(|i| note((2 * i, 2 * i + 1), 124)).collect();
+        let steady: Vec<u64> = (0..=90).map(|i| i * 10).collect();
+        // Late, early and missed updates
+        let jittery = [0, 3, 4, 41, 42, 130, 131, 380, 385, 390, 610, 900];
+
+        let (steady_ons, steady_pos) = played_with_clock(notes.clone(), &steady);
+        let (jittery_ons, jittery_pos) = played_with_clock(notes.clone(), &jittery);
+
+        // One measure a second, 16 ticks to the measure
+        let expected: Vec<(Note, Duration)> = notes.iter()
+            .map(|&n| (n, Duration::from_secs_f32(n.time.0 as f32 / 16.0)))
+            .collect();
+        assert_eq!(steady_ons, expected);
+        assert_eq!(jittery_ons, expected);
+        assert!((steady_pos - 0.9).abs() < 1e-4, "at {}", steady_pos);
+        assert!((jittery_pos - 0.9).abs() < 1e-4, "at {}", jittery_pos);
+    }
+
+    fn drag(pianoroll: &mut PianoRoll, x: f32, y: f32) {
+        let position = Vector2::new(x, y);
+        pianoroll.handle_msg(Msg::LeftDrag { position, vector: Vector2::new(0.0, 0.0) }, &mut vec![]);
+    }
+
+    #[test]
+    fn drag_stays_latched_near_the_press() {
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+        pianoroll.tool = Tool::Pencil;
+
+        let press = Vector2::new(400.0, 300.0);
+        pianoroll.handle_msg(Msg::LeftPressed { position: press, modifiers: Default::default() }, &mut vec![]);
+        drag(&mut pianoroll, 401.0, 300.0);
+        let unmoved = match pianoroll.state {
+            State::Drawing(brick) => brick,
+            ref state => panic!("not drawing in {:?}", state),
+        };
+
+        drag(&mut pianoroll, 600.0, 300.0);
+        drag(&mut pianoroll, 402.0, 302.0);
+
+        match pianoroll.state {
+            State::Drawing(brick) => {
+                let view_pos = pianoroll.grid.view_position(Vector2::new(402.0, 302.0));
+                assert_eq!(brick.press, None);
+                assert_eq!(brick.time.1, pianoroll.pencil_end(view_pos.x));
+                assert_eq!(brick.pitch, view_pos.y);
+                assert_ne!(brick, unmoved);
             },
-            Msg::RightDrag { vector } => {
-                let shift = -self.grid.view_vector(vector);
+            ref state => panic!("not drawing in {:?}", state),
+        }
+    }
 
-                let v0 = self.grid.view.0 + shift;
-                let v1 = self.grid.view.1 + shift;
+    #[test]
+    fn note_edit_checks_ranges() {
+        let mut edit = NoteEdit::new(note((0, 4), 124));
+        assert_eq!(edit.parse(), Some(note((0, 4), 124)));
 
-                if
-                    (shift.y > 0.0 && v1.y <= 31.0 * 8.0)
-                    || (shift.y < 0.0 && v0.y >= 0.0)
-                {
-                    self.grid.view.0.y = v0.y;
-                    self.grid.view.1.y = v1.y;
-                }
+        for &(field, value) in &[(2, "-1"), (2, "249"), (3, "0"), (3, "17"), (4, "128"), (1, "0")] {
+            let mut invalid = edit.clone();
+            invalid.fields[field] = value.into();
+            assert_eq!(invalid.parse(), None, "{} = {}", NoteEdit::LABELS[field], value);
+        }
+
+        edit.fields[3] = "16".into();
+        assert_eq!(edit.parse().map(|n| n.channel), Some(15));
+    }
+
+    #[test]
+    fn note_edit_keeps_off_locked_channels() {
+        let mut pianoroll = with_selection(vec![note((0, 4), 124)]);
+        pianoroll.locked.insert(1);
+        pianoroll.on_action(Action::EditNote, &mut vec![]);
+
+        if let State::EditingNote(ref mut edit) = pianoroll.state {
+            edit.fields[3] = "2".into()
+        }
+        pianoroll.apply_edit();
+        assert!(matches!(pianoroll.state, State::EditingNote(_)));
+        assert_eq!(pianoroll.score.notes, vec![note((0, 4), 124)]);
+    }
 
-                self.grid.view.0.x = v0.x;
-                self.grid.view.1.x = v1.x;
+    fn full_score() -> Score {
+        Score {
+            notes: vec![note((0, 4), 124), Note { channel: 3, velocity: 64, ..note((4, 12), 140) }],
+            tempo: vec![(0, 120.0), (32, 90.5)],
+            length: Some(8),
+            meter: {
+                let mut meter = ui::Meter::new(3);
+                meter.set_beats(4, 5);
+                meter
             },
-            Msg::Time(t) => {
-                self.on_time(t, cmds)
+            ..Score::new()
+        }
+    }
+
+    #[test]
+    fn quantizing_keeps_notes_in_the_ticks() {
+        let last = note((i16::MAX - 7, i16::MAX), 124);
+        let mut pianoroll = with_selection(vec![note((0, 3), 124), last]);
+        pianoroll.quantize_lengths(4);
+
+        // Rounded up, the last note would end past the last tick
+        assert_eq!(selection(&pianoroll), vec![note((0, 4), 124), last]);
+    }
+
+    #[test]
+    fn loading_checks_the_tempo() {
+        let load_both = |score: &Score| (
+            Score::load(&score.save().unwrap()),
+            Score::from_bytes(&score.to_bytes().unwrap()),
+        );
+
+        let unsorted = Score { tempo: vec![(32, 90.5), (0, 120.0)], ..full_score() };
+        let (from_ron, from_bytes) = load_both(&unsorted);
+        assert_eq!(from_ron, Ok(full_score()));
+        assert_eq!(from_bytes, Ok(full_score()));
+
+        for tempo in &[vec![], vec![(0, 120.0), (16, 0.0)], vec![(0, -60.0)], vec![(0, f32::NAN)]] {
+            let (from_ron, from_bytes) = load_both(&Score { tempo: tempo.clone(), ..full_score() });
+            assert!(from_ron.is_err() && from_bytes.is_err(), "{:?}", tempo);
+        }
+    }
+
+    #[test]
+    fn binary_and_ron_round_trip() {
+        let score = full_score();
+
+        let from_bytes = Score::from_bytes(&score.to_bytes().unwrap()).unwrap();
+        assert_eq!(from_bytes, score);
+        let from_ron = Score::load(&score.save().unwrap()).unwrap();
+        assert_eq!(from_ron, score);
+
+        // Through one format into the other and back
+        let through_ron = Score::load(&from_bytes.save().unwrap()).unwrap();
+        assert_eq!(Score::from_bytes(&through_ron.to_bytes().unwrap()).unwrap(), score);
+    }
+
+    #[test]
+    fn loads_version_1_binary() {
+        let notes = vec![note((0, 4), 124), note((4, 8), 130)];
+        let tempo = vec![(0, 120.0)];
+        let bytes = bincode::serialize(&(1u32, 16u16, notes.clone(), tempo.clone())).unwrap();
+
+        let score = Score::from_bytes(&bytes).unwrap();
+        assert_eq!(score, Score { notes, tempo, ..Score::new() });
+    }
+
+    #[test]
+    fn refuses_newer_binary() {
+        let mut score = full_score();
+        score.version = SCORE_VERSION + 1;
+
+        assert!(Score::from_bytes(&score.to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn measures_follow_their_meter() {
+        let mut meter = ui::Meter::default();
+        meter.set_beats(2, 3);
+
+        assert_eq!(meter.measure_start(2), 2.0);
+        assert_eq!(meter.measure_start(3), 2.75);
+        assert_eq!(meter.measure_start(5), 4.25);
+        assert_eq!(meter.measure_start(-1), -1.0);
+        assert_eq!(meter.measure_at(2.7), 2);
+        assert_eq!(meter.measure_at(2.8), 3);
+        assert_eq!(meter.measure_at(-0.5), -1);
+    }
+
+    #[test]
+    fn measure_steps_follow_the_meter() {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(Score::with_length(4, 16, 3));
+
+        pianoroll.on_action(Action::MeasureForward, &mut vec![]);
+        assert_eq!(pianoroll.play_pos, 0.75);
+        pianoroll.on_action(Action::MeasureForward, &mut vec![]);
+        assert_eq!(pianoroll.play_pos, 1.5);
+        pianoroll.on_action(Action::MeasureBack, &mut vec![]);
+        assert_eq!(pianoroll.play_pos, 0.75);
+        // 4 measures of 12 ticks
+        assert_eq!(pianoroll.score.song_end(), 48);
+    }
+
+    #[test]
+    fn migrates_version_2_meter() {
+        let ron = "Score(version: 2, measure_ticks: 16, notes: [], length: Some(3), beats: Some(3))";
+
+        let score = Score::load(ron).unwrap();
+        assert_eq!(score.meter, ui::Meter::new(3));
+        // 3 whole notes take 4 measures of 3/4
+        assert_eq!(score.length, Some(4));
+        assert_eq!(score.version, SCORE_VERSION);
+    }
+
+    /// Plays a score from the start, advancing the time by `dt` for at most
+    /// `steps` updates, and returns every command the playback emitted. This is
+    /// the same state machine the window drives, with a fixed update rate.
+    fn simulate_playback(score: &Score, dt: Duration, steps: usize) -> Vec<Command> {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(score.clone());
+
+        let mut cmds = vec![];
+        pianoroll.play_from_start(&mut cmds);
+
+        for step in 1..=steps {
+            if let State::Playing(_) = pianoroll.state {
+                pianoroll.on_time(dt * step as u32, &mut cmds)
             }
-            WindowEvent(KeyboardInput { input, .. })
-            if input.state == glutin::ElementState::Pressed => {
-                self.on_key_press(input.scancode, cmds)
-            },
-            WindowEvent(Resized(sz)) =>
-                self.grid.size = Vector2::new(sz.width as f32, sz.height as f32),
-            _ => (),
+            else { break }
         }
+
+        cmds
     }
 
-    pub fn draw(&self, screen_size: [f32; 2], scene: &mut renderer::Scene) {
-        self.grid.draw(screen_size.into(), scene);
+    #[test]
+    fn playback_pairs_note_ons_with_note_offs() {
+        let notes = vec![
+            note((0, 4), 124), note((2, 6), 128), note((4, 5), 131),
+            note((8, 16), 124), Note { channel: 2, ..note((8, 12), 140) },
+        ];
+        let cmds = simulate_playback(&score(notes.clone()), Duration::from_millis(10), 1000);
 
-        let mut notes = self.score.notes.clone();
+        let ons: Vec<(Note, Instant)> = cmds.iter()
+            .filter_map(|c| match *c { Command::NoteOn(n, at) => Some((n, at)), _ => None })
+            .collect();
+        let offs: Vec<(Note, Instant)> = cmds.iter()
+            .filter_map(|c| match *c { Command::NoteOff(n, at) => Some((n, at)), _ => None })
+            .collect();
 
-        if let State::Drawing(brick) = self.state {
-            notes.push(brick.into())
+        // Every note sounds once, in the order of the score
+        assert_eq!(ons.iter().map(|on| on.0).collect::<Vec<_>>(), notes);
+        assert!(ons.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(offs.len(), ons.len());
+
+        // and stops after it started, as long after as it is long
+        for &(n, on) in &ons {
+            let off = offs.iter().find(|off| off.0 == n).expect("note left sounding").1;
+            assert!(off > on);
+            let ms = (off - on).as_millis();
+            // 16 ticks a second at the default tempo
+            let len = (n.time.1 - n.time.0) as f32 * 62.5;
+            assert!((ms as f32 - len).abs() <= 1.0, "{:?} lasts {}ms", n, ms);
         }
+    }
 
-        if let State::NotesSelected(ref framed) = self.state {
-            notes.retain(|n| !framed.contains(n));
+    #[test]
+    fn overlapping_notes_on_one_pitch_release_before_striking_again() {
+        let (a, b, c) = (note((0, 8), 124), note((4, 12), 124), note((12, 16), 124));
+        // Another pitch overlapping is left alone
+        let d = note((2, 10), 128);
+        let cmds = simulate_playback(&score(vec![a, b, c, d]), Duration::from_millis(10), 1000);
 
-            ui::NoteView {
-                notes: framed.clone(),
-                measure_ticks: self.score.measure_ticks,
-                style: self.grid.style,
-                view: self.grid.view,
-                selected: true,
-            }.draw(screen_size.into(), scene)
+        let events: Vec<(bool, Note)> = cmds.iter()
+            .filter_map(|c| match *c {
+                Command::NoteOn(n, _) => Some((true, n)),
+                Command::NoteOff(n, _) => Some((false, n)),
+                _ => None,
+            })
+            .collect();
+
+        // a is released where b starts, and its own off at 8 doesn't cut b short
+        assert_eq!(events, vec![
+            (true, a), (true, d), (false, a), (true, b), (false, d),
+            (false, b), (true, c), (false, c),
+        ]);
+
+        // A note inside a longer one on the same pitch
+        let (long, short) = (note((0, 16), 124), note((4, 8), 124));
+        let cmds = simulate_playback(&score(vec![long, short]), Duration::from_millis(10), 1000);
+        let events: Vec<(bool, Note, Instant)> = cmds.iter()
+            .filter_map(|c| match *c {
+                Command::NoteOn(n, at) => Some((true, n, at)),
+                Command::NoteOff(n, at) => Some((false, n, at)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            events.iter().map(|e| (e.0, e.1)).collect::<Vec<_>>(),
+            vec![(true, long), (false, long), (true, short), (false, short)]
+        );
+        // The release and the new strike happen together
+        assert_eq!(events[1].2, events[2].2);
+    }
+
+    fn press(pianoroll: &mut PianoRoll, key: glutin::VirtualKeyCode) -> Vec<Command> {
+        let mut cmds = vec![];
+        pianoroll.on_key_press(glutin::KeyboardInput {
+            scancode: 0,
+            state: glutin::ElementState::Pressed,
+            virtual_keycode: Some(key),
+            modifiers: Default::default(),
+        }, &mut cmds);
+        cmds
+    }
+
+    #[test]
+    fn recovery_waits_for_an_answer() {
+        use glutin::VirtualKeyCode as Key;
+
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+        pianoroll.ask_recovery();
+
+        pianoroll.handle_msg(Msg::LeftPressed {
+            position: Vector2::new(400.0, 300.0),
+            modifiers: Default::default(),
+        }, &mut vec![]);
+        assert!(press(&mut pianoroll, Key::Space).is_empty());
+        assert_eq!(pianoroll.state, State::AskingRecovery);
+
+        assert_eq!(press(&mut pianoroll, Key::Y), vec![Command::Recover]);
+        assert_eq!(pianoroll.state, State::Idle);
+
+        pianoroll.ask_recovery();
+        assert!(press(&mut pianoroll, Key::N).is_empty());
+        assert_eq!(pianoroll.state, State::Idle);
+    }
+
+    #[test]
+    fn counts_score_changes() {
+        let mut pianoroll = with_selection(vec![note((0, 4), 124)]);
+
+        pianoroll.on_action(Action::ToggleLoop, &mut vec![]);
+        assert_eq!(pianoroll.score_changes(), 0);
+        pianoroll.on_action(Action::Delete, &mut vec![]);
+        assert_eq!(pianoroll.score_changes(), 1);
+        // Loading a score isn't an edit of it
+        pianoroll.load_score(full_score());
+        assert_eq!(pianoroll.score_changes(), 1);
+    }
+
+    #[test]
+    fn tiny_resizes_keep_the_last_size() {
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+
+        resize(&mut pianoroll, 0.0, 0.0);
+        assert_eq!(pianoroll.grid.size, Vector2::new(800.0, 600.0));
+        resize(&mut pianoroll, 1200.0, 8.0);
+        assert_eq!(pianoroll.grid.size, Vector2::new(800.0, 600.0));
+
+        resize(&mut pianoroll, 640.0, 480.0);
+        assert_eq!(pianoroll.grid.size, Vector2::new(640.0, 480.0));
+    }
+
+    #[test]
+    fn pixel_sizes_land_on_physical_pixels() {
+        for &ratio in &[1.0, 1.25, 1.5, 2.0] {
+            for &width in &[0.3, 1.0, 2.0, 3.0] {
+                let physical = ui::snap_to_pixels(width, ratio) * ratio;
+                assert!(physical >= 1.0 && (physical - physical.round()).abs() < 1e-4);
+
+                let (from, to) = ui::line_span(10.3, width, ratio);
+                assert!(((from * ratio) - (from * ratio).round()).abs() < 1e-4);
+                assert!(((to - from) * ratio - physical).abs() < 1e-4);
+            }
         }
+    }
 
-        if let State::SelectFrame(v0, v1) = self.state {
-            let (from, to) = normalize_square(v0, v1);
-            ui::Frame {
-                from, to,
-                style: self.grid.style,
-            }.draw(screen_size.into(), scene)
+    #[test]
+    fn resizing_stops_a_tick_from_the_other_edge() {
+        let time = (4, 8);
+
+        assert_eq!(Edge::Start.resize(time, 2), (2, 8));
+        assert_eq!(Edge::Start.resize(time, 8), (7, 8));
+        assert_eq!(Edge::Start.resize(time, 12), (7, 8));
+        assert_eq!(Edge::Start.resize(time, -3), (0, 8));
+
+        assert_eq!(Edge::End.resize(time, 10), (4, 10));
+        assert_eq!(Edge::End.resize(time, 4), (4, 5));
+        assert_eq!(Edge::End.resize(time, 0), (4, 5));
+    }
+
+    #[test]
+    fn step_and_line_tools_follow_subdivisions() {
+        let mut pianoroll = PianoRoll::new();
+        resize(&mut pianoroll, 800.0, 600.0);
+        let from = pianoroll.grid.screen_position(Vector2::new(0.0, 124.0));
+        let to = pianoroll.grid.screen_position(Vector2::new(0.5, 124.0));
+        let times = |p: &PianoRoll| p.line_notes(from, to).iter().map(|n| n.time).collect::<Vec<_>>();
+
+        assert_eq!(pianoroll.step_cell(pianoroll.step_index(0.3)), (4, 8));
+        assert_eq!(times(&pianoroll), vec![(0, 4), (4, 8), (8, 12)]);
+
+        pianoroll.grid.subdivision = 2;
+        assert_eq!(pianoroll.step_cell(pianoroll.step_index(0.3)), (4, 6));
+        assert_eq!(times(&pianoroll), vec![(0, 2), (2, 4), (4, 6), (6, 8), (8, 10)]);
+    }
+
+    #[test]
+    fn pasting_checks_the_notes_and_places_them() {
+        let json = |notes: &[Note]| serde_json::to_string(notes).unwrap();
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(score(vec![note((0, 8), 124)]));
+        pianoroll.play_pos = 0.25;
+
+        // Invalid notes fall back to the ones copied last, and there are none
+        for notes in &[
+            vec![note((10, 10), 124)],
+            vec![note((10, 14), MAX_PITCH + 1)],
+            vec![Note { channel: CHANNELS, ..note((10, 14), 124) }],
+            // Moved to the play position the end is past the last tick
+            vec![note((0, 1), 124), note((32_000, i16::MAX), 124)],
+        ] {
+            pianoroll.paste_text(Some(&json(notes)));
+            assert_eq!(pianoroll.score.notes, vec![note((0, 8), 124)], "{:?}", notes);
+            assert_eq!(pianoroll.state, State::Idle);
         }
 
-        ui::NoteView {
-            notes,
-            measure_ticks: self.score.measure_ticks,
-            style: self.grid.style,
-            view: self.grid.view,
-            selected: false,
+        // A pasted note cuts the one under it, like a drawn one
+        pianoroll.paste_text(Some(&json(&[note((10, 14), 124)])));
+        assert_eq!(pianoroll.score.notes, vec![note((0, 4), 124), note((4, 8), 124)]);
+        assert_eq!(selection(&pianoroll), vec![note((4, 8), 124)]);
+
+        // Past the note limit nothing is pasted
+        pianoroll.set_max_notes(2);
+        pianoroll.paste_text(Some(&json(&[note((0, 2), 130)])));
+        assert_eq!(pianoroll.score.notes.len(), 2);
+    }
+
+    #[test]
+    fn snapping_keeps_to_whole_ticks() {
+        let cycle = |pianoroll: &mut PianoRoll| {
+            let mut labels = vec![];
+            loop {
+                pianoroll.on_action(Action::CycleSnapResolution, &mut vec![]);
+                labels.push(pianoroll.snap_resolution.label());
+                if pianoroll.snap_resolution == SnapResolution::Free { return labels }
+            }
+        };
+
+        // Triplets don't fall on the 16 ticks of a whole note
+        let mut pianoroll = PianoRoll::new();
+        assert_eq!(cycle(&mut pianoroll), vec!["1/1", "1/2", "1/4", "1/8", "1/16", ""]);
+        pianoroll.set_snap_resolution(SnapResolution::EighthTriplet);
+        assert_eq!(pianoroll.snap_resolution, SnapResolution::Free);
+
+        // With 48 they do, and the free snapping and the default length scale along
+        pianoroll.load_score(Score { measure_ticks: 48, ..Score::new() });
+        assert_eq!(cycle(&mut pianoroll), vec!["1/1", "1/2", "1/4", "1/8", "1/16", "1/4t", "1/8t", ""]);
+        assert_eq!(pianoroll.pencil_tick(0.3), 12.0);
+        assert_eq!(pianoroll.default_note_len(), 12);
+
+        pianoroll.set_snap_resolution(SnapResolution::EighthTriplet);
+        assert_eq!(pianoroll.click_note_len(), 4);
+        assert_eq!(pianoroll.pencil_tick(0.3), 4.0 * (0.3f32 * 12.0).round());
+
+        // Loading a score the value doesn't fit goes back to free snapping
+        pianoroll.load_score(Score::new());
+        assert_eq!(pianoroll.snap_resolution, SnapResolution::Free);
+    }
+
+    #[test]
+    fn input_keys_release_the_note_they_played() {
+        let mut pianoroll = PianoRoll::new();
+        let at = Instant::now();
+        let mut cmds = vec![];
+
+        pianoroll.handle_msg(Msg::InputNoteOn { pitch: 124, velocity: 64, at }, &mut cmds);
+        // Choosing another channel while the key is held
+        pianoroll.on_action(Action::ActiveChannel(3), &mut cmds);
+        pianoroll.handle_msg(Msg::InputNoteOff { pitch: 124, at }, &mut cmds);
+
+        let played = Note { channel: 0, time: (0, 0), pitch: 124, velocity: 64 };
+        let sent: Vec<Command> = cmds.into_iter()
+            .filter(|c| matches!(c, Command::NoteOn(..) | Command::NoteOff(..)))
+            .collect();
+        assert_eq!(sent, vec![Command::NoteOn(played, at), Command::NoteOff(played, at)]);
+    }
+
+    #[test]
+    fn moved_notes_stay_in_the_score_and_cut_what_they_land_on() {
+        let (a, b, under) = (note((4, 8), 124), note((8, 12), 130), note((0, 8), 120));
+        let mut pianoroll = with_selection(vec![a, b]);
+        pianoroll.score.notes.push(under);
+        resize(&mut pianoroll, 800.0, 600.0);
+        pianoroll.state = State::MovingNotes(Move {
+            notes: vec![a, b],
+            grabbed: a,
+            from: Vector2::new(0.5, 124.0),
+            shift: (0, 0),
+            audition: None,
+        });
+        let shift = |p: &PianoRoll| match p.state {
+            State::MovingNotes(ref m) => m.shift,
+            ref state => panic!("not moving in {:?}", state),
+        };
+
+        // Dragged far past the start and the lowest pitch, or the last tick and the highest one
+        let position = pianoroll.grid.screen_position(Vector2::new(-100.0, -1000.0));
+        pianoroll.st_move_notes(position, &mut vec![]);
+        assert_eq!(shift(&pianoroll), (-4, -124));
+        let position = pianoroll.grid.screen_position(Vector2::new(5000.0, 1000.0));
+        pianoroll.st_move_notes(position, &mut vec![]);
+        assert_eq!(shift(&pianoroll), (i16::MAX - 12, MAX_PITCH - 130));
+
+        if let State::MovingNotes(ref mut m) = pianoroll.state {
+            m.shift = (-4, -4)
         }
-        .draw(screen_size.into(), scene);
+        pianoroll.st_drop_notes(&mut vec![]);
 
-        let play_pos =
-            if let State::Playing(pos, _) = self.state { pos }
-            else { self.play_pos };
-        let play_pos = (play_pos - self.grid.view.0.x) / (self.grid.view.1.x - self.grid.view.0.x);
-        ui::PlayBar {
-            position: play_pos,
-            style: self.grid.style,
-        }.draw(screen_size.into(), scene);
+        let mut notes = pianoroll.score.notes.clone();
+        notes.sort_by_key(|n| (n.time, n.pitch));
+        assert_eq!(notes, vec![note((0, 4), 120), note((4, 8), 120), note((4, 8), 126)]);
+        assert_eq!(selection(&pianoroll), vec![note((0, 4), 120), note((4, 8), 126)]);
+    }
+
+    #[test]
+    fn minimap_clicks_keep_to_the_view_bounds() {
+        let mut pianoroll = PianoRoll::new();
+        pianoroll.load_score(Score {
+            notes: vec![note((0, 4), 124), note((636, 640), 124)],
+            length: Some(16),
+            ..Score::new()
+        });
+        resize(&mut pianoroll, 800.0, 600.0);
+        let span = pianoroll.grid.view.1 - pianoroll.grid.view.0;
+
+        // The top right corner of the minimap is past the end and the top pitch
+        let (_, corner) = pianoroll.minimap_rect();
+        let click = corner - Vector2::new(1.0, 1.0);
+        pianoroll.handle_msg(Msg::LeftPressed { position: click, modifiers: Default::default() }, &mut vec![]);
+
+        let (v0, v1) = pianoroll.grid.view;
+        assert!((v1.x - (pianoroll.score.length_pos().unwrap() + 1.0)).abs() < 1e-3);
+        assert!(v0.y >= 0.0 && v1.y <= MAX_PITCH as f32 + 1e-3);
+        let moved_span = v1 - v0;
+        assert!((moved_span.x - span.x).abs() < 1e-3 && (moved_span.y - span.y).abs() < 1e-3);
+
+        // A press while a note is being edited isn't taken by the minimap
+        let view = pianoroll.grid.view;
+        pianoroll.state = State::EditingNote(NoteEdit::new(note((0, 4), 124)));
+        let (start, _) = pianoroll.minimap_rect();
+        let click = start + Vector2::new(1.0, 1.0);
+        pianoroll.handle_msg(Msg::LeftPressed { position: click, modifiers: Default::default() }, &mut vec![]);
+        assert_eq!(pianoroll.grid.view, view);
     }
-}
\ No newline at end of file
+}
@@ -1,22 +1,32 @@
 use ::gfx;
 use ::glutin;
 
-use gfx::handle::{RenderTargetView, DepthStencilView};
+use gfx::handle::{Buffer, RenderTargetView, DepthStencilView, Texture};
 use gfx::traits::{Factory, FactoryExt};
 use gfx::{Encoder, PipelineState};
 use gfx_device_gl as gl;
 use gfx_window_glutin as gfx_glutin;
 
 use ::cgmath::Vector2;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub type ColorFormat = gfx::format::Rgba8;
 pub type DepthFormat = gfx::format::DepthStencil;
+pub type ColorSurface = <ColorFormat as gfx::format::Formatted>::Surface;
+pub type ColorTarget = RenderTargetView<gl::Resources, ColorFormat>;
 
 const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 
 pub struct Mesh {
     vertices: Vec<Vertex>,
-    indices: Vec<u16>,
+    indices: Vec<u32>,
+}
+
+impl Default for Mesh {
+    fn default() -> Self {
+        Mesh::new()
+    }
 }
 
 impl Mesh {
@@ -26,24 +36,91 @@ impl Mesh {
             indices: vec![],
         }
     }
+
+    /// A mesh with room for `rects` rects
+    pub fn with_capacity(rects: usize) -> Self {
+        Mesh {
+            vertices: Vec::with_capacity(4 * rects),
+            indices: Vec::with_capacity(6 * rects),
+        }
+    }
+
+    /// Whether `vertices` more vertices still fit the 32-bit indices
+    pub fn has_room(&self, vertices: usize) -> bool {
+        self.vertices.len() + vertices <= u32::MAX as usize + 1
+    }
+
     pub fn add_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, color: [f32; 4]) {
-        let i0 = self.vertices.len() as u16;
+        let i0 = self.vertices.len() as u32;
         let vs = [[a0.x, a0.y], [a0.x, a1.y], [a1.x, a1.y], [a1.x, a0.y]];
-        self.vertices.extend(vs.into_iter().map(|p| Vertex {
+        self.vertices.extend(vs.iter().map(|p| Vertex {
             pos: *p,
-            color: color,
+            color,
         }));
         self.indices.extend(&[i0, i0+1, i0+2, i0+2, i0+3, i0]);
     }
 
+    /// A rect that fades out to transparent over `feather` pixels at its edges,
+    /// which softens them without multisampling
+    pub fn add_feathered_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, feather: f32, color: [f32; 4]) {
+        let feather = feather.min((a1.x - a0.x) / 2.0).min((a1.y - a0.y) / 2.0).max(0.0);
+        let (b0, b1) = (a0 + Vector2::new(feather, feather), a1 - Vector2::new(feather, feather));
+        let clear = [color[0], color[1], color[2], 0.0];
+
+        // The opaque inner rect, then the transparent outer one around it
+        let i0 = self.vertices.len() as u32;
+        let inner = [[b0.x, b0.y], [b0.x, b1.y], [b1.x, b1.y], [b1.x, b0.y]];
+        let outer = [[a0.x, a0.y], [a0.x, a1.y], [a1.x, a1.y], [a1.x, a0.y]];
+        self.vertices.extend(inner.iter().map(|p| Vertex { pos: *p, color }));
+        self.vertices.extend(outer.iter().map(|p| Vertex { pos: *p, color: clear }));
+
+        self.indices.extend(&[i0, i0+1, i0+2, i0+2, i0+3, i0]);
+        for side in 0..4 {
+            let (i, j) = (i0 + side, i0 + (side + 1) % 4);
+            self.indices.extend(&[i, i+4, j+4, j+4, j, i]);
+        }
+    }
+
+    /// A rect with corners rounded by `radius`, as a single fan
+    pub fn add_rounded_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, radius: f32, color: [f32; 4]) {
+        use std::f32::consts::FRAC_PI_2;
+        const CORNER_STEPS: usize = 4;
+
+        let radius = radius.min((a1.x - a0.x) / 2.0).min((a1.y - a0.y) / 2.0).max(0.0);
+        let corners = [
+            (a1.x - radius, a1.y - radius),
+            (a0.x + radius, a1.y - radius),
+            (a0.x + radius, a0.y + radius),
+            (a1.x - radius, a0.y + radius),
+        ];
+
+        let center = Vertex {
+            pos: [(a0.x + a1.x) / 2.0, (a0.y + a1.y) / 2.0],
+            color,
+        };
+        let mut rim = vec![];
+        for (i, &(cx, cy)) in corners.iter().enumerate() {
+            for step in 0..=CORNER_STEPS {
+                let angle = (i as f32 + step as f32 / CORNER_STEPS as f32) * FRAC_PI_2;
+                rim.push(Vertex {
+                    pos: [cx + radius * angle.cos(), cy + radius * angle.sin()],
+                    color,
+                });
+            }
+        }
+        rim.push(rim[0]);
+
+        self.add_fan(::std::iter::once(center).chain(rim))
+    }
+
     pub fn add_fan<V>(&mut self, iter: V)
     where V: ::std::iter::IntoIterator<Item=Vertex> {
-        let i0 = self.vertices.len() as u16;
+        let i0 = self.vertices.len() as u32;
         let mut vs = iter.into_iter();
         self.vertices.push(vs.next().unwrap());
         self.vertices.push(vs.next().unwrap());
         for (i, v) in vs.enumerate() {
-            let i = i as u16 + 1;
+            let i = i as u32 + 1;
             self.vertices.push(v);
             self.indices.extend(&[i0, i0+i, i0+i+1]);
         }
@@ -59,28 +136,61 @@ pub enum Object {
     Mesh(Mesh),
 }
 
+/// Every mesh added to a scene gets a new id, so renderers can tell the
+/// ones they've already uploaded
+static NEXT_MESH_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_mesh_id() -> u64 {
+    NEXT_MESH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct Scene {
-    objs: Vec<Mesh>,
+    objs: Vec<(u64, Mesh)>,
     bg_color: [f32; 4],
 }
 
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new()
+    }
+}
+
 impl Scene {
     pub fn new() -> Self {
         Scene {
             objs: vec![],
-            bg_color: [0.0; 4],
+            bg_color: BLACK,
         }
     }
     pub fn add_mesh(&mut self, mesh: Mesh) {
-        self.objs.push(mesh)
+        self.objs.push((next_mesh_id(), mesh))
     }
 
     pub fn set_bg_color(&mut self, color: [f32; 4]) {
         self.bg_color = color
     }
 
+    pub fn bg_color(&self) -> [f32; 4] {
+        self.bg_color
+    }
+
+    /// Adds a mesh that can be replaced later by `set_layer`, keeping its
+    /// place among the others, and returns the layer
+    pub fn add_layer(&mut self, mesh: Mesh) -> usize {
+        self.add_mesh(mesh);
+        self.objs.len() - 1
+    }
+
+    /// Replaces the mesh of a layer, leaving the rest of the scene as it is
+    pub fn set_layer(&mut self, layer: usize, mesh: Mesh) {
+        if let Some(m) = self.objs.get_mut(layer) {
+            *m = (next_mesh_id(), mesh)
+        }
+    }
+
     pub fn clear(&mut self) {
-        self.objs.clear()
+        self.objs.clear();
+        self.bg_color = BLACK
     }
 }
 
@@ -93,30 +203,86 @@ gfx_defines! {
     pipeline pipe {
         screen: gfx::Global<[f32; 2]> = "i_Screen",
         vbuf: gfx::VertexBuffer<Vertex> = (),
-        out: gfx::RenderTarget<ColorFormat> = "Target0",
+        out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
     }
 }
 
+/// Something that shapes can be rendered into
 pub trait Render {
     fn render_fan<V>(&mut self, iter: V)
     where V: ::std::iter::IntoIterator<Item=Vertex>;
 
     fn render_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, color: [f32; 4]);
-    fn clear(&mut self, color: [f32; 4]);
+}
+
+impl Render for Mesh {
+    fn render_fan<V>(&mut self, iter: V)
+    where V: ::std::iter::IntoIterator<Item=Vertex> {
+        self.add_fan(iter)
+    }
+
+    fn render_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, color: [f32; 4]) {
+        self.add_rect(a0, a1, color)
+    }
+}
+
+/// Every shape becomes a mesh of its own, so prefer building a `Mesh`
+/// when drawing many of them
+impl Render for Scene {
+    fn render_fan<V>(&mut self, iter: V)
+    where V: ::std::iter::IntoIterator<Item=Vertex> {
+        let mut mesh = Mesh::new();
+        mesh.add_fan(iter);
+        self.add_mesh(mesh)
+    }
+
+    fn render_rect(&mut self, a0: Vector2<f32>, a1: Vector2<f32>, color: [f32; 4]) {
+        let mut mesh = Mesh::new();
+        mesh.add_rect(a0, a1, color);
+        self.add_mesh(mesh)
+    }
+}
+
+/// A texture to render into offscreen, and its render target view. The
+/// texture can be read back with `Renderer::read_pixels`.
+pub fn offscreen_target(factory: &mut gl::Factory, size: [u32; 2])
+    -> Result<(Texture<gl::Resources, ColorSurface>, ColorTarget), String>
+{
+    use gfx::format::{ChannelTyped, Formatted};
+    use gfx::memory::{Bind, Usage};
+    use gfx::texture::{AaMode, Kind};
+
+    let channel = <<ColorFormat as Formatted>::Channel as ChannelTyped>::get_channel_type();
+    let texture = factory.create_texture::<ColorSurface>(
+        Kind::D2(size[0] as u16, size[1] as u16, AaMode::Single), 1,
+        Bind::RENDER_TARGET | Bind::TRANSFER_SRC, Usage::Data, Some(channel)
+    ).map_err(|e| e.to_string())?;
+    let target = factory.view_texture_as_render_target::<ColorFormat>(&texture, 0, None)
+        .map_err(|e| e.to_string())?;
+
+    Ok((texture, target))
+}
+
+/// A UI element that draws itself into a scene of the given screen size
+pub trait Draw {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene);
 }
 
 pub struct Renderer {
     factory: gl::Factory,
     encoder: Encoder<gl::Resources, gl::CommandBuffer>,
-    out_color: RenderTargetView<gl::Resources, ColorFormat>,
+    out_color: ColorTarget,
     pso: PipelineState<gl::Resources, pipe::Meta>,
+    /// Vertex buffers of the meshes rendered last time, by mesh id. Meshes
+    /// that are still in the scene aren't uploaded again.
+    buffers: HashMap<u64, (Buffer<gl::Resources, Vertex>, gfx::Slice<gl::Resources>)>,
 }
 
 impl Renderer {
     pub fn new(
         mut factory: gl::Factory,
         encoder: Encoder<gl::Resources, gl::CommandBuffer>,
-        out_color: RenderTargetView<gl::Resources, ColorFormat>
+        out_color: ColorTarget
     ) -> Self {
         use gfx::state::{Rasterizer, MultiSample};
 
@@ -139,29 +305,75 @@ impl Renderer {
 
         Renderer {
             factory, encoder, pso, out_color,
+            buffers: HashMap::new(),
         }
     }
     pub fn render_scene(&mut self, scene: &Scene, screen_size: [f32; 2], device: &mut gl::Device) {
-        for m in scene.objs.iter() {
-            let (vbuf, sl) =
-            self.factory.create_vertex_buffer_with_slice(&m.vertices, &*m.indices);
+        let target = self.out_color.clone();
+        self.render_scene_to(&target, scene, screen_size, device)
+    }
+    /// Renders into any target instead of the window, e.g. an offscreen texture
+    pub fn render_scene_to(
+        &mut self,
+        target: &ColorTarget,
+        scene: &Scene,
+        screen_size: [f32; 2],
+        device: &mut gl::Device
+    ) {
+        let mut buffers = HashMap::with_capacity(scene.objs.len());
+
+        for (id, m) in scene.objs.iter() {
+            let (vbuf, sl) = match self.buffers.remove(id) {
+                Some(uploaded) => uploaded,
+                None => self.factory.create_vertex_buffer_with_slice(&m.vertices, &*m.indices),
+            };
 
             let data = pipe::Data {
                 screen: screen_size,
-                vbuf,
-                out: self.out_color.clone(),
+                vbuf: vbuf.clone(),
+                out: target.clone(),
             };
 
             self.encoder.draw(&sl, &self.pso, &data);
+            buffers.insert(*id, (vbuf, sl));
         }
 
+        // Buffers of meshes gone from the scene are dropped here
+        self.buffers = buffers;
+
         self.encoder.flush(device);
     }
     pub fn update_views(&mut self, window: &glutin::GlWindow, depth: &mut DepthStencilView<gl::Resources, DepthFormat>) {
-        gfx_glutin::update_views(&window, &mut self.out_color, depth)
+        gfx_glutin::update_views(window, &mut self.out_color, depth)
     }
 
     pub fn clear(&mut self, color: [f32; 4]) {
-        self.encoder.clear(&mut self.out_color, color)
+        let target = self.out_color.clone();
+        self.clear_target(&target, color)
+    }
+
+    pub fn clear_target(&mut self, target: &ColorTarget, color: [f32; 4]) {
+        self.encoder.clear(target, color)
+    }
+
+    /// Copies the pixels of a texture back to memory, bottom row first
+    pub fn read_pixels(&mut self, texture: &Texture<gl::Resources, ColorSurface>, device: &mut gl::Device) -> Result<Vec<u8>, String> {
+        use gfx::format::{ChannelTyped, Formatted};
+        use gfx::memory::Typed;
+
+        let info = texture.get_info();
+        let (width, height, _, _) = info.kind.get_dimensions();
+        let channel = <<ColorFormat as Formatted>::Channel as ChannelTyped>::get_channel_type();
+
+        let buffer = self.factory.create_download_buffer::<[u8; 4]>(width as usize * height as usize)
+            .map_err(|e| e.to_string())?;
+        self.encoder.copy_texture_to_buffer_raw(
+            texture.raw(), None, info.to_raw_image_info(channel, 0),
+            buffer.raw(), 0
+        ).map_err(|e| format!("{:?}", e))?;
+        self.encoder.flush(device);
+
+        let reader = self.factory.read_mapping(&buffer).map_err(|e| e.to_string())?;
+        Ok(reader.iter().flat_map(|p| p.iter().cloned()).collect())
     }
 }
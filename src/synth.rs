@@ -0,0 +1,168 @@
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use dieseq::pianoroll::Note;
+use dieseq::tuning::Tuning;
+
+const ATTACK: f32 = 0.01;
+const DECAY: f32 = 0.1;
+const SUSTAIN: f32 = 0.6;
+const RELEASE: f32 = 0.2;
+/// Keeps a handful of simultaneous voices from clipping
+const GAIN: f32 = 0.15;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wave {
+    Sine,
+    Saw,
+}
+
+/// How note velocity maps to loudness
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    /// Soft notes get much quieter, for more room at the soft end
+    Exponential,
+    /// Soft notes stay closer in loudness to loud ones
+    Logarithmic,
+}
+
+impl VelocityCurve {
+    /// The amplitude from 0 to 1 of a velocity from 0 to 127
+    fn amplitude(self, velocity: u8) -> f32 {
+        let v = velocity.min(127) as f32 / 127.0;
+
+        match self {
+            VelocityCurve::Linear => v,
+            VelocityCurve::Exponential => ((4.0 * v).exp() - 1.0) / (4.0f32.exp() - 1.0),
+            VelocityCurve::Logarithmic => (1.0 + 9.0 * v).log10(),
+        }
+    }
+}
+
+struct Voice {
+    note: Note,
+    freq: f32,
+    /// Loudness by the note's velocity
+    amplitude: f32,
+    phase: f32,
+    /// Seconds since the note on
+    time: f32,
+    /// Envelope level and seconds since the note off, once released
+    released: Option<(f32, f32)>,
+}
+
+impl Voice {
+    fn envelope(&self) -> f32 {
+        let held =
+            if self.time < ATTACK { self.time / ATTACK }
+            else if self.time < ATTACK + DECAY {
+                1.0 - (1.0 - SUSTAIN) * (self.time - ATTACK) / DECAY
+            }
+            else { SUSTAIN };
+
+        match self.released {
+            Some((level, t)) => level * (1.0 - t / RELEASE).max(0.0),
+            None => held,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self.released {
+            Some((_, t)) => t >= RELEASE,
+            None => false,
+        }
+    }
+
+    fn sample(&mut self, wave: Wave, dt: f32) -> f32 {
+        let value = match wave {
+            Wave::Sine => (self.phase * 2.0 * ::std::f32::consts::PI).sin(),
+            Wave::Saw => 2.0 * self.phase - 1.0,
+        };
+        let value = value * self.envelope() * self.amplitude;
+
+        self.phase = (self.phase + self.freq * dt).fract();
+        self.time += dt;
+        if let Some((_, ref mut t)) = self.released {
+            *t += dt
+        }
+
+        value
+    }
+}
+
+pub struct Synth {
+    voices: Arc<Mutex<Vec<Voice>>>,
+    tuning: Tuning,
+    curve: VelocityCurve,
+    _stream: cpal::Stream,
+}
+
+impl Synth {
+    pub fn new(wave: Wave, tuning: Tuning, curve: VelocityCurve) -> Result<Self, String> {
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or("No audio output device")?;
+        let config: cpal::StreamConfig = device.default_output_config()
+            .map_err(|e| e.to_string())?
+            .into();
+
+        let channels = config.channels as usize;
+        let dt = 1.0 / config.sample_rate.0 as f32;
+
+        let voices = Arc::new(Mutex::new(Vec::<Voice>::new()));
+        let shared = voices.clone();
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut voices = shared.lock().unwrap();
+
+                for frame in data.chunks_mut(channels) {
+                    let value: f32 = voices.iter_mut().map(|v| v.sample(wave, dt)).sum();
+                    for s in frame.iter_mut() {
+                        *s = value * GAIN
+                    }
+                }
+
+                voices.retain(|v| !v.is_done());
+            },
+            |e| eprintln!("Audio stream error: {}", e),
+            None
+        ).map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(Synth {
+            voices, tuning, curve,
+            _stream: stream,
+        })
+    }
+
+    pub fn note_on(&mut self, note: Note) {
+        let freq = self.tuning.freq(note.pitch);
+        let amplitude = self.curve.amplitude(note.velocity);
+
+        self.voices.lock().unwrap().push(Voice {
+            note, freq, amplitude,
+            phase: 0.0,
+            time: 0.0,
+            released: None,
+        })
+    }
+
+    pub fn note_off(&mut self, note: Note) {
+        let mut voices = self.voices.lock().unwrap();
+
+        if let Some(v) = voices.iter_mut().find(|v| v.note == note && v.released.is_none()) {
+            v.released = Some((v.envelope(), 0.0))
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        for v in self.voices.lock().unwrap().iter_mut() {
+            if v.released.is_none() {
+                v.released = Some((v.envelope(), 0.0))
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// How score pitches map to frequencies, for the outputs that produce or bend
+// the sound themselves
+
+/// Steps per octave of the score's pitches
+pub const EDO: f32 = 31.0;
+
+/// Frequency of a pitch, given the frequency of pitch 0
+pub fn pitch_to_freq(pitch: f32, edo: f32, base_freq: f32) -> f32 {
+    base_freq * 2f32.powf(pitch / edo)
+}
+
+/// A reference pitch and its frequency, which tune the whole instrument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    pub pitch: i16,
+    pub freq: f32,
+}
+
+impl Default for Tuning {
+    /// The middle C, pitch 124, at its equal tempered 261.63 Hz. This puts
+    /// pitch 0 at 16.35 Hz, four octaves below, and the A above the middle C
+    /// (pitch 147) at 437.5 Hz rather than 440.
+    fn default() -> Self {
+        Tuning {
+            pitch: 124,
+            freq: 261.625_6,
+        }
+    }
+}
+
+impl Tuning {
+    /// Parses a reference like `147=432`, a pitch and its frequency in Hz
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.splitn(2, '=');
+        let pitch = parts.next()?.trim().parse().ok()?;
+        let freq: f32 = parts.next()?.trim().parse().ok()?;
+
+        if freq > 0.0 { Some(Tuning { pitch, freq }) }
+        else { None }
+    }
+
+    /// Frequency of pitch 0
+    pub fn base_freq(&self) -> f32 {
+        self.freq / 2f32.powf(self.pitch as f32 / EDO)
+    }
+
+    pub fn freq(&self, pitch: i16) -> f32 {
+        pitch_to_freq(pitch as f32, EDO, self.base_freq())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3 * b
+    }
+
+    #[test]
+    fn pitch_to_freq_doubles_every_octave() {
+        assert_eq!(pitch_to_freq(0.0, EDO, 16.0), 16.0);
+        assert!(close(pitch_to_freq(31.0, EDO, 16.0), 32.0));
+        assert!(close(pitch_to_freq(-62.0, EDO, 16.0), 4.0));
+        assert!(close(pitch_to_freq(6.0, 12.0, 440.0), 440.0 * 2f32.sqrt()));
+    }
+
+    #[test]
+    fn default_tuning_keeps_pitch_0_on_c() {
+        let tuning = Tuning::default();
+
+        assert!(close(tuning.base_freq(), 16.351_6));
+        assert!(close(tuning.freq(124), 261.625_6));
+        assert!(close(tuning.freq(147), 437.55));
+    }
+
+    #[test]
+    fn parses_a_reference() {
+        assert_eq!(Tuning::parse("147=432"), Some(Tuning { pitch: 147, freq: 432.0 }));
+        assert!(close(Tuning::parse("147 = 440").unwrap().freq(147), 440.0));
+        assert_eq!(Tuning::parse("147"), None);
+        assert_eq!(Tuning::parse("147=0"), None);
+    }
+}
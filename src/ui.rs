@@ -1,8 +1,12 @@
+use std::collections::{HashMap, HashSet};
+
 use palette::Pixel;
 use palette::rgb::Srgba;
 use cgmath::{ElementWise, Vector2};
 
-use crate::renderer::{Mesh, Scene};
+use crate::font;
+use crate::normalize_square;
+use crate::renderer::{Draw, Mesh, Scene, Vertex};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 // Hard-coded Solarized theme
@@ -17,6 +21,10 @@ fn u8_to_rgb(r: u8, g: u8, b: u8) -> [f32; 4] {
     rgb.into_linear().into_raw()
 }
 
+fn with_alpha(color: [f32; 4], alpha: f32) -> [f32; 4] {
+    [color[0], color[1], color[2], alpha]
+}
+
 impl Style {
     pub fn inverse(&self) -> Self {
         match *self {
@@ -84,16 +92,217 @@ impl Style {
     pub fn green(&self) -> [f32; 4] {
         u8_to_rgb(0x85, 0x99, 0x00)
     }
+
+    /// Note color of a channel
+    pub fn channel(&self, channel: u16) -> [f32; 4] {
+        match channel % 8 {
+            0 => self.orange(),
+            1 => self.blue(),
+            2 => self.green(),
+            3 => self.magenta(),
+            4 => self.cyan(),
+            5 => self.violet(),
+            6 => self.red(),
+            _ => self.yellow(),
+        }
+    }
+}
+
+/// Named scales of 31-EDO pitch classes, the first one is the default
+pub const SCALES: [(&str, [i16; 7]); 5] = [
+    ("major", [0, 5, 10, 13, 18, 23, 28]),
+    ("minor", [0, 5, 8, 13, 18, 21, 26]),
+    ("dorian", [0, 5, 8, 13, 18, 23, 26]),
+    ("neutral", [0, 5, 9, 13, 18, 22, 27]),
+    // Major with the harmonic seventh, 7/4
+    ("harmonic", [0, 5, 10, 13, 18, 23, 25]),
+];
+
+pub fn scale_preset(name: &str) -> Option<Vec<i16>> {
+    SCALES.iter()
+        .find(|s| s.0 == name)
+        .map(|s| s.1.to_vec())
+}
+
+fn default_scale() -> Vec<i16> {
+    SCALES[0].1.to_vec()
+}
+
+fn default_min_line_spacing() -> f32 {
+    6.0
+}
+
+fn default_pixel_ratio() -> f32 {
+    1.0
+}
+
+fn default_subdivision() -> u8 {
+    1
+}
+
+/// The time signatures of a score, as the measures they start at and their
+/// beats, sorted by measure. A beat is a quarter note and positions are in
+/// whole notes, so a measure of 3/4 lasts three quarters of one. The first
+/// signature also holds before its measure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Meter(Vec<(i32, u8)>);
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter::new(4)
+    }
+}
+
+impl Meter {
+    /// The same number of beats in every measure
+    pub fn new(beats: u8) -> Self {
+        Meter(vec![(0, beats.max(1))])
+    }
+
+    /// The signature changes, as measures and beats
+    pub fn signatures(&self) -> &[(i32, u8)] {
+        &self.0
+    }
+
+    pub fn beats_at(&self, measure: i32) -> u8 {
+        self.0.iter()
+            .rev()
+            .find(|s| s.0 <= measure)
+            .or_else(|| self.0.first())
+            .map_or(4, |s| s.1.max(1))
+    }
+
+    /// How long a measure is in whole notes
+    pub fn measure_len(&self, measure: i32) -> f32 {
+        self.beats_at(measure) as f32 / 4.0
+    }
+
+    /// The position in whole notes a measure starts at
+    pub fn measure_start(&self, measure: i32) -> f32 {
+        if measure <= 0 {
+            return measure as f32 * self.measure_len(measure)
+        }
+
+        // Summed over the stretches of the same signature
+        let mut pos = 0.0;
+        let mut from = 0;
+        for &(at, _) in self.0.iter().filter(|s| s.0 > 0 && s.0 < measure) {
+            pos += (at - from) as f32 * self.measure_len(from);
+            from = at;
+        }
+
+        pos + (measure - from) as f32 * self.measure_len(from)
+    }
+
+    /// The measure a position in whole notes is in
+    pub fn measure_at(&self, pos: f32) -> i32 {
+        if pos < 0.0 {
+            return (pos / self.measure_len(-1)).floor() as i32
+        }
+
+        let mut start = 0.0;
+        let mut from = 0;
+        for &(at, _) in self.0.iter().filter(|s| s.0 > 0) {
+            let at_pos = start + (at - from) as f32 * self.measure_len(from);
+            if at_pos > pos { break }
+
+            start = at_pos;
+            from = at;
+        }
+
+        from + ((pos - start) / self.measure_len(from)).floor() as i32
+    }
+
+    /// Positions in whole notes of the beat lines from `from` to `to`, and
+    /// whether each starts a measure
+    pub fn beat_lines(&self, from: f32, to: f32) -> Vec<(f32, bool)> {
+        let mut lines = vec![];
+
+        for measure in self.measure_at(from)..=self.measure_at(to) {
+            let start = self.measure_start(measure);
+
+            for beat in 0..self.beats_at(measure) {
+                let pos = start + beat as f32 / 4.0;
+                if pos >= from && pos <= to {
+                    lines.push((pos, beat == 0))
+                }
+            }
+        }
+
+        lines
+    }
+
+    /// Sets the beats from a measure on, until the next signature change
+    pub fn set_beats(&mut self, measure: i32, beats: u8) {
+        let beats = beats.max(1);
+
+        match self.0.binary_search_by_key(&measure, |s| s.0) {
+            Ok(i) => self.0[i].1 = beats,
+            Err(i) => self.0.insert(i, (measure, beats)),
+        }
+    }
+}
+
+/// A length in logical pixels made a whole number of physical pixels, at
+/// least one, so that what it sizes isn't blurred over a partly covered pixel
+pub fn snap_to_pixels(length: f32, pixel_ratio: f32) -> f32 {
+    (length * pixel_ratio).round().max(1.0) / pixel_ratio
+}
+
+/// A position in logical pixels moved to the nearest edge of a physical pixel
+fn round_to_pixels(pos: f32, pixel_ratio: f32) -> f32 {
+    (pos * pixel_ratio).round() / pixel_ratio
+}
+
+/// Where a line starts and ends, moved to fall on whole physical pixels
+/// so it doesn't get blurred over two
+pub fn line_span(pos: f32, width: f32, pixel_ratio: f32) -> (f32, f32) {
+    let width = snap_to_pixels(width, pixel_ratio);
+    let start = round_to_pixels(pos - 0.5 * width, pixel_ratio);
+
+    (start, start + width)
+}
+
+/// Where a point of the view, in measures and pitches, is on a screen of
+/// the given size. Every element showing the score goes through this, so
+/// they all line up.
+pub fn screen_position(view: (Vector2<f32>, Vector2<f32>), size: Vector2<f32>, position: Vector2<f32>) -> Vector2<f32> {
+    (position - view.0).div_element_wise(view.1 - view.0).mul_element_wise(size)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Grid {
     pub size: Vector2<f32>,
     pub view: (Vector2<f32>, Vector2<f32>),
-    pub beats: u8,
     pub style: Style,
-    thin_width: f32,
-    thick_width: f32,
+    /// Thin lines closer than this many pixels are left out
+    #[serde(default = "default_min_line_spacing")]
+    pub min_line_spacing: f32,
+    /// Pitch classes whose rows are tinted, to stay in a key
+    #[serde(default)]
+    pub key: Option<Vec<i16>>,
+    /// Whether every second octave is shaded
+    #[serde(default)]
+    pub octave_bands: bool,
+    /// Pitch classes whose lines are highlighted
+    #[serde(default = "default_scale")]
+    pub scale: Vec<i16>,
+    /// Physical pixels per logical pixel, the window's HiDPI factor
+    #[serde(default = "default_pixel_ratio")]
+    pixel_ratio: f32,
+    /// Parts every beat is divided into by faint lines, e.g. 3 for triplets.
+    /// The pencil snaps to them when there's more than one.
+    #[serde(default = "default_subdivision")]
+    pub subdivision: u8,
+    /// How far every second subdivision line is moved later, as a fraction
+    /// of a subdivision
+    #[serde(default)]
+    pub swing: f32,
+    /// Widths of the thin and thick lines in logical pixels, or one and two
+    /// physical pixels when not set
+    #[serde(default)]
+    pub line_widths: Option<(f32, f32)>,
 }
 
 impl Grid {
@@ -101,9 +310,14 @@ impl Grid {
         Grid {
             size, view,
             style: Style::Dark,
-            beats: 4,
-            thin_width: 1.0,
-            thick_width: 2.0,
+            min_line_spacing: default_min_line_spacing(),
+            key: None,
+            octave_bands: false,
+            scale: default_scale(),
+            pixel_ratio: default_pixel_ratio(),
+            subdivision: default_subdivision(),
+            swing: 0.0,
+            line_widths: None,
         }
     }
 
@@ -116,85 +330,333 @@ impl Grid {
     pub fn view_position(&self, position: Vector2<f32>) -> Vector2<f32> {
         self.view.0 + self.view_vector(position)
     }
-}
 
-impl Grid {
-    pub fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+    /// Makes sure the view starts before it ends on both axes
+    pub fn normalize_view(&mut self) {
+        let (v0, v1) = normalize_square(self.view.0, self.view.1);
+
+        self.view = (v0, v1)
+    }
+
+    pub fn screen_position(&self, position: Vector2<f32>) -> Vector2<f32> {
+        screen_position(self.view, self.size, position)
+    }
+
+    pub fn set_pixel_ratio(&mut self, ratio: f32) {
+        self.pixel_ratio = ratio;
+    }
+
+    /// Physical pixels in a logical one
+    pub fn pixel_ratio(&self) -> f32 {
+        self.pixel_ratio
+    }
+
+    /// The color of a pitch line, which its label shares: octave Cs and the
+    /// degrees of the highlighted scale stand out
+    fn pitch_color(&self, line: i32) -> [f32; 4] {
+        if line % 31 == 0 { self.style.base1() }
+        else if self.scale.contains(&(line.rem_euclid(31) as i16)) { self.style.blue() }
+        else { self.style.base2() }
+    }
+
+    /// The label at the left edge of a pitch line: the octave on Cs, like C4
+    /// for the middle C, and the scale degree on the highlighted lines
+    fn pitch_label(&self, line: i32) -> Option<String> {
+        if line.rem_euclid(31) == 0 {
+            return Some(format!("C{}", line.div_euclid(31)))
+        }
+
+        self.scale.iter()
+            .position(|&pc| pc == line.rem_euclid(31) as i16)
+            .map(|degree| format!("{}", degree + 1))
+    }
+
+    /// Widths of the thin and thick lines in logical pixels
+    fn line_widths(&self) -> (f32, f32) {
+        self.line_widths.unwrap_or((1.0 / self.pixel_ratio, 2.0 / self.pixel_ratio))
+    }
+
+    fn line_span(&self, pos: f32, width: f32) -> (f32, f32) {
+        line_span(pos, width, self.pixel_ratio)
+    }
+
+    /// Positions in whole notes of the subdivision lines from `from` to `to`,
+    /// the beat lines left out
+    pub fn subdivision_lines(&self, meter: &Meter, from: f32, to: f32) -> Vec<f32> {
+        let sub = self.subdivision as f32;
+        let mut lines = vec![];
+
+        // The beat before `from` may have lines after it
+        for (beat, _) in meter.beat_lines(from - 0.25, to) {
+            for i in 1..self.subdivision {
+                let swing = if i % 2 == 1 { self.swing } else { 0.0 };
+                let pos = beat + 0.25 * (i as f32 + swing) / sub;
+
+                if pos >= from && pos <= to {
+                    lines.push(pos)
+                }
+            }
+        }
+
+        lines
+    }
+
+    /// The beat or subdivision line closest to a position in whole notes
+    pub fn nearest_subdivision(&self, meter: &Meter, pos: f32) -> f32 {
+        let beats = meter.beat_lines(pos - 0.25, pos + 0.25).into_iter().map(|(b, _)| b);
+        let subdivisions = self.subdivision_lines(meter, pos - 0.25, pos + 0.25);
+
+        beats.chain(subdivisions)
+            .min_by(|a, b| (a - pos).abs().partial_cmp(&(b - pos).abs()).unwrap())
+            .unwrap_or(pos)
+    }
+
+    /// Switches to the scale after the current one in `SCALES`, or to the
+    /// first one from a custom scale
+    pub fn next_scale(&mut self) {
+        let next = SCALES.iter()
+            .position(|s| s.1[..] == self.scale[..])
+            .map_or(0, |i| (i + 1) % SCALES.len());
+
+        self.scale = SCALES[next].1.to_vec()
+    }
+
+    /// Draws the grid of a score with the given meter, dimmed past `end`
+    /// in whole notes if the score is bounded
+    pub fn draw(&self, meter: &Meter, end: Option<f32>, size: Vector2<f32>, scene: &mut Scene) {
         let mut mesh = Mesh::new();
 
         let (v0, v1) = self.view;
         let v_size = v1 - v0;
         let aspect = size.div_element_wise(v_size);
 
-        let naturals = [0, 5, 10, 13, 18, 23, 28];
-
         let (y_first, y_last) = (
             v0.y.ceil() as i32,
             v1.y.floor() as i32
         );
+        let dense_pitches = aspect.y < self.min_line_spacing;
+        let (thin_width, thick_width) = self.line_widths();
+
+        // Octaves start on the thick lines
+        if self.octave_bands {
+            for octave in (v0.y / 31.0).floor() as i32..=(v1.y / 31.0).floor() as i32 {
+                if octave.rem_euclid(2) == 0 { continue }
+
+                let from = screen_position(self.view, size, Vector2::new(v0.x, (31 * octave) as f32));
+                let to = screen_position(self.view, size, Vector2::new(v0.x, (31 * octave + 31) as f32));
+                mesh.add_rect(
+                    Vector2::new(0.0, from.y),
+                    Vector2::new(self.size.x, to.y),
+                    with_alpha(self.style.base2(), 0.3)
+                );
+            }
+        }
+
+        // A note's row spans half a step to either side of its pitch line
+        if let Some(ref key) = self.key {
+            if !dense_pitches {
+                for line in (y_first - 1)..(y_last + 2) {
+                    if !key.contains(&(line.rem_euclid(31) as i16)) { continue }
+
+                    let pos = screen_position(self.view, size, Vector2::new(v0.x, line as f32)).y;
+                    mesh.add_rect(
+                        Vector2::new(0.0, pos - 0.5 * aspect.y),
+                        Vector2::new(self.size.x, pos + 0.5 * aspect.y),
+                        with_alpha(self.style.yellow(), 0.06)
+                    );
+                }
+            }
+        }
+
         for line in y_first..(y_last + 1) {
-            let pos = (line as f32 - v0.y) * aspect.y;
+            if dense_pitches && line % 31 != 0 { continue }
+
+            let pos = screen_position(self.view, size, Vector2::new(v0.x, line as f32)).y;
             let line_width =
-                if line % 31 != 0 { self.thin_width }
-                else { self.thick_width };
-            let color =
-                if line % 31 == 0 { self.style.base1() }
-                else if naturals.contains(&(line % 31)) { self.style.blue() }
-                else { self.style.base2() };
+                if line % 31 != 0 { thin_width }
+                else { thick_width };
+            let color = self.pitch_color(line);
 
+            let (from, to) = self.line_span(pos, line_width);
             mesh.add_rect(
-                Vector2::new(0.0, pos - 0.5 * line_width),
-                Vector2::new(self.size.x, pos + 0.5 * line_width),
+                Vector2::new(0.0, from),
+                Vector2::new(self.size.x, to),
                 color
             );
         }
 
-        let beats = self.beats as f32;
-        let (x_first, x_last) = (
-            (v0.x * beats).ceil() as i32,
-            (v1.x * beats).floor() as i32,
-        );
-        for line in x_first..(x_last) + 1 {
-            let pos = (line as f32 / beats - v0.x) * aspect.x;
+        // Labels sit on a backing at the left edge, in their line's color.
+        // The degrees only show when the rows are tall enough for them.
+        let label_scale = 1.0;
+        let label_height = font::HEIGHT as f32 * snap_to_pixels(label_scale, self.pixel_ratio);
+        let label_margin = snap_to_pixels(2.0, self.pixel_ratio);
+        let label_x = snap_to_pixels(4.0, self.pixel_ratio);
+        for line in y_first..(y_last + 1) {
+            let is_octave = line.rem_euclid(31) == 0;
+            if !is_octave && aspect.y < label_height + label_margin { continue }
+
+            let text = match self.pitch_label(line) {
+                Some(text) => text,
+                None => continue,
+            };
+
+            let pos = screen_position(self.view, size, Vector2::new(v0.x, line as f32)).y;
+            let label = Text {
+                text,
+                position: Vector2::new(label_x, round_to_pixels(pos - label_height / 2.0, self.pixel_ratio)),
+                scale: label_scale,
+                color: self.pitch_color(line),
+                pixel_ratio: self.pixel_ratio,
+            };
+
+            mesh.add_rect(
+                label.position - Vector2::new(label_margin, label_margin),
+                label.position + Vector2::new(label.width() + label_margin, label_height + label_margin),
+                with_alpha(self.style.base3(), 0.8)
+            );
+            label.add_to(&mut mesh)
+        }
+
+        let beat_spacing = aspect.x / 4.0;
+        for (line, is_measure) in meter.beat_lines(v0.x, v1.x) {
+            if !is_measure && beat_spacing < self.min_line_spacing { continue }
+
+            let pos = screen_position(self.view, size, Vector2::new(line, v0.y)).x;
             let line_width =
-                if line % self.beats as i32 != 0 { self.thin_width }
-                else { self.thick_width };
+                if !is_measure { thin_width }
+                else { thick_width };
             let color =
-                if line % self.beats as i32 != 0 { self.style.base3() }
+                if !is_measure { self.style.base3() }
                 else { self.style.base2() };
 
+            let (from, to) = self.line_span(pos, line_width);
             mesh.add_rect(
-                Vector2::new(pos - 0.5 * line_width, 0.0),
-                Vector2::new(pos + 0.5 * line_width, self.size.y),
+                Vector2::new(from, 0.0),
+                Vector2::new(to, self.size.y),
                 color
             )
         }
 
+        let sub_spacing = beat_spacing / self.subdivision as f32;
+        if self.subdivision > 1 && sub_spacing >= self.min_line_spacing {
+            for line in self.subdivision_lines(meter, v0.x, v1.x) {
+                let pos = screen_position(self.view, size, Vector2::new(line, v0.y)).x;
+                let (from, to) = self.line_span(pos, thin_width);
+
+                mesh.add_rect(
+                    Vector2::new(from, 0.0),
+                    Vector2::new(to, self.size.y),
+                    with_alpha(self.style.base2(), 0.4)
+                )
+            }
+        }
+
+        if let Some(end) = end {
+            let end = screen_position(self.view, size, Vector2::new(end, v0.y)).x;
+            if end < self.size.x {
+                mesh.add_rect(
+                    Vector2::new(end.max(0.0), 0.0),
+                    Vector2::new(self.size.x, self.size.y),
+                    with_alpha(self.style.base3(), 0.7)
+                )
+            }
+        }
+
         scene.add_mesh(mesh)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct PlayBar {
+    /// Position in measures
     pub position: f32,
+    pub view: (Vector2<f32>, Vector2<f32>),
     pub style: Style,
+    pub pixel_ratio: f32,
 }
 
 impl PlayBar {
-    pub fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+    /// The bar on its own, so it can be a layer that moves without the rest
+    /// of the scene being rebuilt
+    pub fn mesh(&self, size: Vector2<f32>) -> Mesh {
         let mut mesh = Mesh::new();
 
-        let width = 2.0;
         let color = self.style.violet();
 
-        let pos = self.position * size.x;
+        let pos = screen_position(self.view, size, Vector2::new(self.position, self.view.0.y)).x;
+        let (from, to) = line_span(pos, 2.0, self.pixel_ratio);
 
         mesh.add_rect(
-            Vector2::new(pos - 0.5 * width, 0.0),
-            Vector2::new(pos + 0.5 * width, size.y),
+            Vector2::new(from, 0.0),
+            Vector2::new(to, size.y),
             color
         );
 
+        mesh
+    }
+}
+
+impl Draw for PlayBar {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        scene.add_mesh(self.mesh(size))
+    }
+}
+
+/// Width of the resize handles at the note edges, in pixels
+pub const HANDLE_WIDTH: f32 = 3.0;
+/// Handles are only drawn when a tick is at least this wide, in pixels
+const HANDLE_MIN_TICK: f32 = 3.0;
+/// The largest corner radius of rounded notes, in pixels
+const MAX_CORNER_RADIUS: f32 = 6.0;
+
+/// A faint vertical line showing where a drag snaps to
+#[derive(Debug, Clone)]
+pub struct SnapGuide {
+    /// Position in measures
+    pub position: f32,
+    pub view: (Vector2<f32>, Vector2<f32>),
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl Draw for SnapGuide {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let pos = screen_position(self.view, size, Vector2::new(self.position, self.view.0.y)).x;
+        let (from, to) = line_span(pos, 1.0, self.pixel_ratio);
+
+        mesh.add_rect(
+            Vector2::new(from, 0.0),
+            Vector2::new(to, size.y),
+            with_alpha(self.style.base0(), 0.5)
+        );
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// A translucent band over the part of the song that loops
+pub struct LoopRegion {
+    /// Start and end in measures
+    pub range: (f32, f32),
+    pub view: (Vector2<f32>, Vector2<f32>),
+    pub style: Style,
+}
+
+impl Draw for LoopRegion {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let from = screen_position(self.view, size, Vector2::new(self.range.0, self.view.0.y));
+        let to = screen_position(self.view, size, Vector2::new(self.range.1, self.view.0.y));
+
+        mesh.add_rect(
+            Vector2::new(from.x, 0.0),
+            Vector2::new(to.x, size.y),
+            with_alpha(self.style.yellow(), 0.1)
+        );
+
         scene.add_mesh(mesh)
     }
 }
@@ -205,21 +667,33 @@ pub struct NoteView {
     pub measure_ticks: u16,
     pub style: Style,
     pub selected: bool,
+    pub rounded: bool,
+    /// Channels drawn faded, e.g. the muted ones
+    pub dimmed: HashSet<u16>,
+    /// Channels drawn slightly faded, the ones that can't be edited
+    pub locked: HashSet<u16>,
+    /// How many pixels the borders of plain notes fade out over, none for hard edges
+    pub feather: f32,
+    pub pixel_ratio: f32,
 }
 
-impl NoteView {
-    pub fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+impl Draw for NoteView {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
         use cgmath::ElementWise;
 
-        let mut mesh = Mesh::new();
+        // Border, fill and two handles, counted in rects. A rounded rect
+        // takes 22 vertices, less than six plain ones, a feathered one 8.
+        let note_rects = if self.rounded { 14 } else { 5 };
+        let mut mesh = Mesh::with_capacity(note_rects * self.notes.len());
 
         let aspect = size.div_element_wise(self.view.1 - self.view.0);
         let brick_width = 1.4 * aspect.y;
-        let color =
-            if self.selected { self.style.red() }
-            else { self.style.orange() };
-        let border_color = self.style.base2();
-        let border_width = 1.0;
+        let (border_color, border_width) =
+            if self.selected { (self.style.yellow(), 2.0) }
+            else { (self.style.base2(), 1.0) };
+        let border_width = snap_to_pixels(border_width, self.pixel_ratio);
+        let handle_color = self.style.base3();
+        let show_handles = aspect.x / self.measure_ticks as f32 >= HANDLE_MIN_TICK;
 
         for note in &self.notes {
             let start = Vector2::new(
@@ -231,13 +705,138 @@ impl NoteView {
                 note.pitch as f32
             );
 
-            let v0 = (start - self.view.0).mul_element_wise(aspect) - Vector2::new(0.0, brick_width / 2.0);
-            let v1 = (end - self.view.0).mul_element_wise(aspect) + Vector2::new(0.0, brick_width / 2.0);
+            let v0 = screen_position(self.view, size, start) - Vector2::new(0.0, brick_width / 2.0);
+            let v1 = screen_position(self.view, size, end) + Vector2::new(0.0, brick_width / 2.0);
+
+            if v1.x < 0.0 || v0.x > size.x || v1.y < 0.0 || v0.y > size.y {
+                continue
+            }
 
+            // All notes go in one mesh, unless they overflow its indices
+            if !mesh.has_room(4 * note_rects) {
+                scene.add_mesh(mesh);
+                mesh = Mesh::with_capacity(note_rects * self.notes.len());
+            }
+
+            let color = self.style.channel(note.channel);
+            let color =
+                if self.dimmed.contains(&note.channel) { with_alpha(color, 0.3) }
+                else if self.locked.contains(&note.channel) { with_alpha(color, 0.6) }
+                else { color };
             let delta: Vector2<f32> = [border_width / 2.0; 2].into();
+            let radius =
+                if self.rounded { (brick_width / 2.0).min(MAX_CORNER_RADIUS) }
+                else { 0.0 };
+
+            if self.rounded {
+                mesh.add_rounded_rect(v0, v1, radius, border_color);
+                mesh.add_rounded_rect(v0 + delta, v1 - delta, radius - border_width / 2.0, color);
+            }
+            else if self.feather > 0.0 {
+                mesh.add_feathered_rect(v0, v1, self.feather, border_color);
+                mesh.add_rect(v0 + delta, v1 - delta, color);
+            }
+            else {
+                mesh.add_rect(v0, v1, border_color);
+                mesh.add_rect(v0 + delta, v1 - delta, color);
+            }
+
+            if show_handles {
+                // Kept clear of the rounded corners
+                let inset = Vector2::new(0.0, radius / 2.0);
+                let (h0, h1) = (v0 + delta + inset, v1 - delta - inset);
+                let handle_width = snap_to_pixels(HANDLE_WIDTH, self.pixel_ratio).min((h1.x - h0.x) / 3.0);
+
+                mesh.add_rect(h0, Vector2::new(h0.x + handle_width, h1.y), handle_color);
+                mesh.add_rect(Vector2::new(h1.x - handle_width, h0.y), h1, handle_color);
+            }
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// How the notes of the score are shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewMode {
+    /// Every note on its own, in the color of its channel
+    Notes,
+    /// Beat-wide cells colored by how many notes overlap them
+    Heatmap,
+}
+
+/// Pitches a heatmap cell spans, a whole tone in 31-EDO
+const HEATMAP_PITCHES: i16 = 5;
+
+pub struct Heatmap<'a> {
+    pub notes: &'a [super::pianoroll::Note],
+    pub view: (Vector2<f32>, Vector2<f32>),
+    pub measure_ticks: u16,
+    pub style: Style,
+}
 
-            mesh.add_rect(v0, v1, border_color);
-            mesh.add_rect(v0 + delta, v1 - delta, color);
+impl Heatmap<'_> {
+    /// The beats a note overlaps, at least the one it starts in, counted in
+    /// quarter notes from the start. Every measure starts on one.
+    fn cells(&self, note: &super::pianoroll::Note) -> std::ops::Range<i32> {
+        let quarter = self.measure_ticks as f32 / 4.0;
+        let first = (note.time.0 as f32 / quarter).floor() as i32;
+        let last = (note.time.1 as f32 / quarter).ceil() as i32;
+
+        first..last.max(first + 1)
+    }
+
+    /// Blue for the sparsest cells through green and yellow to red for the densest
+    fn color(&self, level: f32) -> [f32; 4] {
+        let stops = [
+            self.style.blue(), self.style.cyan(), self.style.green(),
+            self.style.yellow(), self.style.orange(), self.style.red(),
+        ];
+        let position = level.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let i = (position.floor() as usize).min(stops.len() - 2);
+        let t = position - i as f32;
+
+        let mut color = [0.0; 4];
+        for (c, (a, b)) in color.iter_mut().zip(stops[i].iter().zip(stops[i + 1].iter())) {
+            *c = a + (b - a) * t;
+        }
+
+        with_alpha(color, 0.85)
+    }
+}
+
+impl Draw for Heatmap<'_> {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut counts: HashMap<(i32, i16), u32> = HashMap::new();
+
+        for note in self.notes {
+            let row = note.pitch.div_euclid(HEATMAP_PITCHES);
+
+            for beat in self.cells(note) {
+                *counts.entry((beat, row)).or_insert(0) += 1
+            }
+        }
+
+        let max = counts.values().cloned().max().unwrap_or(1) as f32;
+        let mut mesh = Mesh::with_capacity(counts.len());
+
+        for (&(beat, row), &count) in &counts {
+            let from = Vector2::new(beat as f32 / 4.0, (row * HEATMAP_PITCHES) as f32 - 0.5);
+            let to = Vector2::new((beat + 1) as f32 / 4.0, ((row + 1) * HEATMAP_PITCHES) as f32 - 0.5);
+
+            let v0 = screen_position(self.view, size, from);
+            let v1 = screen_position(self.view, size, to);
+            if v1.x < 0.0 || v0.x > size.x || v1.y < 0.0 || v0.y > size.y {
+                continue
+            }
+
+            if !mesh.has_room(4) {
+                scene.add_mesh(mesh);
+                mesh = Mesh::with_capacity(counts.len());
+            }
+
+            // A lone note is still clearly colder than the densest cell
+            mesh.add_rect(v0, v1, self.color((count - 1) as f32 / (max - 1.0).max(1.0)))
         }
 
         scene.add_mesh(mesh)
@@ -248,14 +847,18 @@ pub struct Frame {
     pub from: Vector2<f32>,
     pub to: Vector2<f32>,
     pub style: Style,
+    pub pixel_ratio: f32,
 }
 
-impl Frame {
-    pub fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+impl Draw for Frame {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
         let mut mesh = Mesh::new();
 
-        let border_width = 2.0;
+        let border_width = snap_to_pixels(2.0, self.pixel_ratio);
         let color = self.style.base0();
+        let fill_color = with_alpha(self.style.base1(), 0.25);
+
+        mesh.add_rect(self.from, self.to, fill_color);
 
         mesh.add_rect(
             [self.from.x - border_width / 2.0, self.from.y - border_width / 2.0].into(),
@@ -281,3 +884,581 @@ impl Frame {
         scene.add_mesh(mesh)
     }
 }
+
+pub struct MiniMap {
+    pub notes: Vec<super::pianoroll::Note>,
+    pub measure_ticks: u16,
+    pub extent: (Vector2<f32>, Vector2<f32>),
+    pub view: (Vector2<f32>, Vector2<f32>),
+    pub rect: (Vector2<f32>, Vector2<f32>),
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl MiniMap {
+    fn screen_position(&self, position: Vector2<f32>) -> Vector2<f32> {
+        let (e0, e1) = self.extent;
+        let (r0, r1) = self.rect;
+
+        r0 + (position - e0).div_element_wise(e1 - e0).mul_element_wise(r1 - r0)
+    }
+
+    pub fn view_position(&self, position: Vector2<f32>) -> Vector2<f32> {
+        let (e0, e1) = self.extent;
+        let (r0, r1) = self.rect;
+
+        e0 + (position - r0).div_element_wise(r1 - r0).mul_element_wise(e1 - e0)
+    }
+
+    pub fn contains(&self, position: Vector2<f32>) -> bool {
+        let (r0, r1) = self.rect;
+
+        position.x >= r0.x && position.x <= r1.x
+        && position.y >= r0.y && position.y <= r1.y
+    }
+}
+
+impl Draw for MiniMap {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let (r0, r1) = self.rect;
+        let clamp = |v: Vector2<f32>| Vector2::new(
+            v.x.max(r0.x).min(r1.x),
+            v.y.max(r0.y).min(r1.y),
+        );
+
+        mesh.add_rect(r0, r1, with_alpha(self.style.base2(), 0.8));
+
+        let ticks = self.measure_ticks as f32;
+        let height = ((r1.y - r0.y) / (self.extent.1.y - self.extent.0.y)).max(1.0);
+        for note in &self.notes {
+            let v0 = self.screen_position(Vector2::new(note.time.0 as f32 / ticks, note.pitch as f32));
+            let v1 = self.screen_position(Vector2::new(note.time.1 as f32 / ticks, note.pitch as f32));
+
+            mesh.add_rect(
+                clamp(v0 - Vector2::new(0.0, height / 2.0)),
+                clamp(v1 + Vector2::new(0.0, height / 2.0)),
+                self.style.channel(note.channel)
+            );
+        }
+
+        scene.add_mesh(mesh);
+
+        Frame {
+            from: clamp(self.screen_position(self.view.0)),
+            to: clamp(self.screen_position(self.view.1)),
+            style: self.style,
+            pixel_ratio: self.pixel_ratio,
+        }.draw(size, scene)
+    }
+}
+
+pub struct Text {
+    pub text: String,
+    /// The bottom left corner of the first letter
+    pub position: Vector2<f32>,
+    /// Size of a font pixel in logical pixels, made whole physical ones
+    pub scale: f32,
+    pub color: [f32; 4],
+    pub pixel_ratio: f32,
+}
+
+impl Text {
+    fn pixel_size(&self) -> f32 {
+        snap_to_pixels(self.scale, self.pixel_ratio)
+    }
+
+    pub fn width(&self) -> f32 {
+        font::text_width(&self.text) as f32 * self.pixel_size()
+    }
+
+    pub fn height(&self) -> f32 {
+        font::HEIGHT as f32 * self.pixel_size()
+    }
+
+    pub fn add_to(&self, mesh: &mut Mesh) {
+        let s = self.pixel_size();
+        // The font pixels start on a physical pixel too
+        let origin = self.position.map(|x| round_to_pixels(x, self.pixel_ratio));
+
+        for (i, c) in self.text.chars().enumerate() {
+            let x0 = origin.x + (i * (font::WIDTH + 1)) as f32 * s;
+
+            for (row, &bits) in font::glyph(c).iter().enumerate() {
+                let y = origin.y + (font::HEIGHT - 1 - row) as f32 * s;
+                let lit = |col: usize| bits & (1 << (font::WIDTH - 1 - col)) != 0;
+
+                // Lit pixels next to each other go in a single rect
+                let mut col = 0;
+                while col < font::WIDTH {
+                    if !lit(col) {
+                        col += 1;
+                        continue
+                    }
+
+                    let start = col;
+                    while col < font::WIDTH && lit(col) {
+                        col += 1
+                    }
+
+                    mesh.add_rect(
+                        Vector2::new(x0 + start as f32 * s, y),
+                        Vector2::new(x0 + col as f32 * s, y + s),
+                        self.color
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Draw for Text {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+        self.add_to(&mut mesh);
+        scene.add_mesh(mesh)
+    }
+}
+
+pub struct Ruler {
+    pub view: (Vector2<f32>, Vector2<f32>),
+    pub meter: Meter,
+    /// Tempo changes as their position in whole notes and quarter notes per minute
+    pub tempo: Vec<(f32, f32)>,
+    /// The looping part of the song in whole notes, if any
+    pub loop_range: Option<(f32, f32)>,
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl Ruler {
+    pub const HEIGHT: f32 = 22.0;
+}
+
+impl Draw for Ruler {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let (v0, v1) = self.view;
+        let aspect = size.x / (v1.x - v0.x);
+        let screen_x = |pos: f32| screen_position(self.view, size, Vector2::new(pos, v0.y)).x;
+        let bottom = size.y - Self::HEIGHT;
+        let text_scale = snap_to_pixels(2.0, self.pixel_ratio);
+
+        mesh.add_rect(
+            Vector2::new(0.0, bottom),
+            Vector2::new(size.x, size.y),
+            self.style.base2()
+        );
+
+        if let Some((from, to)) = self.loop_range {
+            mesh.add_rect(
+                Vector2::new(screen_x(from), bottom),
+                Vector2::new(screen_x(to), size.y),
+                with_alpha(self.style.yellow(), 0.5)
+            );
+        }
+
+        // Label every measure when there's room, otherwise every 2nd, 4th…
+        // The shortest measure in view decides
+        let (first, last) = (self.meter.measure_at(v0.x), self.meter.measure_at(v1.x));
+        let label_room = (font::text_width(&format!("{}", last + 1)) as f32 + 3.0) * text_scale;
+        let shortest = (first..=last)
+            .map(|m| self.meter.measure_len(m))
+            .fold(f32::INFINITY, f32::min);
+        let mut label_step = 1;
+        while (label_step as f32) * shortest * aspect < label_room {
+            label_step *= 2
+        }
+
+        for (line, is_measure) in self.meter.beat_lines(v0.x, v1.x) {
+            let measure = self.meter.measure_at(line);
+            let pos = screen_x(line);
+
+            let (width, height) =
+                if is_measure { (2.0, Self::HEIGHT) }
+                else { (1.0, Self::HEIGHT / 4.0) };
+            let (from, to) = line_span(pos, width, self.pixel_ratio);
+
+            mesh.add_rect(
+                Vector2::new(from, bottom),
+                Vector2::new(to, bottom + height),
+                self.style.base0()
+            );
+
+            if is_measure && measure % label_step == 0 {
+                Text {
+                    text: format!("{}", measure + 1),
+                    position: Vector2::new(pos + 2.0 * text_scale, bottom + 3.0),
+                    scale: text_scale,
+                    color: self.style.base0(),
+                    pixel_ratio: self.pixel_ratio,
+                }.add_to(&mut mesh)
+            }
+        }
+
+        // Meter changes are labeled like 3/4
+        for (i, &(measure, beats)) in self.meter.signatures().iter().enumerate() {
+            let pos = self.meter.measure_start(measure);
+            if pos < v0.x || pos > v1.x || (i == 0 && beats == 4) { continue }
+            let pos = screen_x(pos);
+
+            Text {
+                text: format!("{}/4", beats),
+                position: Vector2::new(pos - 12.0, size.y - 6.0),
+                scale: 1.0,
+                color: self.style.cyan(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        for &(pos, bpm) in &self.tempo {
+            if pos < v0.x || pos > v1.x { continue }
+            let pos = screen_x(pos);
+
+            let (from, to) = line_span(pos, 2.0, self.pixel_ratio);
+
+            mesh.add_rect(
+                Vector2::new(from, size.y - 7.0),
+                Vector2::new(to, size.y),
+                self.style.violet()
+            );
+            Text {
+                text: format!("{}", bpm),
+                position: Vector2::new(pos + 3.0, size.y - 6.0),
+                scale: 1.0,
+                color: self.style.violet(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// A few lines of text on a translucent panel
+pub struct InfoBox {
+    pub lines: Vec<String>,
+    /// The top left corner
+    pub position: Vector2<f32>,
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl InfoBox {
+    const SCALE: f32 = 2.0;
+    const PADDING: f32 = 6.0;
+
+    /// Size of a font pixel, in whole physical pixels
+    fn scale(&self) -> f32 {
+        snap_to_pixels(Self::SCALE, self.pixel_ratio)
+    }
+
+    fn padding(&self) -> f32 {
+        snap_to_pixels(Self::PADDING, self.pixel_ratio)
+    }
+
+    /// Width and height of the box, padding included
+    pub fn size(&self) -> Vector2<f32> {
+        let scale = self.scale();
+        let line_height = (font::HEIGHT + 3) as f32 * scale;
+
+        let width = self.lines.iter()
+            .map(|l| font::text_width(l) as f32 * scale)
+            .fold(0.0, f32::max);
+        let height = self.lines.len() as f32 * line_height - 3.0 * scale;
+
+        Vector2::new(width, height) + Vector2::new(2.0, 2.0) * self.padding()
+    }
+}
+
+impl Draw for InfoBox {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let scale = self.scale();
+        let padding = self.padding();
+        let line_height = (font::HEIGHT + 3) as f32 * scale;
+        let size = self.size();
+
+        mesh.add_rect(
+            self.position - Vector2::new(0.0, size.y),
+            self.position + Vector2::new(size.x, 0.0),
+            with_alpha(self.style.base2(), 0.8)
+        );
+
+        for (i, line) in self.lines.iter().enumerate() {
+            Text {
+                text: line.clone(),
+                position: self.position + Vector2::new(
+                    padding,
+                    -padding - i as f32 * line_height - font::HEIGHT as f32 * scale
+                ),
+                scale,
+                color: self.style.base0(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// Labeled text fields, one of them taking the typed text
+pub struct PropertyPanel {
+    /// Labels and values
+    pub fields: Vec<(String, String)>,
+    pub active: usize,
+    /// Whether the values can be applied, otherwise the panel is outlined in red
+    pub valid: bool,
+    /// The top left corner
+    pub position: Vector2<f32>,
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl Draw for PropertyPanel {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let scale = snap_to_pixels(2.0, self.pixel_ratio);
+        let padding = snap_to_pixels(6.0, self.pixel_ratio);
+        let line_height = (font::HEIGHT + 4) as f32 * scale;
+
+        let label_width = self.fields.iter()
+            .map(|f| font::text_width(&f.0) as f32 * scale)
+            .fold(0.0, f32::max);
+        // Room for a few more digits than the longest value
+        let value_width = self.fields.iter()
+            .map(|f| font::text_width(&f.1) as f32 * scale)
+            .fold(0.0, f32::max)
+            .max(font::text_width("00000") as f32 * scale);
+        let gap = 4.0 * scale;
+
+        let size = Vector2::new(
+            label_width + gap + value_width + 2.0 * padding,
+            self.fields.len() as f32 * line_height + 2.0 * padding - 4.0 * scale
+        );
+        let bottom_left = self.position - Vector2::new(0.0, size.y);
+
+        if !self.valid {
+            let outline = snap_to_pixels(2.0, self.pixel_ratio);
+            mesh.add_rect(
+                bottom_left - Vector2::new(outline, outline),
+                self.position + Vector2::new(size.x + outline, outline),
+                self.style.red()
+            )
+        }
+        mesh.add_rect(
+            bottom_left,
+            self.position + Vector2::new(size.x, 0.0),
+            with_alpha(self.style.base2(), 0.95)
+        );
+
+        for (i, (label, value)) in self.fields.iter().enumerate() {
+            let y = self.position.y - padding - i as f32 * line_height - font::HEIGHT as f32 * scale;
+            let value_x = self.position.x + padding + label_width + gap;
+
+            if i == self.active {
+                mesh.add_rect(
+                    Vector2::new(value_x - scale, y - 2.0 * scale),
+                    Vector2::new(value_x + value_width + scale, y + (font::HEIGHT + 1) as f32 * scale),
+                    self.style.base3()
+                )
+            }
+
+            Text {
+                text: label.clone(),
+                position: Vector2::new(self.position.x + padding, y),
+                scale,
+                color: self.style.base1(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh);
+            Text {
+                text: if i == self.active { format!("{}_", value) } else { value.clone() },
+                position: Vector2::new(value_x, y),
+                scale,
+                color: self.style.base0(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// Lines of small text along the left edge, the newest at the bottom
+pub struct EventLog {
+    pub lines: Vec<String>,
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl Draw for EventLog {
+    fn draw(&self, size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+
+        let scale = snap_to_pixels(1.0, self.pixel_ratio);
+        let padding = snap_to_pixels(4.0, self.pixel_ratio);
+        let line_height = (font::HEIGHT + 3) as f32 * scale;
+        // The whole line fits within the screen, the rest is cut off
+        let max_chars = ((size.x - 2.0 * padding) / ((font::WIDTH + 1) as f32 * scale)) as usize;
+
+        let height = self.lines.len() as f32 * line_height + 2.0 * padding;
+        let top = size.y - Ruler::HEIGHT;
+
+        mesh.add_rect(
+            Vector2::new(0.0, top - height),
+            Vector2::new(size.x, top),
+            with_alpha(self.style.base3(), 0.85)
+        );
+
+        for (i, line) in self.lines.iter().enumerate() {
+            Text {
+                text: line.chars().take(max_chars).collect(),
+                position: Vector2::new(
+                    padding,
+                    top - padding - (i + 1) as f32 * line_height + 3.0 * scale
+                ),
+                scale,
+                color: self.style.base0(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// A column of clickable text items on a panel
+pub struct Menu {
+    pub items: Vec<String>,
+    /// The top left corner
+    pub position: Vector2<f32>,
+    pub style: Style,
+    pub pixel_ratio: f32,
+}
+
+impl Menu {
+    const SCALE: f32 = 2.0;
+    const PADDING: f32 = 6.0;
+
+    /// Size of a font pixel, in whole physical pixels
+    fn scale(&self) -> f32 {
+        snap_to_pixels(Self::SCALE, self.pixel_ratio)
+    }
+
+    fn padding(&self) -> f32 {
+        snap_to_pixels(Self::PADDING, self.pixel_ratio)
+    }
+
+    fn item_height(&self) -> f32 {
+        (font::HEIGHT + 6) as f32 * self.scale()
+    }
+
+    pub fn size(&self) -> Vector2<f32> {
+        let width = self.items.iter()
+            .map(|l| font::text_width(l) as f32 * self.scale())
+            .fold(0.0, f32::max);
+
+        Vector2::new(
+            width + 2.0 * self.padding(),
+            self.items.len() as f32 * self.item_height()
+        )
+    }
+
+    /// The index of the item under a screen point
+    pub fn item_at(&self, point: Vector2<f32>) -> Option<usize> {
+        let size = self.size();
+        let offset = point - self.position;
+
+        if offset.x < 0.0 || offset.x > size.x || offset.y > 0.0 || -offset.y >= size.y {
+            return None
+        }
+
+        Some((-offset.y / self.item_height()) as usize)
+    }
+}
+
+impl Draw for Menu {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
+        let mut mesh = Mesh::new();
+        let size = self.size();
+
+        mesh.add_rect(
+            self.position - Vector2::new(0.0, size.y),
+            self.position + Vector2::new(size.x, 0.0),
+            with_alpha(self.style.base2(), 0.95)
+        );
+
+        for (i, item) in self.items.iter().enumerate() {
+            let top = self.position.y - i as f32 * self.item_height();
+
+            // Separators between the items
+            if i > 0 {
+                let (from, to) = line_span(top, 1.0, self.pixel_ratio);
+                mesh.add_rect(
+                    Vector2::new(self.position.x, from),
+                    Vector2::new(self.position.x + size.x, to),
+                    self.style.base1()
+                )
+            }
+
+            Text {
+                text: item.clone(),
+                position: Vector2::new(
+                    self.position.x + self.padding(),
+                    top - self.item_height() + 3.0 * self.scale()
+                ),
+                scale: self.scale(),
+                color: self.style.base0(),
+                pixel_ratio: self.pixel_ratio,
+            }.add_to(&mut mesh)
+        }
+
+        scene.add_mesh(mesh)
+    }
+}
+
+/// A freehand selection path, closed between its last and first points
+pub struct Lasso {
+    pub points: Vec<Vector2<f32>>,
+    pub style: Style,
+}
+
+impl Draw for Lasso {
+    fn draw(&self, _size: Vector2<f32>, scene: &mut Scene) {
+        if self.points.len() < 2 { return }
+
+        let mut mesh = Mesh::new();
+
+        let width = 2.0;
+        let color = self.style.base0();
+        let fill_color = with_alpha(self.style.base1(), 0.25);
+
+        // The fan is only exact for convex paths, but as a translucent hint
+        // it reads fine for the rest too
+        if self.points.len() > 2 {
+            mesh.add_fan(self.points.iter().map(|p| Vertex {
+                pos: [p.x, p.y],
+                color: fill_color,
+            }));
+        }
+
+        for (i, &a) in self.points.iter().enumerate() {
+            let b = self.points[(i + 1) % self.points.len()];
+            let dir = b - a;
+            let len = (dir.x * dir.x + dir.y * dir.y).sqrt();
+            if len == 0.0 { continue }
+
+            let normal = Vector2::new(-dir.y, dir.x) * (0.5 * width / len);
+            mesh.add_fan([a - normal, b - normal, b + normal, a + normal].iter().map(|p| Vertex {
+                pos: [p.x, p.y],
+                color,
+            }));
+        }
+
+        scene.add_mesh(mesh)
+    }
+}